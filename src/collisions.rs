@@ -0,0 +1,310 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::fmt::Formatter;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::file_system::FileSystem;
+
+/// How to deal with several files ending up with the same name
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CollisionStrategy {
+    /// Leave the colliding file alone
+    Skip,
+    /// Append " (2)", " (3)" etc. to the colliding file's name
+    Suffix,
+    /// Don't rename anything in the directory
+    Abort,
+}
+
+impl FromStr for CollisionStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(CollisionStrategy::Skip),
+            "suffix" => Ok(CollisionStrategy::Suffix),
+            "abort" => Ok(CollisionStrategy::Abort),
+            _ => Err(format!(
+                "Unknown collision strategy \"{}\", use skip, suffix, or abort",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for CollisionStrategy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            CollisionStrategy::Skip => write!(f, "skip"),
+            CollisionStrategy::Suffix => write!(f, "suffix"),
+            CollisionStrategy::Abort => write!(f, "abort"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rename {
+    pub from: PathBuf,
+    pub to: PathBuf,
+}
+
+/// The outcome of checking a set of renames for collisions
+#[derive(Debug, PartialEq)]
+pub struct Resolution {
+    /// The renames that can be done safely
+    pub renames: Vec<Rename>,
+    /// The renames left out, together with the reason
+    pub skipped: Vec<(Rename, String)>,
+    /// Set if the whole set of renames has to be left out
+    pub aborted: Option<String>,
+}
+
+/// Checks a set of renames for targets used more than once and for targets
+/// taken by existing files that aren't renamed themselves. Paths are compared
+/// case-insensitively to be safe on case-insensitive file systems.
+///
+/// `existing` contains all paths present on the disk, `with_suffix` returns
+/// the target of a rename with the given number appended, or None if there's
+/// no room for the number.
+pub fn resolve<F>(
+    renames: Vec<Rename>,
+    existing: &HashSet<PathBuf>,
+    strategy: CollisionStrategy,
+    with_suffix: F,
+) -> Resolution
+where
+    F: Fn(&Path, usize) -> Option<PathBuf>,
+{
+    let sources: HashSet<String> = renames.iter().map(|r| key(&r.from)).collect();
+    let unrelated: HashSet<String> = existing
+        .iter()
+        .map(|path| key(path))
+        .filter(|path| !sources.contains(path))
+        .collect();
+
+    // files that keep their name occupy it in any case, so handle them first
+    let (unchanged, changed): (Vec<Rename>, Vec<Rename>) =
+        renames.into_iter().partition(|r| r.from == r.to);
+
+    // Skipping a rename leaves the file at its old path, which might be the
+    // target of a rename accepted before. Repeat until nothing changes.
+    let mut left_in_place: HashSet<String> = HashSet::new();
+    loop {
+        let mut taken = unrelated.clone();
+        taken.extend(left_in_place.iter().cloned());
+        let mut accepted = Vec::new();
+        let mut skipped = Vec::new();
+
+        for rename in unchanged.iter().chain(changed.iter()) {
+            let target = key(&rename.to);
+            if !taken.contains(&target) {
+                taken.insert(target);
+                accepted.push(rename.clone());
+                continue;
+            }
+
+            let reason = format!("\"{}\" is already taken", rename.to.to_string_lossy());
+            match strategy {
+                CollisionStrategy::Abort => {
+                    return Resolution {
+                        renames: Vec::new(),
+                        skipped: Vec::new(),
+                        aborted: Some(reason),
+                    }
+                }
+                CollisionStrategy::Suffix => {
+                    let mut number = 2;
+                    let mut to = with_suffix(&rename.to, number);
+                    while to.as_ref().is_some_and(|to| taken.contains(&key(to))) {
+                        number += 1;
+                        to = with_suffix(&rename.to, number);
+                    }
+                    match to {
+                        Some(to) => {
+                            taken.insert(key(&to));
+                            accepted.push(Rename {
+                                from: rename.from.clone(),
+                                to,
+                            });
+                        }
+                        None => skipped.push((
+                            rename.clone(),
+                            format!("{}, and there's no room for a number", reason),
+                        )),
+                    }
+                }
+                CollisionStrategy::Skip => skipped.push((rename.clone(), reason)),
+            }
+        }
+
+        let now_left_in_place: HashSet<String> = skipped
+            .iter()
+            .map(|(rename, _)| key(&rename.from))
+            .collect();
+        if now_left_in_place == left_in_place {
+            return Resolution {
+                renames: accepted,
+                skipped,
+                aborted: None,
+            };
+        }
+        left_in_place = now_left_in_place;
+    }
+}
+
+/// Returns all paths within the directories the renames lead to
+pub fn existing_paths(renames: &[Rename], file_system: &dyn FileSystem) -> HashSet<PathBuf> {
+    let dirs: HashSet<&Path> = renames.iter().filter_map(|r| r.to.parent()).collect();
+    let mut existing = HashSet::new();
+
+    for dir in dirs {
+        if let Ok(paths) = file_system.read_dir(dir) {
+            existing.extend(paths);
+        }
+    }
+
+    existing
+}
+
+/// Do both paths name the same file on a case-insensitive file system?
+pub fn is_same_path(left: &Path, right: &Path) -> bool {
+    key(left) == key(right)
+}
+
+fn key(path: &Path) -> String {
+    path.to_string_lossy().to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rename(from: &str, to: &str) -> Rename {
+        Rename {
+            from: PathBuf::from(from),
+            to: PathBuf::from(to),
+        }
+    }
+
+    fn with_suffix(path: &Path, number: usize) -> Option<PathBuf> {
+        Some(PathBuf::from(format!(
+            "{} ({})",
+            path.to_string_lossy(),
+            number
+        )))
+    }
+
+    fn existing(paths: &[&str]) -> HashSet<PathBuf> {
+        paths.iter().map(PathBuf::from).collect()
+    }
+
+    #[test]
+    fn test_resolve_without_collisions() {
+        let renames = vec![rename("a", "1"), rename("b", "2")];
+        let resolution = resolve(
+            renames.clone(),
+            &existing(&["a", "b"]),
+            CollisionStrategy::Skip,
+            with_suffix,
+        );
+        assert_eq!(resolution.renames, renames);
+        assert!(resolution.skipped.is_empty());
+        assert_eq!(resolution.aborted, None);
+
+        // changing the case only is fine, just like swapping names
+        let renames = vec![rename("a", "A"), rename("b", "c"), rename("c", "b")];
+        let resolution = resolve(
+            renames.clone(),
+            &existing(&["a", "b", "c"]),
+            CollisionStrategy::Abort,
+            with_suffix,
+        );
+        assert_eq!(resolution.renames, renames);
+    }
+
+    #[test]
+    fn test_resolve_duplicate_targets() {
+        let renames = vec![rename("a", "1"), rename("b", "1"), rename("c", "1")];
+
+        let resolution = resolve(
+            renames.clone(),
+            &existing(&["a", "b", "c"]),
+            CollisionStrategy::Skip,
+            with_suffix,
+        );
+        assert_eq!(resolution.renames, vec![rename("a", "1")]);
+        assert_eq!(resolution.skipped.len(), 2);
+
+        let resolution = resolve(
+            renames.clone(),
+            &existing(&["a", "b", "c"]),
+            CollisionStrategy::Suffix,
+            with_suffix,
+        );
+        assert_eq!(
+            resolution.renames,
+            vec![rename("a", "1"), rename("b", "1 (2)"), rename("c", "1 (3)")]
+        );
+
+        let resolution = resolve(
+            renames,
+            &existing(&["a", "b", "c"]),
+            CollisionStrategy::Abort,
+            with_suffix,
+        );
+        assert!(resolution.renames.is_empty());
+        assert!(resolution.aborted.is_some());
+    }
+
+    #[test]
+    fn test_resolve_case_insensitive_targets() {
+        let renames = vec![rename("a", "Foo"), rename("b", "foo")];
+        let resolution = resolve(
+            renames,
+            &existing(&["a", "b"]),
+            CollisionStrategy::Skip,
+            with_suffix,
+        );
+        assert_eq!(resolution.renames, vec![rename("a", "Foo")]);
+    }
+
+    #[test]
+    fn test_resolve_existing_unrelated_files() {
+        let renames = vec![rename("a", "cover.jpg")];
+        let resolution = resolve(
+            renames,
+            &existing(&["a", "Cover.JPG"]),
+            CollisionStrategy::Suffix,
+            with_suffix,
+        );
+        assert_eq!(resolution.renames, vec![rename("a", "cover.jpg (2)")]);
+    }
+
+    #[test]
+    fn test_resolve_unchanged_files_keep_their_names() {
+        // "b" stays where it is, so "a" cannot take its name
+        let renames = vec![rename("a", "b"), rename("b", "b")];
+        let resolution = resolve(
+            renames,
+            &existing(&["a", "b"]),
+            CollisionStrategy::Skip,
+            with_suffix,
+        );
+        assert_eq!(resolution.renames, vec![rename("b", "b")]);
+    }
+
+    #[test]
+    fn test_resolve_skipped_files_block_their_old_name() {
+        // "c" collides with "x" and stays, so "b" cannot take over its name
+        let renames = vec![rename("a", "x"), rename("c", "x"), rename("b", "c")];
+        let resolution = resolve(
+            renames,
+            &existing(&["a", "b", "c", "x"]),
+            CollisionStrategy::Skip,
+            with_suffix,
+        );
+        assert!(resolution.renames.is_empty());
+        assert_eq!(resolution.skipped.len(), 3);
+    }
+}
@@ -1,48 +1,206 @@
 extern crate clap;
 
+use std::collections::HashMap;
 use std::fmt::Formatter;
 use std::path::PathBuf;
 use std::{env, fmt, process};
 
+use crate::collisions::CollisionStrategy;
+use crate::fallback::Fallbacks;
+use crate::length::{LengthUnit, ShortenStrategy};
+use crate::music_file;
+use crate::normalize::{Language, Replacement, Steps};
+use crate::output::OutputFormat;
+use crate::path_pattern::PathPattern;
+use crate::sanitize;
+use crate::sanitize::Rule;
+use crate::settings;
+use crate::settings::{Settings, Source};
+use crate::tagging::TagSources;
+use crate::target_fs::TargetFs;
+use crate::template::Template;
 use crate::util;
-use clap::{crate_authors, crate_version, App, Arg};
+use clap::{crate_authors, crate_version, App, AppSettings, Arg, ArgGroup, SubCommand};
+
+/// What to do in this run
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    /// Rename the music files below the start directory
+    Rename,
+    /// Carry out a plan saved by an earlier run
+    Apply(PathBuf),
+    /// Write tags into the music files below the start directory
+    Tag(TagSources),
+    /// Show how a text is turned into a file or directory name
+    ExplainName(String),
+    /// Revert the changes recorded in a journal
+    Undo(PathBuf),
+}
 
-#[derive(Default)]
 pub struct Config {
+    pub command: Command,
+    pub detect_content: bool,
+    pub dir_template: Template,
     pub dry_run: bool,
+    /// Marks names shortened to the length limit
+    pub ellipsis: String,
+    /// The missing tags guessed from the path
+    pub fallbacks: Fallbacks,
+    pub file_template: Template,
+    pub journal: Option<PathBuf>,
+    /// What the length limit is counted in
+    pub length_unit: LengthUnit,
+    /// The limit of whole paths below the mount root
+    pub max_path_length: Option<u32>,
+    /// Where the length of paths is measured from
+    pub mount_root: Option<PathBuf>,
     pub name_length: u32,
+    /// The clean-up steps applied to tag values before building names
+    pub normalize: Steps,
     pub omit_artist: bool,
+    pub on_collision: CollisionStrategy,
+    pub organize_into: Option<PathBuf>,
+    pub organize_template: Template,
+    pub output: OutputFormat,
+    /// Do the values taken from the path replace existing tags?
+    pub override_tags: bool,
+    /// Where tags missing in the files are taken from
+    pub path_pattern: Option<PathPattern>,
     pub remove_artist: bool,
     pub remove_ordinary_files: bool,
     pub rename_directory: bool,
+    /// Applied to tag values in this order before building names
+    pub replacements: Vec<Replacement>,
+    /// Replacements in file and directory names, applied in this order before
+    /// the rules of the target file system
+    pub sanitize_rules: Vec<Rule>,
+    pub save_plan: Option<PathBuf>,
     pub shorten_names: bool,
+    /// How names exceeding the length limit are shortened
+    pub shorten_strategy: ShortenStrategy,
+    /// Where the values came from, by field name, unless it is the default
+    pub sources: HashMap<&'static str, Source>,
     pub start_dir: PathBuf,
+    /// Decides the characters allowed in names and their maximum length
+    pub target_fs: TargetFs,
+    /// The language whose small words stay lowercase in title case
+    pub title_case_language: Language,
+    /// The artist used in directory names of compilations
+    pub various_artists: String,
     pub verbose: bool,
+    /// Write the normalized values into the files?
+    pub write_normalized: bool,
+    /// Write the values taken from the path into the files?
+    pub write_tags: bool,
 }
 
 impl Config {
     pub fn new() -> Config {
+        const ALBUM_FROM_DIR: &str = "album-from-dir";
+        const APPLY: &str = "apply";
         const ARTIST: &str = "artist";
+        const CSV: &str = "csv";
+        const CSV_VALUE: &str = "FILE";
+        const DIR_VALUE: &str = "DIR";
+        const DETECT_CONTENT: &str = "detect-content";
         const DIRECTORY: &str = "directory";
+        const DIR_TEMPLATE: &str = "dir-template";
         const DRY_RUN: &str = "dry-run";
+        const ELLIPSIS: &str = "ellipsis";
+        const ELLIPSIS_VALUE: &str = "MARKER";
+        const EXPLAIN_NAME: &str = "explain-name";
+        const FALLBACK: &str = "fallback";
+        const FALLBACK_VALUE: &str = "TAGS";
+        const FILE_TEMPLATE: &str = "file-template";
+        const FILE_TEMPLATE_VALUE: &str = "TEMPLATE";
+        const FROM_PATH_PATTERN: &str = "from-path-pattern";
+        const FROM_PATH_PATTERN_VALUE: &str = "PATTERN";
+        const JOURNAL: &str = "journal";
+        const JOURNAL_VALUE: &str = "FILE";
         const LENGTH: &str = "limit-length";
         const LENGTH_VALUE: &str = "LENGTH";
+        const LENGTH_UNIT: &str = "length-unit";
+        const LENGTH_UNIT_VALUE: &str = "UNIT";
+        const MAX_PATH_LENGTH: &str = "max-path-length";
+        const NAME_VALUE: &str = "NAME";
+        const MOUNT_ROOT: &str = "mount-root";
+        const NORMALIZE: &str = "normalize";
+        const NORMALIZE_VALUE: &str = "STEPS";
         const OMIT_ARTIST: &str = "omit-artist";
+        const ON_COLLISION: &str = "on-collision";
+        const ON_COLLISION_VALUE: &str = "STRATEGY";
+        const ORGANIZE_INTO: &str = "organize-into";
+        const ORGANIZE_INTO_VALUE: &str = "LIBRARY_ROOT";
+        const ORGANIZE_TEMPLATE: &str = "organize-template";
+        const OUTPUT: &str = "output";
+        const OUTPUT_VALUE: &str = "FORMAT";
+        const OVERRIDE_TAGS: &str = "override-tags";
+        const PATTERN: &str = "pattern";
+        const PLAN_VALUE: &str = "PLAN";
+        const PROFILE: &str = "profile";
+        const PROFILE_VALUE: &str = "NAME";
         const REMOVE: &str = "remove";
+        const REPLACE: &str = "replace";
+        const REPLACE_VALUES: &[&str] = &["REGEX", "REPLACEMENT"];
+        const SAVE_PLAN: &str = "save-plan";
+        const SAVE_PLAN_VALUE: &str = "FILE";
+        const SHORTEN: &str = "shorten";
+        const SHORTEN_VALUE: &str = "STRATEGY";
         const START_DIR: &str = "START_DIR";
+        const TAG: &str = "tag";
+        const TARGET_FS: &str = "target-fs";
+        const TARGET_FS_VALUE: &str = "FS";
+        const TITLE_CASE_LANGUAGE: &str = "title-case-language";
+        const TITLE_CASE_LANGUAGE_VALUE: &str = "LANG";
+        const UNDO: &str = "undo";
+        const VARIOUS_ARTISTS: &str = "various-artists";
+        const VARIOUS_ARTISTS_VALUE: &str = "LABEL";
         const VERBOSE: &str = "verbose";
+        const WRITE_NORMALIZED: &str = "write-normalized";
+        const WRITE_TAGS: &str = "write-tags";
+        // the options switching off flags, e. g. when a configuration file switches them on
+        const NEGATED_FLAGS: &[(&str, &str, &str)] = &[
+            (ARTIST, "no-artist", "Keeps the artist in the filename"),
+            (
+                DETECT_CONTENT,
+                "no-detect-content",
+                "Recognizes music files by their extension",
+            ),
+            (DIRECTORY, "no-directory", "Doesn't rename directories"),
+            (DRY_RUN, "no-dry-run", "Carries out the changes"),
+            (OMIT_ARTIST, "no-omit-artist", "Doesn't omit the artist"),
+            (
+                OVERRIDE_TAGS,
+                "no-override-tags",
+                "Only fills in missing tags with the values taken from the path",
+            ),
+            (REMOVE, "no-remove", "Keeps non-music files"),
+            (VERBOSE, "no-verbose", "Isn't verbose"),
+            (
+                WRITE_NORMALIZED,
+                "no-write-normalized",
+                "Doesn't write the normalized values into the tags",
+            ),
+            (
+                WRITE_TAGS,
+                "no-write-tags",
+                "Doesn't write the values taken from the path into the tags",
+            ),
+        ];
 
-        let matches = App::new("mp3rename")
+        let mut app = App::new("mp3rename")
             // use crate_version! to pull the version number
             .version(crate_version!())
             .author(crate_authors!())
+            .setting(AppSettings::SubcommandsNegateReqs)
             .about(
                 "Traverses a directory tree and renames all music files and,
 optionally, the directories containing them according to the
 tags in the music files.
 The resulting file name will have the form
 [<Disc Number> - ]<Track Number> [<Artist> - ]<Track Title>.<extension>
-(with extension in <mp3|flac|m4a|m4b|m4p|m4v>).",
+(with extension in <mp3|flac|m4a|m4b|m4p|m4v|ogg|oga|opus|wav|aif|aifc|aiff|
+wma|ape|wv|mpc>) unless you provide a template of your own.",
             )
             .arg(
                 Arg::with_name(ARTIST)
@@ -50,11 +208,24 @@ The resulting file name will have the form
                     .long(ARTIST)
                     .help("Removes the artist from the filename if it is the same for all files in a directory"),
             )
+            .arg(
+                Arg::with_name(DETECT_CONTENT)
+                    .long(DETECT_CONTENT)
+                    .help("Recognizes music files by their content instead of their extension and fixes wrong extensions"),
+            )
             .arg(
                 Arg::with_name(DIRECTORY)
                     .short("d")
                     .long(DIRECTORY)
-                    .help("Renames directories according to the album tag"),
+                    .help("Renames directories according to the album tag or the directory template"),
+            )
+            .arg(
+                Arg::with_name(DIR_TEMPLATE)
+                    .long(DIR_TEMPLATE)
+                    .takes_value(true)
+                    .value_name(FILE_TEMPLATE_VALUE)
+                    .help("Builds directory names from <TEMPLATE>, e. g. \"{artist} - <{year} - >{album}\"; \
+                    placeholders only have a value if it is the same for all files in the directory"),
             )
             .arg(
                 Arg::with_name(DRY_RUN)
@@ -62,13 +233,92 @@ The resulting file name will have the form
                     .long(DRY_RUN)
                     .help("Uses dry-run mode"),
             )
+            .arg(
+                Arg::with_name(ELLIPSIS)
+                    .long(ELLIPSIS)
+                    .takes_value(true)
+                    .value_name(ELLIPSIS_VALUE)
+                    .help("Ends names shortened by --limit-length with <MARKER>, e. g. \"…\""),
+            )
+            .arg(
+                Arg::with_name(FALLBACK)
+                    .long(FALLBACK)
+                    .takes_value(true)
+                    .value_name(FALLBACK_VALUE)
+                    .help("Guesses missing tags instead of skipping the file: <TAGS> is a comma-separated list of \
+                    album (from the directory name), artist (from the parent directory name), title (from the file name), \
+                    and track (from a leading number in the file name or the position in the directory), or all"),
+            )
+            .arg(
+                Arg::with_name(FILE_TEMPLATE)
+                    .long(FILE_TEMPLATE)
+                    .takes_value(true)
+                    .value_name(FILE_TEMPLATE_VALUE)
+                    .help("Builds file names from <TEMPLATE>, e. g. \"<{disc:02} - >{track} {artist} - {title}\"; \
+                    available placeholders are {album}, {album_artist}, {artist}, {composer}, {conductor}, {date}, \
+                    {disc}, {format}, {genre}, {original_year}, {title}, {total_discs}, {total_tracks}, {track}, \
+                    and {year}; in directory names, {artist} is the album artist if there is one"),
+            )
+            .arg(
+                Arg::with_name(FROM_PATH_PATTERN)
+                    .long(FROM_PATH_PATTERN)
+                    .takes_value(true)
+                    .value_name(FROM_PATH_PATTERN_VALUE)
+                    .help("Takes missing tags from the file and directory names matching <PATTERN>, \
+                    e. g. \"{artist}/{album} ({year})/{track} - {title}\"; available placeholders are {album}, \
+                    {album_artist}, {artist}, {disc}, {title}, {track}, and {year}"),
+            )
+            .arg(
+                Arg::with_name(JOURNAL)
+                    .short("j")
+                    .long(JOURNAL)
+                    .takes_value(true)
+                    .value_name(JOURNAL_VALUE)
+                    .help("Records all changes in <FILE> instead of a new journal in the user's data directory"),
+            )
             .arg(
                 Arg::with_name(LENGTH)
                     .short("l")
                     .long(LENGTH)
                     .takes_value(true)
                     .value_name(LENGTH_VALUE)
-                    .help("Limits the file and directory names to <LENGTH> characters"),
+                    .help("Limits the file and directory names to <LENGTH>, counted in the unit given by \
+                    --length-unit"),
+            )
+            .arg(
+                Arg::with_name(LENGTH_UNIT)
+                    .long(LENGTH_UNIT)
+                    .takes_value(true)
+                    .value_name(LENGTH_UNIT_VALUE)
+                    .possible_values(&["chars", "graphemes", "utf8", "utf16"])
+                    .help("Counts the length of names in characters, grapheme clusters, or bytes of the UTF-8 \
+                    or UTF-16 encoding (default: chars)"),
+            )
+            .arg(
+                Arg::with_name(MAX_PATH_LENGTH)
+                    .long(MAX_PATH_LENGTH)
+                    .takes_value(true)
+                    .value_name(LENGTH_VALUE)
+                    .help("Shortens file names, and the directory names when organizing, until the whole path \
+                    below the mount root fits in <LENGTH>, counted in the unit given by --length-unit; paths that \
+                    don't fit are skipped"),
+            )
+            .arg(
+                Arg::with_name(MOUNT_ROOT)
+                    .long(MOUNT_ROOT)
+                    .takes_value(true)
+                    .value_name(DIR_VALUE)
+                    .help("Measures paths for --max-path-length below <DIR> instead of the library root when \
+                    organizing or the start directory"),
+            )
+            .arg(
+                Arg::with_name(NORMALIZE)
+                    .long(NORMALIZE)
+                    .takes_value(true)
+                    .value_name(NORMALIZE_VALUE)
+                    .help("Cleans up the tag values before building names: <STEPS> is a comma-separated list of \
+                    whitespace (trims and collapses whitespace), quotes (straightens typographic quotes), \
+                    dashes (turns all kinds of dashes into hyphens), and title-case (capitalizes titles and albums), or all"),
             )
             .arg(
                 Arg::with_name(OMIT_ARTIST)
@@ -76,12 +326,85 @@ The resulting file name will have the form
                     .long(OMIT_ARTIST)
                     .help("Omit artist"),
             )
+            .arg(
+                Arg::with_name(ON_COLLISION)
+                    .long(ON_COLLISION)
+                    .takes_value(true)
+                    .value_name(ON_COLLISION_VALUE)
+                    .possible_values(&["skip", "suffix", "abort"])
+                    .help("Decides what happens if several files would get the same name: \
+                    skip them, add a \" (2)\" suffix, or abort the whole directory (default: skip)"),
+            )
+            .arg(
+                Arg::with_name(ORGANIZE_INTO)
+                    .long(ORGANIZE_INTO)
+                    .takes_value(true)
+                    .value_name(ORGANIZE_INTO_VALUE)
+                    .help("Moves the music files into an Artist/Album tree below <LIBRARY_ROOT> instead of renaming them in place"),
+            )
+            .arg(
+                Arg::with_name(ORGANIZE_TEMPLATE)
+                    .long(ORGANIZE_TEMPLATE)
+                    .takes_value(true)
+                    .value_name(FILE_TEMPLATE_VALUE)
+                    .help("Builds the directories below <LIBRARY_ROOT> from <TEMPLATE> with \"/\" separating the levels, \
+                    defaults to \"{artist}/<{year} - >{album}\""),
+            )
+            .arg(
+                Arg::with_name(OUTPUT)
+                    .long(OUTPUT)
+                    .takes_value(true)
+                    .value_name(OUTPUT_VALUE)
+                    .possible_values(&["text", "json", "jsonl"])
+                    .help("Reports directories and operations as text, as one JSON array, \
+                    or as one JSON record per line (default: text)"),
+            )
+            .arg(
+                Arg::with_name(OVERRIDE_TAGS)
+                    .long(OVERRIDE_TAGS)
+                    .help("Lets the values taken from the path replace existing tags instead of only filling in missing ones"),
+            )
+            .arg(
+                Arg::with_name(PROFILE)
+                    .long(PROFILE)
+                    .takes_value(true)
+                    .value_name(PROFILE_VALUE)
+                    .help("Uses the values of the [profile.<NAME>] section in the configuration files"),
+            )
             .arg(
                 Arg::with_name(REMOVE)
                     .short("r")
                     .long(REMOVE)
                     .help("Removes non-music files"),
             )
+            .arg(
+                Arg::with_name(REPLACE)
+                    .long(REPLACE)
+                    .takes_value(true)
+                    .value_names(REPLACE_VALUES)
+                    .number_of_values(2)
+                    .multiple(true)
+                    .help("Replaces the matches of <REGEX> in the tag values with <REPLACEMENT>, which may refer to \
+                    groups like $1, before building names; may be given several times and is applied in this order"),
+            )
+            .arg(
+                Arg::with_name(SAVE_PLAN)
+                    .long(SAVE_PLAN)
+                    .takes_value(true)
+                    .value_name(SAVE_PLAN_VALUE)
+                    .help("Saves everything this run is going to do to <FILE>, usually together with --dry-run, \
+                    to carry it out later with the apply subcommand"),
+            )
+            .arg(
+                Arg::with_name(SHORTEN)
+                    .long(SHORTEN)
+                    .takes_value(true)
+                    .value_name(SHORTEN_VALUE)
+                    .possible_values(&["truncate", "smart"])
+                    .help("Shortens file names exceeding --limit-length by cutting them at the end (truncate, the \
+                    default) or by dropping the artist, suffixes in parentheses, and abbreviating words before \
+                    cutting the title (smart)"),
+            )
             .arg(
                 // this is a positional argument
                 Arg::with_name(START_DIR)
@@ -89,64 +412,882 @@ The resulting file name will have the form
                     .index(1)
                     .required(true),
             )
+            .arg(
+                Arg::with_name(TARGET_FS)
+                    .long(TARGET_FS)
+                    .takes_value(true)
+                    .value_name(TARGET_FS_VALUE)
+                    .possible_values(&["posix", "windows", "fat32", "exfat", "macos", "iso9660"])
+                    .help("Only uses characters in names the file system <FS> allows and limits names to its \
+                    maximum length (default: windows)"),
+            )
+            .arg(
+                Arg::with_name(TITLE_CASE_LANGUAGE)
+                    .long(TITLE_CASE_LANGUAGE)
+                    .takes_value(true)
+                    .value_name(TITLE_CASE_LANGUAGE_VALUE)
+                    .possible_values(&["en", "de", "fr", "es", "it", "nl"])
+                    .help("Keeps the small words of <LANG> lowercase in title case (default: en)"),
+            )
+            .arg(
+                Arg::with_name(VARIOUS_ARTISTS)
+                    .long(VARIOUS_ARTISTS)
+                    .takes_value(true)
+                    .value_name(VARIOUS_ARTISTS_VALUE)
+                    .help("Uses <LABEL> as artist in the directory names of compilations, \
+                    whose file names always keep the artist (default: \"Various Artists\")"),
+            )
             .arg(
                 Arg::with_name(VERBOSE)
                     .short("v")
                     .long(VERBOSE)
                     .help("Be verbose"),
             )
+            .arg(
+                Arg::with_name(WRITE_NORMALIZED)
+                    .long(WRITE_NORMALIZED)
+                    .help("Writes the normalized values into the tags of MP3, FLAC, and MP4 files"),
+            )
+            .arg(
+                Arg::with_name(WRITE_TAGS)
+                    .long(WRITE_TAGS)
+                    .help("Writes the values taken from the path into the tags of MP3, FLAC, and MP4 files"),
+            )
+            .subcommand(
+                SubCommand::with_name(APPLY)
+                    .about("Carries out a plan saved with --save-plan if none of its files have changed since")
+                    .arg(
+                        Arg::with_name(PLAN_VALUE)
+                            .help("The plan to carry out")
+                            .index(1)
+                            .required(true),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name(EXPLAIN_NAME)
+                    .about("Shows which sanitize rules and rules of the target file system change <NAME>")
+                    .arg(
+                        Arg::with_name(NAME_VALUE)
+                            .help("The text to turn into a file or directory name")
+                            .index(1)
+                            .required(true),
+                    )
+                    .arg(
+                        Arg::with_name(DIR_VALUE)
+                            .help("The directory whose configuration file to use")
+                            .index(2),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name(TAG)
+                    .about("Writes album, artist, title, track, and disc into the music files below <DIR>, \
+                    taken from a CSV file, a path pattern, or the directory names")
+                    .arg(
+                        Arg::with_name(DIR_VALUE)
+                            .help("The directory to start from")
+                            .index(1)
+                            .required(true),
+                    )
+                    .arg(
+                        Arg::with_name(CSV)
+                            .long(CSV)
+                            .takes_value(true)
+                            .value_name(CSV_VALUE)
+                            .help("Takes the tags from a CSV file whose header row names the columns: \
+                            \"path\" (relative to <DIR>) and any of album, album_artist, artist, disc, title, track, and year"),
+                    )
+                    .arg(
+                        Arg::with_name(PATTERN)
+                            .long(PATTERN)
+                            .takes_value(true)
+                            .value_name(FROM_PATH_PATTERN_VALUE)
+                            .help("Takes the tags from the file and directory names matching <PATTERN>, \
+                            e. g. \"{artist}/{album} ({year})/{track} - {title}\""),
+                    )
+                    .arg(
+                        Arg::with_name(ALBUM_FROM_DIR)
+                            .long(ALBUM_FROM_DIR)
+                            .help("Takes the album from the name of the directory containing the file"),
+                    )
+                    .group(
+                        ArgGroup::with_name("values")
+                            .args(&[CSV, PATTERN, ALBUM_FROM_DIR])
+                            .multiple(true)
+                            .required(true),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name(UNDO)
+                    .about("Reverts all changes recorded in a journal, skipping files that have changed since")
+                    .arg(
+                        Arg::with_name(JOURNAL_VALUE)
+                            .help("The journal written by the run to revert")
+                            .index(1)
+                            .required(true),
+                    ),
+            );
+        for (_, negated, help) in NEGATED_FLAGS {
+            app = app.arg(Arg::with_name(negated).long(negated).help(help));
+        }
+        let matches = app.get_matches();
 
-            .get_matches();
-
-        // the directory is mandatory
-        let start_dir = matches.value_of(START_DIR).unwrap();
-        let start_dir = match util::string_to_path(&start_dir) {
-            Ok(path) => path,
-            Err(_) => {
-                eprintln!("Couldn't find the path \"{}\"", start_dir);
-                process::exit(1);
+        // the plan, the journal, and the directory to tag are mandatory
+        let mut tag_dir = None;
+        let mut explain_dir = None;
+        let command = match matches.subcommand() {
+            (APPLY, Some(apply_matches)) => {
+                Command::Apply(PathBuf::from(apply_matches.value_of(PLAN_VALUE).unwrap()))
             }
+            (EXPLAIN_NAME, Some(explain_matches)) => {
+                explain_dir = explain_matches.value_of(DIR_VALUE);
+                Command::ExplainName(explain_matches.value_of(NAME_VALUE).unwrap().to_string())
+            }
+            (TAG, Some(tag_matches)) => {
+                tag_dir = tag_matches.value_of(DIR_VALUE);
+                let pattern = tag_matches.value_of(PATTERN).map(|pattern| {
+                    PathPattern::parse(pattern).unwrap_or_else(|err| {
+                        eprintln!("Cannot parse path pattern \"{}\": {}", pattern, err);
+                        process::exit(1);
+                    })
+                });
+                Command::Tag(TagSources {
+                    csv: tag_matches.value_of(CSV).map(PathBuf::from),
+                    pattern,
+                    album_from_dir: tag_matches.is_present(ALBUM_FROM_DIR),
+                })
+            }
+            (UNDO, Some(undo_matches)) => {
+                Command::Undo(PathBuf::from(undo_matches.value_of(JOURNAL_VALUE).unwrap()))
+            }
+            _ => Command::Rename,
+        };
+
+        // the directory is mandatory unless using a subcommand
+        let start_dir = match matches.value_of(START_DIR).or(tag_dir).or(explain_dir) {
+            None => PathBuf::new(),
+            Some(start_dir) => match util::string_to_path(start_dir) {
+                Ok(path) => path,
+                Err(_) => {
+                    eprintln!("Couldn't find the path \"{}\"", start_dir);
+                    process::exit(1);
+                }
+            },
         };
 
-        let name_length = match matches.value_of(LENGTH) {
-            None => 0,
+        let parse_length = |name: &str| match matches.value_of(name) {
+            None => None,
             Some(num) => match num.parse::<u32>() {
-                Ok(val) => val,
+                Ok(val) => Some(val),
                 Err(_) => {
                     eprintln!("Cannot parse length \"{}\"", num);
                     process::exit(1);
                 }
             },
         };
+        let limit_length = parse_length(LENGTH);
 
-        Config {
-            dry_run: matches.is_present(DRY_RUN),
-            name_length,
-            omit_artist: matches.is_present(OMIT_ARTIST),
-            remove_artist: matches.is_present(ARTIST),
-            remove_ordinary_files: matches.is_present(REMOVE),
-            rename_directory: matches.is_present(DIRECTORY),
-            shorten_names: matches.is_present(LENGTH),
+        // the later of a flag and its "no-" option wins
+        let flag = |name: &str| {
+            let negated = NEGATED_FLAGS
+                .iter()
+                .find(|(flag, _, _)| *flag == name)
+                .and_then(|(_, negated, _)| matches.index_of(negated));
+            match (matches.index_of(name), negated) {
+                (Some(on), Some(off)) => Some(on > off),
+                (Some(_), None) => Some(true),
+                (None, Some(_)) => Some(false),
+                (None, None) => None,
+            }
+        };
+        let command_line = Settings {
+            artist: flag(ARTIST),
+            detect_content: flag(DETECT_CONTENT),
+            directory: flag(DIRECTORY),
+            dir_template: matches.value_of(DIR_TEMPLATE).map(String::from),
+            dry_run: flag(DRY_RUN),
+            ellipsis: matches.value_of(ELLIPSIS).map(String::from),
+            fallback: matches.value_of(FALLBACK).map(String::from),
+            file_template: matches.value_of(FILE_TEMPLATE).map(String::from),
+            from_path_pattern: matches.value_of(FROM_PATH_PATTERN).map(String::from),
+            journal: matches.value_of(JOURNAL).map(PathBuf::from),
+            length_unit: matches.value_of(LENGTH_UNIT).map(String::from),
+            limit_length,
+            max_path_length: parse_length(MAX_PATH_LENGTH),
+            mount_root: matches.value_of(MOUNT_ROOT).map(PathBuf::from),
+            normalize: matches.value_of(NORMALIZE).map(String::from),
+            omit_artist: flag(OMIT_ARTIST),
+            on_collision: matches.value_of(ON_COLLISION).map(String::from),
+            organize_into: matches.value_of(ORGANIZE_INTO).map(PathBuf::from),
+            organize_template: matches.value_of(ORGANIZE_TEMPLATE).map(String::from),
+            output: matches.value_of(OUTPUT).map(String::from),
+            override_tags: flag(OVERRIDE_TAGS),
+            remove: flag(REMOVE),
+            replace: matches.values_of(REPLACE).map(|values| {
+                let values: Vec<&str> = values.collect();
+                values
+                    .chunks(2)
+                    .map(|pair| (pair[0].to_string(), pair[1].to_string()))
+                    .collect()
+            }),
+            // sanitize rules can only be set in configuration files
+            sanitize: None,
+            save_plan: matches.value_of(SAVE_PLAN).map(PathBuf::from),
+            shorten: matches.value_of(SHORTEN).map(String::from),
+            target_fs: matches.value_of(TARGET_FS).map(String::from),
+            title_case_language: matches.value_of(TITLE_CASE_LANGUAGE).map(String::from),
+            various_artists: matches.value_of(VARIOUS_ARTISTS).map(String::from),
+            verbose: flag(VERBOSE),
+            write_normalized: flag(WRITE_NORMALIZED),
+            write_tags: flag(WRITE_TAGS),
+            profile: HashMap::new(),
+        };
+
+        let mut config = Config {
+            command,
             start_dir,
-            verbose: matches.is_present(VERBOSE),
+            ..Config::default()
+        };
+        if config.start_dir != PathBuf::new() {
+            config.set_source("start_dir", &Source::CommandLine);
+        }
+        // command-line values override those in the configuration files
+        let result = config
+            .apply_files(matches.value_of(PROFILE))
+            .and_then(|_| config.apply(&command_line, &Source::CommandLine));
+        if let Err(err) = result {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+
+        config
+    }
+
+    /// Applies the configuration files and, if given, the profile selected
+    /// from them
+    fn apply_files(&mut self, profile: Option<&str>) -> Result<(), String> {
+        let mut files = Vec::new();
+        for path in settings::file_paths(&self.start_dir) {
+            if let Some(settings) = Settings::read(&path)? {
+                files.push((path, settings));
+            }
+        }
+
+        for (path, settings) in &files {
+            self.apply(settings, &Source::File(path.clone()))
+                .map_err(|err| format!("{}: {}", path.to_string_lossy(), err))?;
+        }
+
+        if let Some(name) = profile {
+            let mut is_found = false;
+            for (path, settings) in &files {
+                if let Some(profile_settings) = settings.profile.get(name) {
+                    self.apply(
+                        profile_settings,
+                        &Source::Profile(name.to_string(), path.clone()),
+                    )
+                    .map_err(|err| format!("{}: {}", path.to_string_lossy(), err))?;
+                    is_found = true;
+                }
+            }
+            if !is_found {
+                return Err(format!("Unknown profile \"{}\"", name));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Overrides the values given in `settings`, remembering where they came
+    /// from. Relative paths in configuration files are relative to the file.
+    pub fn apply(&mut self, settings: &Settings, source: &Source) -> Result<(), String> {
+        if let Some(value) = settings.artist {
+            self.remove_artist = value;
+            self.set_source("remove_artist", source);
+        }
+        if let Some(value) = settings.detect_content {
+            self.detect_content = value;
+            self.set_source("detect_content", source);
+        }
+        if let Some(value) = settings.directory {
+            self.rename_directory = value;
+            self.set_source("rename_directory", source);
+        }
+        if let Some(value) = &settings.dir_template {
+            self.dir_template = parse_template(value, "directory")?;
+            self.set_source("dir_template", source);
+        }
+        if let Some(value) = settings.dry_run {
+            self.dry_run = value;
+            self.set_source("dry_run", source);
+        }
+        if let Some(value) = &settings.ellipsis {
+            self.ellipsis = value.clone();
+            self.set_source("ellipsis", source);
+        }
+        if let Some(value) = &settings.fallback {
+            self.fallbacks = value.parse()?;
+            self.set_source("fallbacks", source);
+        }
+        if let Some(value) = &settings.file_template {
+            self.file_template = parse_template(value, "file")?;
+            self.set_source("file_template", source);
+        }
+        if let Some(value) = &settings.from_path_pattern {
+            self.path_pattern = Some(
+                PathPattern::parse(value)
+                    .map_err(|err| format!("Cannot parse path pattern \"{}\": {}", value, err))?,
+            );
+            self.set_source("path_pattern", source);
+        }
+        if let Some(value) = &settings.journal {
+            self.journal = Some(source.resolve(value));
+            self.set_source("journal", source);
+        }
+        if let Some(value) = &settings.length_unit {
+            self.length_unit = value.parse()?;
+            self.set_source("length_unit", source);
+        }
+        if let Some(value) = settings.limit_length {
+            self.name_length = value;
+            self.shorten_names = true;
+            self.set_source("name_length", source);
+            self.set_source("shorten_names", source);
+        }
+        if let Some(value) = settings.max_path_length {
+            self.max_path_length = Some(value);
+            self.set_source("max_path_length", source);
+        }
+        if let Some(value) = &settings.mount_root {
+            self.mount_root = Some(source.resolve(value));
+            self.set_source("mount_root", source);
+        }
+        if let Some(value) = &settings.normalize {
+            self.normalize = value.parse()?;
+            self.set_source("normalize", source);
+        }
+        if let Some(value) = settings.omit_artist {
+            self.omit_artist = value;
+            self.set_source("omit_artist", source);
+        }
+        if let Some(value) = &settings.on_collision {
+            self.on_collision = value.parse()?;
+            self.set_source("on_collision", source);
+        }
+        if let Some(value) = &settings.organize_into {
+            self.organize_into = Some(source.resolve(value));
+            self.set_source("organize_into", source);
+        }
+        if let Some(value) = &settings.organize_template {
+            self.organize_template = parse_template(value, "organize")?;
+            self.set_source("organize_template", source);
+        }
+        if let Some(value) = &settings.output {
+            self.output = value.parse()?;
+            self.set_source("output", source);
+        }
+        if let Some(value) = settings.override_tags {
+            self.override_tags = value;
+            self.set_source("override_tags", source);
+        }
+        if let Some(value) = settings.remove {
+            self.remove_ordinary_files = value;
+            self.set_source("remove_ordinary_files", source);
+        }
+        if let Some(value) = &settings.replace {
+            self.replacements = value
+                .iter()
+                .map(|(pattern, replacement)| Replacement::new(pattern, replacement))
+                .collect::<Result<_, _>>()?;
+            self.set_source("replacements", source);
+        }
+        if let Some(rules) = &settings.sanitize {
+            self.sanitize_rules = rules
+                .iter()
+                .enumerate()
+                .map(|(index, rule)| {
+                    let sanitize_rule = match (&rule.literal, &rule.regex) {
+                        (Some(literal), None) => Rule::literal(literal, &rule.replacement),
+                        (None, Some(regex)) => Rule::regex(regex, &rule.replacement)?,
+                        _ => {
+                            return Err(format!(
+                                "Sanitize rule {} needs either a literal or a regex",
+                                index + 1
+                            ))
+                        }
+                    };
+                    let target_fs = rule
+                        .target_fs
+                        .iter()
+                        .flatten()
+                        .map(|name| name.parse())
+                        .collect::<Result<Vec<TargetFs>, String>>()?;
+                    Ok(sanitize_rule.on(&target_fs))
+                })
+                .collect::<Result<Vec<Rule>, String>>()?;
+            self.set_source("sanitize_rules", source);
+        }
+        if let Some(value) = &settings.save_plan {
+            self.save_plan = Some(source.resolve(value));
+            self.set_source("save_plan", source);
+        }
+        if let Some(value) = &settings.shorten {
+            self.shorten_strategy = value.parse()?;
+            self.set_source("shorten_strategy", source);
+        }
+        if let Some(value) = &settings.target_fs {
+            self.target_fs = value.parse()?;
+            self.set_source("target_fs", source);
+        }
+        if let Some(value) = &settings.title_case_language {
+            self.title_case_language = value.parse()?;
+            self.set_source("title_case_language", source);
+        }
+        if let Some(value) = &settings.various_artists {
+            self.various_artists = value.clone();
+            self.set_source("various_artists", source);
+        }
+        if let Some(value) = settings.verbose {
+            self.verbose = value;
+            self.set_source("verbose", source);
         }
+        if let Some(value) = settings.write_normalized {
+            self.write_normalized = value;
+            self.set_source("write_normalized", source);
+        }
+        if let Some(value) = settings.write_tags {
+            self.write_tags = value;
+            self.set_source("write_tags", source);
+        }
+
+        Ok(())
+    }
+
+    fn set_source(&mut self, field: &'static str, source: &Source) {
+        self.sources.insert(field, source.clone());
+    }
+
+    /// Returns the length names are shortened to, if they are
+    pub fn name_limit(&self) -> Option<usize> {
+        if self.shorten_names {
+            Some(self.name_length as usize)
+        } else {
+            None
+        }
+    }
+
+    /// Returns where the value of a field came from
+    pub fn source(&self, field: &str) -> &Source {
+        self.sources.get(field).unwrap_or(&Source::Default)
     }
 }
 
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            command: Command::Rename,
+            detect_content: false,
+            dir_template: Template::parse(music_file::DEFAULT_DIR_TEMPLATE)
+                .expect("The default directory template must be valid"),
+            dry_run: false,
+            ellipsis: String::new(),
+            fallbacks: Fallbacks::default(),
+            file_template: Template::parse(music_file::DEFAULT_FILE_TEMPLATE)
+                .expect("The default file template must be valid"),
+            journal: None,
+            length_unit: LengthUnit::Chars,
+            max_path_length: None,
+            mount_root: None,
+            name_length: 0,
+            normalize: Steps::default(),
+            omit_artist: false,
+            on_collision: CollisionStrategy::Skip,
+            organize_into: None,
+            organize_template: Template::parse(music_file::DEFAULT_ORGANIZE_TEMPLATE)
+                .expect("The default organize template must be valid"),
+            output: OutputFormat::Text,
+            override_tags: false,
+            path_pattern: None,
+            remove_artist: false,
+            remove_ordinary_files: false,
+            rename_directory: false,
+            replacements: Vec::new(),
+            sanitize_rules: sanitize::default_rules(),
+            save_plan: None,
+            shorten_names: false,
+            shorten_strategy: ShortenStrategy::Truncate,
+            sources: HashMap::new(),
+            start_dir: PathBuf::new(),
+            target_fs: TargetFs::Windows,
+            title_case_language: Language::English,
+            various_artists: music_file::DEFAULT_VARIOUS_ARTISTS.to_string(),
+            verbose: false,
+            write_normalized: false,
+            write_tags: false,
+        }
+    }
+}
+
+/// Parses a template and makes sure it only uses known placeholders
+fn parse_template(source: &str, kind: &str) -> Result<Template, String> {
+    let template = Template::parse(source)
+        .map_err(|err| format!("Cannot parse {} template \"{}\": {}", kind, source, err))?;
+    for name in template.placeholders() {
+        if !music_file::PLACEHOLDERS.contains(&name) {
+            return Err(format!(
+                "Cannot parse {} template \"{}\": Unknown placeholder \"{{{}}}\"",
+                kind, source, name
+            ));
+        }
+    }
+    Ok(template)
+}
+
 impl fmt::Display for Config {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        writeln!(f, "Dry run:                  {:?}", self.dry_run)?;
-        writeln!(f, "Using path                {:?}", self.start_dir)?;
-        writeln!(f, "Name length limit:        {:?}", self.name_length)?;
-        writeln!(f, "Omit artist:              {:?}", self.omit_artist)?;
-        writeln!(f, "Remove artist:            {:?}", self.remove_artist)?;
         writeln!(
             f,
-            "Remove ordinary files:    {:?}",
-            self.remove_ordinary_files
+            "Detect by content:        {:?} ({})",
+            self.detect_content,
+            self.source("detect_content")
+        )?;
+        writeln!(
+            f,
+            "Directory template:       {} ({})",
+            self.dir_template,
+            self.source("dir_template")
+        )?;
+        writeln!(
+            f,
+            "Dry run:                  {:?} ({})",
+            self.dry_run,
+            self.source("dry_run")
+        )?;
+        writeln!(
+            f,
+            "Ellipsis:                 {:?} ({})",
+            self.ellipsis,
+            self.source("ellipsis")
+        )?;
+        writeln!(
+            f,
+            "Fallbacks:                {} ({})",
+            self.fallbacks,
+            self.source("fallbacks")
+        )?;
+        writeln!(
+            f,
+            "Using path                {:?} ({})",
+            self.start_dir,
+            self.source("start_dir")
+        )?;
+        writeln!(
+            f,
+            "File template:            {} ({})",
+            self.file_template,
+            self.source("file_template")
+        )?;
+        writeln!(
+            f,
+            "Journal:                  {:?} ({})",
+            self.journal,
+            self.source("journal")
+        )?;
+        writeln!(
+            f,
+            "Length unit:              {} ({})",
+            self.length_unit,
+            self.source("length_unit")
+        )?;
+        writeln!(
+            f,
+            "Max path length:          {:?} ({})",
+            self.max_path_length,
+            self.source("max_path_length")
+        )?;
+        writeln!(
+            f,
+            "Mount root:               {:?} ({})",
+            self.mount_root,
+            self.source("mount_root")
+        )?;
+        writeln!(
+            f,
+            "Name length limit:        {:?} ({})",
+            self.name_length,
+            self.source("name_length")
+        )?;
+        writeln!(
+            f,
+            "Normalize:                {} ({})",
+            self.normalize,
+            self.source("normalize")
+        )?;
+        writeln!(
+            f,
+            "Omit artist:              {:?} ({})",
+            self.omit_artist,
+            self.source("omit_artist")
+        )?;
+        writeln!(
+            f,
+            "On collision:             {} ({})",
+            self.on_collision,
+            self.source("on_collision")
+        )?;
+        writeln!(
+            f,
+            "Organize into:            {:?} ({})",
+            self.organize_into,
+            self.source("organize_into")
+        )?;
+        writeln!(
+            f,
+            "Organize template:        {} ({})",
+            self.organize_template,
+            self.source("organize_template")
+        )?;
+        writeln!(
+            f,
+            "Output:                   {} ({})",
+            self.output,
+            self.source("output")
         )?;
-        writeln!(f, "Rename directory:         {:?}", self.rename_directory)?;
-        writeln!(f, "Shorten names:            {:?}", self.shorten_names)?;
-        writeln!(f, "Verbose mode:             {:?}", self.verbose)
+        writeln!(
+            f,
+            "Override tags:            {:?} ({})",
+            self.override_tags,
+            self.source("override_tags")
+        )?;
+        writeln!(
+            f,
+            "Path pattern:             {} ({})",
+            self.path_pattern
+                .as_ref()
+                .map(PathPattern::to_string)
+                .unwrap_or_else(|| "None".to_string()),
+            self.source("path_pattern")
+        )?;
+        writeln!(
+            f,
+            "Remove artist:            {:?} ({})",
+            self.remove_artist,
+            self.source("remove_artist")
+        )?;
+        writeln!(
+            f,
+            "Remove ordinary files:    {:?} ({})",
+            self.remove_ordinary_files,
+            self.source("remove_ordinary_files")
+        )?;
+        writeln!(
+            f,
+            "Rename directory:         {:?} ({})",
+            self.rename_directory,
+            self.source("rename_directory")
+        )?;
+        let replacements: Vec<String> = self
+            .replacements
+            .iter()
+            .map(Replacement::to_string)
+            .collect();
+        writeln!(
+            f,
+            "Replacements:             [{}] ({})",
+            replacements.join(", "),
+            self.source("replacements")
+        )?;
+        let rules: Vec<String> = self.sanitize_rules.iter().map(Rule::to_string).collect();
+        writeln!(
+            f,
+            "Sanitize rules:           [{}] ({})",
+            rules.join(", "),
+            self.source("sanitize_rules")
+        )?;
+        writeln!(
+            f,
+            "Save plan:                {:?} ({})",
+            self.save_plan,
+            self.source("save_plan")
+        )?;
+        writeln!(
+            f,
+            "Shorten names:            {:?} ({})",
+            self.shorten_names,
+            self.source("shorten_names")
+        )?;
+        writeln!(
+            f,
+            "Shorten strategy:         {} ({})",
+            self.shorten_strategy,
+            self.source("shorten_strategy")
+        )?;
+        writeln!(
+            f,
+            "Target file system:       {} ({})",
+            self.target_fs,
+            self.source("target_fs")
+        )?;
+        writeln!(
+            f,
+            "Title case language:      {} ({})",
+            self.title_case_language,
+            self.source("title_case_language")
+        )?;
+        writeln!(
+            f,
+            "Various artists label:    {:?} ({})",
+            self.various_artists,
+            self.source("various_artists")
+        )?;
+        writeln!(
+            f,
+            "Verbose mode:             {:?} ({})",
+            self.verbose,
+            self.source("verbose")
+        )?;
+        writeln!(
+            f,
+            "Write normalized:         {:?} ({})",
+            self.write_normalized,
+            self.source("write_normalized")
+        )?;
+        writeln!(
+            f,
+            "Write tags:               {:?} ({})",
+            self.write_tags,
+            self.source("write_tags")
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply() {
+        let file = PathBuf::from("/home/user/.config/mp3rename/config.toml");
+        let settings = Settings::parse(
+            r#"
+            artist = true
+            limit-length = 64
+            on-collision = "suffix"
+
+            [profile.car-usb]
+            limit-length = 32
+            "#,
+        )
+        .unwrap();
+
+        let mut config = Config::default();
+        config
+            .apply(&settings, &Source::File(file.clone()))
+            .unwrap();
+        config
+            .apply(
+                &settings.profile["car-usb"],
+                &Source::Profile("car-usb".to_string(), file.clone()),
+            )
+            .unwrap();
+        let command_line = Settings {
+            directory: Some(true),
+            ..Settings::default()
+        };
+        config.apply(&command_line, &Source::CommandLine).unwrap();
+
+        assert!(config.remove_artist);
+        assert_eq!(config.source("remove_artist"), &Source::File(file.clone()));
+        assert_eq!(config.name_length, 32);
+        assert!(config.shorten_names);
+        assert_eq!(
+            config.source("name_length"),
+            &Source::Profile("car-usb".to_string(), file)
+        );
+        assert_eq!(config.on_collision, CollisionStrategy::Suffix);
+        assert!(config.rename_directory);
+        assert_eq!(config.source("rename_directory"), &Source::CommandLine);
+        assert_eq!(config.source("verbose"), &Source::Default);
+
+        let invalid = Settings {
+            file_template: Some("{unknown}".to_string()),
+            ..Settings::default()
+        };
+        assert!(config.apply(&invalid, &Source::CommandLine).is_err());
+    }
+
+    #[test]
+    fn test_apply_relative_paths() {
+        let file = PathBuf::from("/music/.mp3rename.toml");
+        let settings = Settings::parse(
+            r#"
+            journal = "journals/music.jsonl"
+            mount-root = "/media/usb"
+
+            [profile.car-usb]
+            organize-into = "../usb"
+            "#,
+        )
+        .unwrap();
+
+        let mut config = Config::default();
+        config
+            .apply(&settings, &Source::File(file.clone()))
+            .unwrap();
+        config
+            .apply(
+                &settings.profile["car-usb"],
+                &Source::Profile("car-usb".to_string(), file),
+            )
+            .unwrap();
+        assert_eq!(
+            config.journal,
+            Some(PathBuf::from("/music/journals/music.jsonl"))
+        );
+        assert_eq!(config.mount_root, Some(PathBuf::from("/media/usb")));
+        assert_eq!(config.organize_into, Some(PathBuf::from("/music/../usb")));
+
+        // paths on the command line stay relative to the working directory
+        let command_line = Settings {
+            save_plan: Some(PathBuf::from("plan.json")),
+            ..Settings::default()
+        };
+        config.apply(&command_line, &Source::CommandLine).unwrap();
+        assert_eq!(config.save_plan, Some(PathBuf::from("plan.json")));
+    }
+
+    #[test]
+    fn test_apply_sanitize_rules() {
+        let settings = Settings::parse(
+            r#"
+            [[sanitize]]
+            literal = "/"
+            replacement = "-"
+
+            [[sanitize]]
+            regex = "^The (.*)"
+            replacement = "$1, The"
+
+            [[sanitize]]
+            literal = "$"
+            replacement = "S"
+            target-fs = ["fat32"]
+            "#,
+        )
+        .unwrap();
+        let mut config = Config::default();
+        config.apply(&settings, &Source::CommandLine).unwrap();
+        // the rules replace the default ones, so "$" is kept
+        assert_eq!(
+            util::sanitize_file_or_directory_name("The Foos/Bars $", &config),
+            "Foos-Bars $, The"
+        );
+        config.target_fs = TargetFs::Fat32;
+        assert_eq!(
+            util::sanitize_file_or_directory_name("The Foos/Bars $", &config),
+            "Foos-Bars S, The"
+        );
+
+        let invalid = Settings::parse("sanitize = [{ replacement = \"-\" }]").unwrap();
+        assert!(config.apply(&invalid, &Source::CommandLine).is_err());
     }
 }
@@ -0,0 +1,222 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::fmt::Formatter;
+use std::path::Path;
+use std::str::FromStr;
+
+use regex::Regex;
+
+use crate::music_metadata::RawTags;
+
+/// Which missing tags are guessed from the path of a music file instead of
+/// skipping the file
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Fallbacks {
+    /// The album from the name of the directory
+    pub album: bool,
+    /// The artist from the name of the directory's parent
+    pub artist: bool,
+    /// The title from the file name without a leading track number
+    pub title: bool,
+    /// The track number from a leading number in the file name, else from
+    /// the file's position in the directory
+    pub track: bool,
+}
+
+impl Fallbacks {
+    /// Fills in the missing tags that may be guessed. `position` is the
+    /// place of the file among the music files of its directory, counting
+    /// from 1. Returns a description of each guess.
+    pub fn apply(&self, tags: &mut RawTags, path: &Path, position: usize) -> Vec<String> {
+        let mut guesses = Vec::new();
+        let dir = path.parent();
+        let stem = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        if self.album && tags.album.is_none() {
+            if let Some(album) = dir.and_then(name_of) {
+                guesses.push(format!("album \"{}\" from the directory name", album));
+                tags.album = Some(album);
+            }
+        }
+        if self.artist && tags.artist.is_none() {
+            if let Some(artist) = dir.and_then(Path::parent).and_then(name_of) {
+                guesses.push(format!(
+                    "artist \"{}\" from the parent directory name",
+                    artist
+                ));
+                tags.artist = Some(artist);
+            }
+        }
+        if self.title && tags.title.is_none() {
+            let title = without_leading_number(&stem);
+            if !title.is_empty() {
+                guesses.push(format!("title \"{}\" from the file name", title));
+                tags.title = Some(title);
+            }
+        }
+        if self.track && tags.track.is_none() {
+            match leading_number(&stem) {
+                Some(track) => {
+                    guesses.push(format!("track number {} from the file name", track));
+                    tags.track = Some(track);
+                }
+                None => {
+                    if let Ok(track) = u16::try_from(position) {
+                        guesses.push(format!(
+                            "track number {} from the position in the directory",
+                            track
+                        ));
+                        tags.track = Some(track);
+                    }
+                }
+            }
+        }
+
+        guesses
+    }
+}
+
+impl FromStr for Fallbacks {
+    type Err = String;
+
+    /// Parses a comma-separated list of album, artist, title, and track, or
+    /// "all", or "none"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fallbacks = Fallbacks::default();
+        for name in s.split(',').map(str::trim) {
+            match name {
+                "album" => fallbacks.album = true,
+                "artist" => fallbacks.artist = true,
+                "title" => fallbacks.title = true,
+                "track" => fallbacks.track = true,
+                "all" => {
+                    fallbacks = Fallbacks {
+                        album: true,
+                        artist: true,
+                        title: true,
+                        track: true,
+                    }
+                }
+                "none" => fallbacks = Fallbacks::default(),
+                _ => {
+                    return Err(format!(
+                        "Unknown fallback \"{}\", use album, artist, title, track, all, or none",
+                        name
+                    ))
+                }
+            }
+        }
+        Ok(fallbacks)
+    }
+}
+
+impl fmt::Display for Fallbacks {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let names: Vec<&str> = [
+            (self.album, "album"),
+            (self.artist, "artist"),
+            (self.title, "title"),
+            (self.track, "track"),
+        ]
+        .iter()
+        .filter(|(is_on, _)| *is_on)
+        .map(|(_, name)| *name)
+        .collect();
+        if names.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(f, "{}", names.join(","))
+        }
+    }
+}
+
+fn name_of(path: &Path) -> Option<String> {
+    path.file_name()
+        .map(|name| name.to_string_lossy().trim().to_string())
+        .filter(|name| !name.is_empty())
+}
+
+fn leading_number(stem: &str) -> Option<u16> {
+    let re = Regex::new(r"^\s*(\d+)").unwrap();
+    re.captures(stem)
+        .and_then(|captures| captures[1].parse().ok())
+}
+
+/// Removes a leading track number and the separators following it, e. g.
+/// "03 - Title" becomes "Title"
+fn without_leading_number(stem: &str) -> String {
+    let re = Regex::new(r"^\s*\d+\s*[-._)\]]*\s*").unwrap();
+    let title = re.replace(stem, "").trim().to_string();
+    if title.is_empty() {
+        stem.trim().to_string()
+    } else {
+        title
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply() {
+        let all: Fallbacks = "all".parse().unwrap();
+        let path = Path::new("/music/The Foos/Greatest Hits/03 - Foo de Foo.mp3");
+
+        let mut tags = RawTags::default();
+        let guesses = all.apply(&mut tags, path, 5);
+        assert_eq!(tags.album, Some("Greatest Hits".to_string()));
+        assert_eq!(tags.artist, Some("The Foos".to_string()));
+        assert_eq!(tags.title, Some("Foo de Foo".to_string()));
+        assert_eq!(tags.track, Some(3));
+        assert_eq!(guesses.len(), 4);
+
+        // existing tags are kept, and without a number the position is used
+        let mut tags = RawTags {
+            title: Some("Bar".to_string()),
+            ..RawTags::default()
+        };
+        let guesses = all.apply(&mut tags, Path::new("/music/Hits/Foo.mp3"), 5);
+        assert_eq!(tags.title, Some("Bar".to_string()));
+        assert_eq!(tags.track, Some(5));
+        assert_eq!(
+            guesses.last(),
+            Some(&"track number 5 from the position in the directory".to_string())
+        );
+
+        // only the fallbacks switched on are used
+        let mut tags = RawTags::default();
+        let guesses = Fallbacks::default().apply(&mut tags, path, 5);
+        assert!(guesses.is_empty());
+        assert_eq!(tags, RawTags::default());
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            "track, title".parse(),
+            Ok(Fallbacks {
+                title: true,
+                track: true,
+                ..Fallbacks::default()
+            })
+        );
+        assert_eq!("none".parse(), Ok(Fallbacks::default()));
+        assert_eq!(
+            "all".parse::<Fallbacks>().unwrap().to_string(),
+            "album,artist,title,track"
+        );
+        assert!("genre".parse::<Fallbacks>().is_err());
+    }
+
+    #[test]
+    fn test_without_leading_number() {
+        assert_eq!(without_leading_number("03 - Foo"), "Foo");
+        assert_eq!(without_leading_number("1. Foo"), "Foo");
+        assert_eq!(without_leading_number("Foo 2"), "Foo 2");
+        assert_eq!(without_leading_number("42"), "42");
+    }
+}
@@ -0,0 +1,478 @@
+use std::cell::{Cell, RefCell};
+use std::cmp;
+use std::collections::BTreeMap;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use walkdir::WalkDir;
+
+/// Access to the files and directories being renamed, and to the plans and
+/// journals written along the way
+pub trait FileSystem {
+    /// Returns the start directory and all directories below it, the
+    /// contents of each directory before the directory itself
+    fn directories(&self, start_dir: &Path) -> Vec<PathBuf>;
+    /// Returns the paths of all entries of a directory
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>>;
+    fn exists(&self, path: &Path) -> bool;
+    fn is_file(&self, path: &Path) -> bool;
+    /// Returns the size and modification time (in milliseconds) of a file.
+    /// Directories have neither as their modification time changes with their contents.
+    fn file_state(&self, path: &Path) -> (Option<u64>, Option<u64>);
+    /// Renames a file or directory within the same directory
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+    /// Moves a file to another directory, possibly on another file system
+    fn move_file(&self, from: &Path, to: &Path) -> io::Result<()>;
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+    /// Removes an empty directory
+    fn remove_dir(&self, path: &Path) -> io::Result<()>;
+    fn read_to_string(&self, path: &Path) -> io::Result<String>;
+    /// Reads up to `length` bytes of a file, starting at `offset`
+    fn read_at(&self, path: &Path, offset: u64, length: usize) -> io::Result<Vec<u8>>;
+    /// Creates or truncates a file and writes the contents into it
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()>;
+    /// Appends the contents to a file, creating it if necessary
+    fn append(&self, path: &Path, contents: &[u8]) -> io::Result<()>;
+}
+
+/// The disk
+pub struct RealFileSystem;
+
+impl FileSystem for RealFileSystem {
+    fn directories(&self, start_dir: &Path) -> Vec<PathBuf> {
+        WalkDir::new(start_dir)
+            .contents_first(true)
+            .into_iter()
+            .filter_entry(|e| e.file_type().is_dir())
+            // filter *and* report errors
+            .filter_map(|e| match e {
+                Ok(e) => Some(e.into_path()),
+                Err(err) => {
+                    eprintln!("Error traversing directories: {}", err);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        fs::read_dir(dir)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect()
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        path.is_file()
+    }
+
+    fn file_state(&self, path: &Path) -> (Option<u64>, Option<u64>) {
+        match fs::metadata(path) {
+            Ok(metadata) if metadata.is_file() => {
+                let modified = metadata
+                    .modified()
+                    .ok()
+                    .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                    .map(|duration| duration.as_millis() as u64);
+                (Some(metadata.len()), modified)
+            }
+            _ => (None, None),
+        }
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    /// Falls back to copying and deleting the file if source and destination
+    /// are on different file systems
+    fn move_file(&self, from: &Path, to: &Path) -> io::Result<()> {
+        match fs::rename(from, to) {
+            Err(err) if err.kind() == io::ErrorKind::CrossesDevices => {
+                fs::copy(from, to)?;
+                fs::remove_file(from)
+            }
+            result => result,
+        }
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        fs::remove_dir(path)
+    }
+
+    fn read_to_string(&self, path: &Path) -> io::Result<String> {
+        fs::read_to_string(path)
+    }
+
+    fn read_at(&self, path: &Path, offset: u64, length: usize) -> io::Result<Vec<u8>> {
+        let mut file = File::open(path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut contents = Vec::new();
+        file.take(length as u64).read_to_end(&mut contents)?;
+        Ok(contents)
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        fs::write(path, contents)
+    }
+
+    fn append(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?
+            .write_all(contents)
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Node {
+    Directory,
+    File { contents: Vec<u8>, modified: u64 },
+}
+
+/// A file system kept in memory, e. g. to test renaming whole directory
+/// trees without touching the disk. Paths are case-sensitive.
+#[derive(Default)]
+pub struct InMemoryFileSystem {
+    nodes: RefCell<BTreeMap<PathBuf, Node>>,
+    /// Counts the writes, serving as modification time
+    clock: Cell<u64>,
+}
+
+impl InMemoryFileSystem {
+    pub fn new() -> InMemoryFileSystem {
+        InMemoryFileSystem::default()
+    }
+
+    /// Adds a file together with the directories leading to it
+    pub fn add_file(&self, path: &Path, contents: &str) {
+        if let Some(parent) = path.parent() {
+            self.create_dir_all(parent).unwrap();
+        }
+        self.write(path, contents.as_bytes()).unwrap();
+    }
+
+    /// Returns the paths of all files in alphabetical order
+    pub fn files(&self) -> Vec<PathBuf> {
+        self.nodes
+            .borrow()
+            .iter()
+            .filter(|(_, node)| matches!(node, Node::File { .. }))
+            .map(|(path, _)| path.clone())
+            .collect()
+    }
+
+    fn node(&self, path: &Path) -> Option<Node> {
+        self.nodes.borrow().get(path).cloned()
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        // relative paths start in the current directory, which always exists
+        path == Path::new("") || matches!(self.node(path), Some(Node::Directory))
+    }
+
+    fn children(&self, dir: &Path) -> Vec<PathBuf> {
+        self.nodes
+            .borrow()
+            .keys()
+            .filter(|path| path.parent() == Some(dir))
+            .cloned()
+            .collect()
+    }
+
+    fn collect_directories(&self, dir: &Path, directories: &mut Vec<PathBuf>) {
+        for child in self.children(dir) {
+            if self.is_dir(&child) {
+                self.collect_directories(&child, directories);
+            }
+        }
+        directories.push(dir.to_path_buf());
+    }
+
+    /// Checks that the directory a new entry is to be put into exists
+    fn check_parent(&self, path: &Path) -> io::Result<()> {
+        match path.parent() {
+            Some(parent) if !self.is_dir(parent) => Err(not_found(parent)),
+            _ => Ok(()),
+        }
+    }
+
+    fn tick(&self) -> u64 {
+        self.clock.set(self.clock.get() + 1);
+        self.clock.get()
+    }
+}
+
+impl FileSystem for InMemoryFileSystem {
+    fn directories(&self, start_dir: &Path) -> Vec<PathBuf> {
+        let mut directories = Vec::new();
+        if self.is_dir(start_dir) {
+            self.collect_directories(start_dir, &mut directories);
+        }
+        directories
+    }
+
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        if !self.is_dir(dir) {
+            return Err(not_found(dir));
+        }
+        Ok(self.children(dir))
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.nodes.borrow().contains_key(path)
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        matches!(self.node(path), Some(Node::File { .. }))
+    }
+
+    fn file_state(&self, path: &Path) -> (Option<u64>, Option<u64>) {
+        match self.node(path) {
+            Some(Node::File { contents, modified }) => {
+                (Some(contents.len() as u64), Some(modified))
+            }
+            _ => (None, None),
+        }
+    }
+
+    /// Replaces an existing file, or an empty directory, like the disk does
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let node = self.node(from).ok_or_else(|| not_found(from))?;
+        self.check_parent(to)?;
+        if from == to {
+            return Ok(());
+        }
+        match (&node, self.node(to)) {
+            (_, None) => {}
+            (Node::File { .. }, Some(Node::File { .. })) => {}
+            (Node::Directory, Some(Node::Directory)) if self.children(to).is_empty() => {}
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("\"{}\" already exists", to.to_string_lossy()),
+                ))
+            }
+        }
+        if to.starts_with(from) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Cannot move \"{}\" into itself", from.to_string_lossy()),
+            ));
+        }
+
+        let mut nodes = self.nodes.borrow_mut();
+        let moved: Vec<PathBuf> = nodes
+            .keys()
+            .filter(|path| path.starts_with(from))
+            .cloned()
+            .collect();
+        for path in moved {
+            if let Some(node) = nodes.remove(&path) {
+                let new_path = match path.strip_prefix(from) {
+                    Ok(rest) if rest != Path::new("") => to.join(rest),
+                    _ => to.to_path_buf(),
+                };
+                nodes.insert(new_path, node);
+            }
+        }
+        Ok(())
+    }
+
+    fn move_file(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.rename(from, to)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        for dir in path.ancestors().filter(|dir| *dir != Path::new("")) {
+            match self.node(dir) {
+                Some(Node::Directory) => break,
+                Some(Node::File { .. }) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AlreadyExists,
+                        format!("\"{}\" is a file", dir.to_string_lossy()),
+                    ))
+                }
+                None => {}
+            }
+        }
+        let mut nodes = self.nodes.borrow_mut();
+        for dir in path.ancestors().filter(|dir| *dir != Path::new("")) {
+            nodes.entry(dir.to_path_buf()).or_insert(Node::Directory);
+        }
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        if !self.is_file(path) {
+            return Err(not_found(path));
+        }
+        self.nodes.borrow_mut().remove(path);
+        Ok(())
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        if !self.is_dir(path) {
+            return Err(not_found(path));
+        }
+        if !self.children(path).is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::DirectoryNotEmpty,
+                format!("\"{}\" isn't empty", path.to_string_lossy()),
+            ));
+        }
+        self.nodes.borrow_mut().remove(path);
+        Ok(())
+    }
+
+    fn read_to_string(&self, path: &Path) -> io::Result<String> {
+        match self.node(path) {
+            Some(Node::File { contents, .. }) => String::from_utf8(contents)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)),
+            _ => Err(not_found(path)),
+        }
+    }
+
+    fn read_at(&self, path: &Path, offset: u64, length: usize) -> io::Result<Vec<u8>> {
+        match self.node(path) {
+            Some(Node::File { contents, .. }) => {
+                let start = cmp::min(offset as usize, contents.len());
+                let end = cmp::min(start + length, contents.len());
+                Ok(contents[start..end].to_vec())
+            }
+            _ => Err(not_found(path)),
+        }
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        self.check_parent(path)?;
+        if self.is_dir(path) {
+            return Err(io::Error::new(
+                io::ErrorKind::IsADirectory,
+                format!("\"{}\" is a directory", path.to_string_lossy()),
+            ));
+        }
+        let modified = self.tick();
+        self.nodes.borrow_mut().insert(
+            path.to_path_buf(),
+            Node::File {
+                contents: contents.to_vec(),
+                modified,
+            },
+        );
+        Ok(())
+    }
+
+    fn append(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        match self.node(path) {
+            Some(Node::File {
+                contents: mut existing,
+                ..
+            }) => {
+                existing.extend_from_slice(contents);
+                self.write(path, &existing)
+            }
+            _ => self.write(path, contents),
+        }
+    }
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("\"{}\" doesn't exist", path.to_string_lossy()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_in_memory_file_system() {
+        let file_system = InMemoryFileSystem::new();
+        file_system.add_file(Path::new("/music/a/1.mp3"), "one");
+        file_system.add_file(Path::new("/music/a/b/2.mp3"), "two");
+        file_system.add_file(Path::new("/music/c/3.mp3"), "three");
+
+        // contents first, like on the disk
+        assert_eq!(
+            file_system.directories(Path::new("/music")),
+            vec![
+                PathBuf::from("/music/a/b"),
+                PathBuf::from("/music/a"),
+                PathBuf::from("/music/c"),
+                PathBuf::from("/music"),
+            ]
+        );
+        assert_eq!(
+            file_system.file_state(Path::new("/music/a/1.mp3")),
+            (Some(3), Some(1))
+        );
+
+        // renaming a directory moves its contents along
+        file_system
+            .rename(Path::new("/music/a"), Path::new("/music/x"))
+            .unwrap();
+        assert_eq!(
+            file_system.files(),
+            vec![
+                PathBuf::from("/music/c/3.mp3"),
+                PathBuf::from("/music/x/1.mp3"),
+                PathBuf::from("/music/x/b/2.mp3"),
+            ]
+        );
+        assert!(file_system
+            .rename(Path::new("/music/c"), Path::new("/music/x"))
+            .is_err());
+        assert!(file_system
+            .move_file(Path::new("/music/c/3.mp3"), Path::new("/other/3.mp3"))
+            .is_err());
+
+        assert!(file_system.remove_dir(Path::new("/music/c")).is_err());
+        file_system
+            .remove_file(Path::new("/music/c/3.mp3"))
+            .unwrap();
+        file_system.remove_dir(Path::new("/music/c")).unwrap();
+        assert!(!file_system.exists(Path::new("/music/c")));
+
+        file_system
+            .append(Path::new("/music/x/1.mp3"), b" more")
+            .unwrap();
+        assert_eq!(
+            file_system
+                .read_to_string(Path::new("/music/x/1.mp3"))
+                .unwrap(),
+            "one more"
+        );
+        assert_eq!(
+            file_system
+                .read_at(Path::new("/music/x/1.mp3"), 2, 3)
+                .unwrap(),
+            b"e m"
+        );
+        assert_eq!(
+            file_system
+                .read_at(Path::new("/music/x/1.mp3"), 20, 3)
+                .unwrap(),
+            b""
+        );
+    }
+}
@@ -0,0 +1,949 @@
+use std::cmp;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, Cursor, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::file_system::FileSystem;
+
+/// Refuse to read chunks larger than this to not run out of memory on broken files
+const MAX_CHUNK_SIZE: usize = 16 * 1024 * 1024;
+
+/// The comment header is at the start of an Ogg file, so don't look any further
+const MAX_OGG_PAGES: usize = 64;
+
+const ASF_HEADER: [u8; 16] = [
+    0x30, 0x26, 0xB2, 0x75, 0x8E, 0x66, 0xCF, 0x11, 0xA6, 0xD9, 0x00, 0xAA, 0x00, 0x62, 0xCE, 0x6C,
+];
+const ASF_CONTENT_DESCRIPTION: [u8; 16] = [
+    0x33, 0x26, 0xB2, 0x75, 0x8E, 0x66, 0xCF, 0x11, 0xA6, 0xD9, 0x00, 0xAA, 0x00, 0x62, 0xCE, 0x6C,
+];
+const ASF_EXTENDED_CONTENT_DESCRIPTION: [u8; 16] = [
+    0x40, 0xA4, 0xD0, 0xD2, 0x07, 0xE3, 0xD2, 0x11, 0x97, 0xF0, 0x00, 0xA0, 0xC9, 0x5E, 0xA8, 0x50,
+];
+
+/// Tag values as found in a file, before checking they are complete
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RawTags {
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    pub artist: Option<String>,
+    /// Set by the compilation flag (TCMP in ID3, cpil in MP4, COMPILATION elsewhere)
+    pub compilation: bool,
+    pub composer: Option<String>,
+    pub conductor: Option<String>,
+    /// The release date as tagged, e. g. "2001" or "2001-05-17"
+    pub date: Option<String>,
+    pub disc: Option<u16>,
+    pub genre: Option<String>,
+    pub original_year: Option<i32>,
+    pub title: Option<String>,
+    pub total_discs: Option<u16>,
+    pub total_tracks: Option<u16>,
+    pub track: Option<u16>,
+    pub year: Option<i32>,
+}
+
+impl RawTags {
+    /// Fills in the fields missing here with those of `other`
+    pub fn merge(&mut self, other: RawTags) {
+        self.album = self.album.take().or(other.album);
+        self.album_artist = self.album_artist.take().or(other.album_artist);
+        self.artist = self.artist.take().or(other.artist);
+        self.compilation = self.compilation || other.compilation;
+        self.composer = self.composer.take().or(other.composer);
+        self.conductor = self.conductor.take().or(other.conductor);
+        self.date = self.date.take().or(other.date);
+        self.disc = self.disc.or(other.disc);
+        self.genre = self.genre.take().or(other.genre);
+        self.original_year = self.original_year.or(other.original_year);
+        self.title = self.title.take().or(other.title);
+        self.total_discs = self.total_discs.or(other.total_discs);
+        self.total_tracks = self.total_tracks.or(other.total_tracks);
+        self.track = self.track.or(other.track);
+        self.year = self.year.or(other.year);
+    }
+
+    /// Sets a field from a textual tag named like in Vorbis comments, APEv2,
+    /// or ASF tags unless it is set already
+    fn set(&mut self, key: &str, value: &str) {
+        let value = value.trim_end_matches('\0').trim();
+        if value.is_empty() {
+            return;
+        }
+
+        match key.to_uppercase().as_str() {
+            "ALBUM" | "WM/ALBUMTITLE" => {
+                self.album.get_or_insert_with(|| value.to_string());
+            }
+            "ALBUMARTIST" | "ALBUM ARTIST" | "ALBUM_ARTIST" | "WM/ALBUMARTIST" => {
+                self.album_artist.get_or_insert_with(|| value.to_string());
+            }
+            "ARTIST" | "AUTHOR" => {
+                self.artist.get_or_insert_with(|| value.to_string());
+            }
+            "COMPILATION" | "WM/ISCOMPILATION" => {
+                self.compilation = self.compilation || is_true(value)
+            }
+            "COMPOSER" | "WM/COMPOSER" => {
+                self.composer.get_or_insert_with(|| value.to_string());
+            }
+            "CONDUCTOR" | "WM/CONDUCTOR" => {
+                self.conductor.get_or_insert_with(|| value.to_string());
+            }
+            "DISCNUMBER" | "DISC" | "WM/PARTOFSET" => {
+                self.disc = self.disc.or(parse_number(value));
+                self.total_discs = self.total_discs.or(parse_total(value));
+            }
+            "DISCTOTAL" | "TOTALDISCS" => {
+                self.total_discs = self.total_discs.or(parse_number(value))
+            }
+            "GENRE" | "WM/GENRE" => {
+                self.genre.get_or_insert_with(|| value.to_string());
+            }
+            "ORIGINALDATE" | "ORIGINALYEAR" | "WM/ORIGINALRELEASEYEAR" => {
+                self.original_year = self.original_year.or(parse_year(value))
+            }
+            "TITLE" => {
+                self.title.get_or_insert_with(|| value.to_string());
+            }
+            "TRACKNUMBER" | "TRACK" | "WM/TRACKNUMBER" => {
+                self.track = self.track.or(parse_number(value));
+                self.total_tracks = self.total_tracks.or(parse_total(value));
+            }
+            "TRACKTOTAL" | "TOTALTRACKS" => {
+                self.total_tracks = self.total_tracks.or(parse_number(value))
+            }
+            "DATE" | "YEAR" | "WM/YEAR" => {
+                self.date.get_or_insert_with(|| value.to_string());
+                self.year = self.year.or(parse_year(value));
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Detects the format of a music file by its first bytes and returns the
+/// matching lowercase extension. Returns `None` for all other files.
+pub fn sniff(path: &Path, file_system: &dyn FileSystem) -> Option<&'static str> {
+    let header = file_system.read_at(path, 0, 64).ok()?;
+
+    // an ID3v2 tag may precede MP3 as well as FLAC data
+    if header.starts_with(b"ID3") && header.len() >= 10 {
+        let mut size = header[6..10]
+            .iter()
+            .fold(0u64, |size, &byte| (size << 7) | u64::from(byte & 0x7F))
+            + 10;
+        if header[5] & 0x10 != 0 {
+            // with footer
+            size += 10;
+        }
+        let header = file_system.read_at(path, size, 64).ok()?;
+        return match sniff_bytes(&header) {
+            Some("flac") => Some("flac"),
+            _ => Some("mp3"),
+        };
+    }
+
+    sniff_bytes(&header)
+}
+
+fn sniff_bytes(header: &[u8]) -> Option<&'static str> {
+    if header.starts_with(b"fLaC") {
+        Some("flac")
+    } else if header.starts_with(b"OggS") {
+        // the first packet tells the codec
+        let packet = header.get(27 + *header.get(26)? as usize..)?;
+        if packet.starts_with(b"OpusHead") {
+            Some("opus")
+        } else if packet.starts_with(b"\x01vorbis") {
+            Some("ogg")
+        } else {
+            Some("oga")
+        }
+    } else if header.get(4..8) == Some(b"ftyp") {
+        // the major brand, followed by the minor version and the compatible
+        // brands, e. g. "M4A " among those of an "isom" or "mp42" file
+        let size = u32::from_be_bytes(<[u8; 4]>::try_from(header.get(0..4)?).ok()?) as usize;
+        let brands = header.get(8..cmp::min(size, header.len()))?;
+        brands
+            .chunks_exact(4)
+            .enumerate()
+            .filter(|(index, _)| *index != 1)
+            .find_map(|(_, brand)| match brand {
+                b"M4A " => Some("m4a"),
+                b"M4B " => Some("m4b"),
+                b"M4P " => Some("m4p"),
+                b"M4V " | b"M4VH" | b"M4VP" => Some("m4v"),
+                _ => None,
+            })
+    } else if header.starts_with(b"RIFF") && header.get(8..12) == Some(b"WAVE") {
+        Some("wav")
+    } else if header.starts_with(b"FORM") && header.get(8..12) == Some(b"AIFF") {
+        Some("aiff")
+    } else if header.starts_with(b"FORM") && header.get(8..12) == Some(b"AIFC") {
+        Some("aifc")
+    } else if header.starts_with(&ASF_HEADER) {
+        Some("wma")
+    } else if header.starts_with(b"MAC ") {
+        Some("ape")
+    } else if header.starts_with(b"wvpk") {
+        Some("wv")
+    } else if header.starts_with(b"MPCK") || header.starts_with(b"MP+") {
+        Some("mpc")
+    } else if is_mpeg_audio_frame(header) {
+        Some("mp3")
+    } else {
+        None
+    }
+}
+
+/// Does the header start with the frame sync of an MPEG Layer III frame?
+fn is_mpeg_audio_frame(header: &[u8]) -> bool {
+    header.len() >= 4
+        && header[0] == 0xFF
+        && header[1] & 0xE0 == 0xE0
+        // layer III
+        && (header[1] >> 1) & 3 == 1
+        // neither a free nor an invalid bitrate
+        && header[2] >> 4 != 0
+        && header[2] >> 4 != 0xF
+        // no reserved sample rate
+        && (header[2] >> 2) & 3 != 3
+}
+
+/// Reads the tags of the formats audiotags doesn't know, chosen by the
+/// given lowercase extension. Returns `None` for all other formats.
+pub fn read(path: &Path, extension: &str) -> Option<Result<RawTags, String>> {
+    let read_tags: fn(&mut BufReader<File>) -> Result<RawTags, String> = match extension {
+        "ogg" | "oga" | "opus" => read_ogg,
+        "wav" => read_riff,
+        "aif" | "aifc" | "aiff" => read_aiff,
+        "wma" => read_asf,
+        "ape" | "mpc" | "wv" => read_ape,
+        _ => return None,
+    };
+
+    Some(
+        File::open(path)
+            .map_err(|err| err.to_string())
+            .and_then(|file| read_tags(&mut BufReader::new(file))),
+    )
+}
+
+/// Reads the Vorbis comments of an Ogg Vorbis or Opus file
+fn read_ogg<R: Read + Seek>(reader: &mut R) -> Result<RawTags, String> {
+    let mut packets: Vec<Vec<u8>> = Vec::new();
+    let mut packet = Vec::new();
+    let mut serial = None;
+
+    // the comment header is the second packet of the first logical stream
+    for _ in 0..MAX_OGG_PAGES {
+        let header = read_bytes(reader, 27)?;
+        if &header[0..4] != b"OggS" {
+            return Err("Not an Ogg file".to_string());
+        }
+        let page_serial = u32::from_le_bytes([header[14], header[15], header[16], header[17]]);
+        let segments = read_bytes(reader, header[26] as usize)?;
+        let body = read_bytes(reader, segments.iter().map(|&s| s as usize).sum())?;
+        if *serial.get_or_insert(page_serial) != page_serial {
+            continue;
+        }
+
+        let mut offset = 0;
+        for &length in &segments {
+            packet.extend_from_slice(&body[offset..offset + length as usize]);
+            offset += length as usize;
+            // a packet ends with the first segment shorter than 255 bytes
+            if length < 255 {
+                packets.push(std::mem::take(&mut packet));
+                if packets.len() == 2 {
+                    return parse_comment_packet(&packets[1]);
+                }
+            }
+        }
+    }
+
+    Err("No comment header found".to_string())
+}
+
+fn parse_comment_packet(packet: &[u8]) -> Result<RawTags, String> {
+    if packet.starts_with(b"\x03vorbis") {
+        parse_vorbis_comments(&packet[7..])
+    } else if packet.starts_with(b"OpusTags") {
+        parse_vorbis_comments(&packet[8..])
+    } else {
+        Err("Unknown Ogg stream".to_string())
+    }
+}
+
+fn parse_vorbis_comments(data: &[u8]) -> Result<RawTags, String> {
+    let mut cursor = Cursor::new(data);
+    let mut tags = RawTags::default();
+
+    let vendor_length = read_u32_le(&mut cursor)?;
+    read_bytes(&mut cursor, vendor_length as usize)?;
+    let count = read_u32_le(&mut cursor)?;
+    for _ in 0..count {
+        let length = read_u32_le(&mut cursor)?;
+        let comment = read_bytes(&mut cursor, length as usize)?;
+        let comment = String::from_utf8_lossy(&comment);
+        if let Some((key, value)) = comment.split_once('=') {
+            tags.set(key, value);
+        }
+    }
+
+    Ok(tags)
+}
+
+/// Reads the ID3 and RIFF INFO tags of a WAV file, preferring ID3
+fn read_riff<R: Read + Seek>(reader: &mut R) -> Result<RawTags, String> {
+    let header = read_bytes(reader, 12)?;
+    if &header[0..4] != b"RIFF" || &header[8..12] != b"WAVE" {
+        return Err("Not a WAV file".to_string());
+    }
+
+    let mut tags = RawTags::default();
+    let mut info = RawTags::default();
+    while let Some((id, size)) = next_chunk(reader, false)? {
+        match &id {
+            b"LIST" => {
+                let data = read_bytes(reader, size)?;
+                if data.starts_with(b"INFO") {
+                    info.merge(parse_riff_info(&data[4..]));
+                }
+            }
+            b"id3 " | b"ID3 " => tags.merge(parse_id3(&read_bytes(reader, size)?)),
+            _ => skip(reader, size)?,
+        }
+        // chunks are padded to an even size
+        if size % 2 == 1 {
+            skip(reader, 1)?;
+        }
+    }
+    tags.merge(info);
+
+    Ok(tags)
+}
+
+fn parse_riff_info(data: &[u8]) -> RawTags {
+    let mut cursor = Cursor::new(data);
+    let mut tags = RawTags::default();
+
+    while let Ok(Some((id, size))) = next_chunk(&mut cursor, false) {
+        let value = match read_bytes(&mut cursor, size) {
+            Ok(value) => value,
+            Err(_) => break,
+        };
+        let key = match &id {
+            b"IART" => "ARTIST",
+            b"ICRD" => "DATE",
+            b"IGNR" => "GENRE",
+            b"INAM" => "TITLE",
+            b"IPRD" => "ALBUM",
+            b"IPRT" | b"ITRK" => "TRACKNUMBER",
+            _ => "",
+        };
+        tags.set(key, &String::from_utf8_lossy(&value));
+        if size % 2 == 1 && skip(&mut cursor, 1).is_err() {
+            break;
+        }
+    }
+
+    tags
+}
+
+/// Reads the ID3 tag and the name and author chunks of an AIFF file, preferring ID3
+fn read_aiff<R: Read + Seek>(reader: &mut R) -> Result<RawTags, String> {
+    let header = read_bytes(reader, 12)?;
+    if &header[0..4] != b"FORM" || (&header[8..12] != b"AIFF" && &header[8..12] != b"AIFC") {
+        return Err("Not an AIFF file".to_string());
+    }
+
+    let mut tags = RawTags::default();
+    let mut text = RawTags::default();
+    while let Some((id, size)) = next_chunk(reader, true)? {
+        match &id {
+            b"ID3 " | b"id3 " => tags.merge(parse_id3(&read_bytes(reader, size)?)),
+            b"NAME" => text.set(
+                "TITLE",
+                &String::from_utf8_lossy(&read_bytes(reader, size)?),
+            ),
+            b"AUTH" => text.set(
+                "ARTIST",
+                &String::from_utf8_lossy(&read_bytes(reader, size)?),
+            ),
+            _ => skip(reader, size)?,
+        }
+        if size % 2 == 1 {
+            skip(reader, 1)?;
+        }
+    }
+    tags.merge(text);
+
+    Ok(tags)
+}
+
+/// Reads an ID3 tag embedded in a chunk, ignoring broken ones
+fn parse_id3(data: &[u8]) -> RawTags {
+    match id3::Tag::read_from(data) {
+        Ok(tag) => from_id3(&tag),
+        Err(_) => RawTags::default(),
+    }
+}
+
+/// Returns the values of an ID3 tag
+pub fn from_id3(tag: &id3::Tag) -> RawTags {
+    let text = |id: &str| tag.get(id).and_then(|frame| frame.content().text());
+    RawTags {
+        album: tag.album().map(String::from),
+        album_artist: tag.album_artist().map(String::from),
+        artist: tag.artist().map(String::from),
+        compilation: text("TCMP").is_some_and(is_true),
+        composer: text("TCOM").map(String::from),
+        conductor: text("TPE3").map(String::from),
+        date: tag
+            .date_recorded()
+            .map(|date| date.to_string())
+            .or_else(|| tag.year().map(|year| year.to_string())),
+        disc: tag.disc().and_then(|disc| u16::try_from(disc).ok()),
+        genre: tag.genre().map(String::from),
+        // TDOR in ID3v2.4, TORY in ID3v2.3
+        original_year: text("TDOR").or_else(|| text("TORY")).and_then(parse_year),
+        title: tag.title().map(String::from),
+        total_discs: tag
+            .total_discs()
+            .and_then(|total| u16::try_from(total).ok()),
+        total_tracks: tag
+            .total_tracks()
+            .and_then(|total| u16::try_from(total).ok()),
+        track: tag.track().and_then(|track| u16::try_from(track).ok()),
+        year: tag
+            .year()
+            .or_else(|| tag.date_recorded().map(|date| date.year)),
+    }
+}
+
+/// Returns the values of the Vorbis comments of a FLAC file
+pub fn from_flac(tag: &metaflac::Tag) -> RawTags {
+    let mut tags = RawTags::default();
+    if let Some(vorbis_comments) = tag.vorbis_comments() {
+        for (key, values) in &vorbis_comments.comments {
+            for value in values {
+                tags.set(key, value);
+            }
+        }
+    }
+    tags
+}
+
+/// Returns the values of an MP4 tag audiotags doesn't provide
+pub fn from_mp4(tag: &mp4ameta::Tag) -> RawTags {
+    RawTags {
+        album_artist: tag.album_artist().map(String::from),
+        compilation: tag.compilation(),
+        composer: tag.composer().map(String::from),
+        date: tag.year().map(String::from),
+        genre: tag.genre().map(String::from),
+        ..RawTags::default()
+    }
+}
+
+/// Reads the content description objects of a WMA file
+fn read_asf<R: Read + Seek>(reader: &mut R) -> Result<RawTags, String> {
+    let header = read_bytes(reader, 30)?;
+    if header[0..16] != ASF_HEADER {
+        return Err("Not a WMA file".to_string());
+    }
+
+    let mut tags = RawTags::default();
+    let count = u32::from_le_bytes([header[24], header[25], header[26], header[27]]);
+    for _ in 0..count {
+        let object_header = read_bytes(reader, 24)?;
+        let mut size_bytes = [0; 8];
+        size_bytes.copy_from_slice(&object_header[16..24]);
+        let size = u64::from_le_bytes(size_bytes)
+            .checked_sub(24)
+            .ok_or_else(|| "Invalid ASF object".to_string())? as usize;
+
+        let guid = &object_header[0..16];
+        if guid == ASF_CONTENT_DESCRIPTION {
+            parse_asf_content_description(&read_bytes(reader, size)?, &mut tags)?;
+        } else if guid == ASF_EXTENDED_CONTENT_DESCRIPTION {
+            parse_asf_extended_content_description(&read_bytes(reader, size)?, &mut tags)?;
+        } else {
+            skip(reader, size)?;
+        }
+    }
+
+    Ok(tags)
+}
+
+fn parse_asf_content_description(data: &[u8], tags: &mut RawTags) -> Result<(), String> {
+    let mut cursor = Cursor::new(data);
+    let title_length = read_u16_le(&mut cursor)?;
+    let author_length = read_u16_le(&mut cursor)?;
+    // copyright, description, and rating follow, but aren't used
+    read_bytes(&mut cursor, 6)?;
+
+    tags.set(
+        "TITLE",
+        &utf16_le(&read_bytes(&mut cursor, title_length as usize)?),
+    );
+    tags.set(
+        "AUTHOR",
+        &utf16_le(&read_bytes(&mut cursor, author_length as usize)?),
+    );
+
+    Ok(())
+}
+
+fn parse_asf_extended_content_description(data: &[u8], tags: &mut RawTags) -> Result<(), String> {
+    let mut cursor = Cursor::new(data);
+    // "WM/Track" is zero-based and only used without "WM/TrackNumber"
+    let mut zero_based_track = None;
+
+    let count = read_u16_le(&mut cursor)?;
+    for _ in 0..count {
+        let name_length = read_u16_le(&mut cursor)?;
+        let name = utf16_le(&read_bytes(&mut cursor, name_length as usize)?);
+        let value_type = read_u16_le(&mut cursor)?;
+        let value_length = read_u16_le(&mut cursor)?;
+        let value = read_bytes(&mut cursor, value_length as usize)?;
+
+        let value = match (value_type, value.len()) {
+            (0, _) => utf16_le(&value),
+            // booleans are stored as 32-bit values as well
+            (2, 4) | (3, 4) => {
+                u32::from_le_bytes([value[0], value[1], value[2], value[3]]).to_string()
+            }
+            (5, 2) => u16::from_le_bytes([value[0], value[1]]).to_string(),
+            _ => continue,
+        };
+        if name.eq_ignore_ascii_case("WM/Track") {
+            zero_based_track = parse_number(&value).and_then(|track| track.checked_add(1));
+        } else {
+            tags.set(&name, &value);
+        }
+    }
+    tags.track = tags.track.or(zero_based_track);
+
+    Ok(())
+}
+
+/// Reads the APEv2 tag at the end of a Monkey's Audio, WavPack, or Musepack file
+fn read_ape<R: Read + Seek>(reader: &mut R) -> Result<RawTags, String> {
+    let length = reader
+        .seek(SeekFrom::End(0))
+        .map_err(|err| err.to_string())?;
+
+    // an ID3v1 tag of 128 bytes may follow the APEv2 tag
+    for &footer_offset in &[32, 160] {
+        if length < footer_offset {
+            continue;
+        }
+        let footer_start = length - footer_offset;
+        reader
+            .seek(SeekFrom::Start(footer_start))
+            .map_err(|err| err.to_string())?;
+        let footer = read_bytes(reader, 32)?;
+        if &footer[0..8] != b"APETAGEX" {
+            continue;
+        }
+
+        // the tag size includes the footer, but not the optional header
+        let tag_size = u64::from(u32::from_le_bytes([
+            footer[12], footer[13], footer[14], footer[15],
+        ]));
+        let count = u32::from_le_bytes([footer[16], footer[17], footer[18], footer[19]]);
+        let items_start = (footer_start + 32)
+            .checked_sub(tag_size)
+            .filter(|_| tag_size >= 32)
+            .ok_or_else(|| "Invalid APEv2 tag".to_string())?;
+        reader
+            .seek(SeekFrom::Start(items_start))
+            .map_err(|err| err.to_string())?;
+        let items = read_bytes(reader, (tag_size - 32) as usize)?;
+
+        return parse_ape_items(&items, count);
+    }
+
+    Err("No APEv2 tag found".to_string())
+}
+
+fn parse_ape_items(data: &[u8], count: u32) -> Result<RawTags, String> {
+    let mut cursor = Cursor::new(data);
+    let mut tags = RawTags::default();
+
+    for _ in 0..count {
+        let value_size = read_u32_le(&mut cursor)?;
+        let flags = read_u32_le(&mut cursor)?;
+        let mut key = Vec::new();
+        loop {
+            let byte = read_bytes(&mut cursor, 1)?[0];
+            if byte == 0 {
+                break;
+            }
+            key.push(byte);
+        }
+        let value = read_bytes(&mut cursor, value_size as usize)?;
+
+        // only text items are of interest, not binary ones like cover art
+        if (flags >> 1) & 3 == 0 {
+            tags.set(
+                &String::from_utf8_lossy(&key),
+                &String::from_utf8_lossy(&value),
+            );
+        }
+    }
+
+    Ok(tags)
+}
+
+/// Returns the ID and size of the next RIFF or AIFF chunk, or `None` at the end
+fn next_chunk<R: Read>(
+    reader: &mut R,
+    big_endian: bool,
+) -> Result<Option<([u8; 4], usize)>, String> {
+    let mut header = [0; 8];
+    match reader.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.to_string()),
+    }
+
+    let id = [header[0], header[1], header[2], header[3]];
+    let size = [header[4], header[5], header[6], header[7]];
+    let size = if big_endian {
+        u32::from_be_bytes(size)
+    } else {
+        u32::from_le_bytes(size)
+    };
+
+    Ok(Some((id, size as usize)))
+}
+
+fn read_bytes<R: Read>(reader: &mut R, length: usize) -> Result<Vec<u8>, String> {
+    if length > MAX_CHUNK_SIZE {
+        return Err(format!("Refusing to read {} bytes of tags", length));
+    }
+    let mut buffer = vec![0; length];
+    reader
+        .read_exact(&mut buffer)
+        .map_err(|err| err.to_string())?;
+    Ok(buffer)
+}
+
+fn read_u16_le<R: Read>(reader: &mut R) -> Result<u16, String> {
+    let bytes = read_bytes(reader, 2)?;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32_le<R: Read>(reader: &mut R) -> Result<u32, String> {
+    let bytes = read_bytes(reader, 4)?;
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn skip<R: Seek>(reader: &mut R, length: usize) -> Result<(), String> {
+    reader
+        .seek(SeekFrom::Current(length as i64))
+        .map(|_| ())
+        .map_err(|err| err.to_string())
+}
+
+fn utf16_le(bytes: &[u8]) -> String {
+    let units: Vec<u16> = bytes
+        .chunks_exact(2)
+        .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+        .collect();
+    String::from_utf16_lossy(&units)
+        .trim_end_matches('\0')
+        .to_string()
+}
+
+/// Interprets a flag like "1" or "true"
+fn is_true(value: &str) -> bool {
+    matches!(value.trim().to_lowercase().as_str(), "1" | "true" | "yes")
+}
+
+/// Parses numbers like "3" or "3/12"
+fn parse_number(value: &str) -> Option<u16> {
+    value.split('/').next()?.trim().parse().ok()
+}
+
+/// Parses the total of numbers like "3/12"
+fn parse_total(value: &str) -> Option<u16> {
+    value.split('/').nth(1)?.trim().parse().ok()
+}
+
+/// Parses the year of dates like "2001" or "2001-05-17"
+fn parse_year(value: &str) -> Option<i32> {
+    value.get(0..4)?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vorbis_comments(comments: &[&str]) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&6u32.to_le_bytes());
+        data.extend_from_slice(b"vendor");
+        data.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+        for comment in comments {
+            data.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+            data.extend_from_slice(comment.as_bytes());
+        }
+        data
+    }
+
+    fn ogg_page(packet: &[u8]) -> Vec<u8> {
+        let mut page = b"OggS".to_vec();
+        page.extend_from_slice(&[0; 10]);
+        page.extend_from_slice(&1u32.to_le_bytes());
+        page.extend_from_slice(&[0; 8]);
+        let mut segments: Vec<u8> = vec![255; packet.len() / 255];
+        segments.push((packet.len() % 255) as u8);
+        page.push(segments.len() as u8);
+        page.extend_from_slice(&segments);
+        page.extend_from_slice(packet);
+        page
+    }
+
+    fn chunk(id: &[u8], data: &[u8], big_endian: bool) -> Vec<u8> {
+        let mut chunk = id.to_vec();
+        let size = data.len() as u32;
+        chunk.extend_from_slice(&if big_endian {
+            size.to_be_bytes()
+        } else {
+            size.to_le_bytes()
+        });
+        chunk.extend_from_slice(data);
+        if data.len() % 2 == 1 {
+            chunk.push(0);
+        }
+        chunk
+    }
+
+    fn utf16(text: &str) -> Vec<u8> {
+        text.encode_utf16()
+            .chain(std::iter::once(0))
+            .flat_map(|unit| unit.to_le_bytes().to_vec())
+            .collect()
+    }
+
+    fn complete_tags() -> RawTags {
+        RawTags {
+            album: Some("Album".to_string()),
+            album_artist: None,
+            artist: Some("Artist".to_string()),
+            compilation: false,
+            composer: None,
+            conductor: None,
+            date: Some("2001".to_string()),
+            disc: None,
+            genre: None,
+            original_year: None,
+            title: Some("Title".to_string()),
+            total_discs: None,
+            total_tracks: None,
+            track: Some(3),
+            year: Some(2001),
+        }
+    }
+
+    #[test]
+    fn test_read_ogg() {
+        let mut comment_packet = b"OpusTags".to_vec();
+        comment_packet.extend(vorbis_comments(&[
+            "TITLE=Title",
+            "artist=Artist",
+            "ALBUM=Album",
+            "TRACKNUMBER=3/12",
+            "DATE=2001-05-17",
+        ]));
+        let mut file = ogg_page(b"OpusHead");
+        file.extend(ogg_page(&comment_packet));
+
+        assert_eq!(
+            read_ogg(&mut Cursor::new(file)),
+            Ok(RawTags {
+                date: Some("2001-05-17".to_string()),
+                total_tracks: Some(12),
+                ..complete_tags()
+            })
+        );
+        assert!(read_ogg(&mut Cursor::new(b"RIFF".to_vec())).is_err());
+    }
+
+    #[test]
+    fn test_set() {
+        let mut tags = RawTags::default();
+        tags.set("Album Artist", "The Foos");
+        tags.set("ALBUMARTIST", "Somebody Else");
+        tags.set("COMPILATION", "0");
+        assert_eq!(tags.album_artist, Some("The Foos".to_string()));
+        assert!(!tags.compilation);
+
+        tags.set("WM/IsCompilation", "1");
+        assert!(tags.compilation);
+
+        tags.set("DISCNUMBER", "1/2");
+        tags.set("TRACKTOTAL", "12");
+        tags.set("ORIGINALDATE", "1977-10-01");
+        tags.set("Genre", "Krautrock");
+        tags.set("COMPOSER", "Foo");
+        tags.set("CONDUCTOR", "Bar");
+        assert_eq!(tags.disc, Some(1));
+        assert_eq!(tags.total_discs, Some(2));
+        assert_eq!(tags.total_tracks, Some(12));
+        assert_eq!(tags.original_year, Some(1977));
+        assert_eq!(tags.genre, Some("Krautrock".to_string()));
+        assert_eq!(tags.composer, Some("Foo".to_string()));
+        assert_eq!(tags.conductor, Some("Bar".to_string()));
+    }
+
+    #[test]
+    fn test_read_riff() {
+        let mut info = b"INFO".to_vec();
+        info.extend(chunk(b"INAM", b"Title\0", false));
+        info.extend(chunk(b"IART", b"Artist\0", false));
+        info.extend(chunk(b"IPRD", b"Album", false));
+        info.extend(chunk(b"ITRK", b"3\0", false));
+        info.extend(chunk(b"ICRD", b"2001\0", false));
+        let mut body = b"WAVE".to_vec();
+        body.extend(chunk(b"fmt ", &[0; 16], false));
+        body.extend(chunk(b"data", &[0; 7], false));
+        body.extend(chunk(b"LIST", &info, false));
+        let file = chunk(b"RIFF", &body, false);
+
+        assert_eq!(read_riff(&mut Cursor::new(file)), Ok(complete_tags()));
+    }
+
+    #[test]
+    fn test_read_aiff() {
+        let mut body = b"AIFF".to_vec();
+        body.extend(chunk(b"COMM", &[0; 18], true));
+        body.extend(chunk(b"NAME", b"Title", true));
+        body.extend(chunk(b"AUTH", b"Artist", true));
+        let file = chunk(b"FORM", &body, true);
+
+        let tags = read_aiff(&mut Cursor::new(file)).unwrap();
+        assert_eq!(tags.title, Some("Title".to_string()));
+        assert_eq!(tags.artist, Some("Artist".to_string()));
+        assert_eq!(tags.album, None);
+    }
+
+    #[test]
+    fn test_read_asf() {
+        let title = utf16("Title");
+        let author = utf16("Artist");
+        let mut description = Vec::new();
+        description.extend_from_slice(&(title.len() as u16).to_le_bytes());
+        description.extend_from_slice(&(author.len() as u16).to_le_bytes());
+        description.extend_from_slice(&[0; 6]);
+        description.extend(title);
+        description.extend(author);
+
+        let mut extended = 3u16.to_le_bytes().to_vec();
+        for (name, value_type, value) in &[
+            ("WM/AlbumTitle", 0u16, utf16("Album")),
+            ("WM/Track", 3, 2u32.to_le_bytes().to_vec()),
+            ("WM/Year", 0, utf16("2001")),
+        ] {
+            let name = utf16(name);
+            extended.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            extended.extend(name);
+            extended.extend_from_slice(&value_type.to_le_bytes());
+            extended.extend_from_slice(&(value.len() as u16).to_le_bytes());
+            extended.extend_from_slice(value);
+        }
+
+        let mut file = ASF_HEADER.to_vec();
+        file.extend_from_slice(&[0; 8]);
+        file.extend_from_slice(&2u32.to_le_bytes());
+        file.extend_from_slice(&[1, 2]);
+        for (guid, data) in &[
+            (ASF_CONTENT_DESCRIPTION, description),
+            (ASF_EXTENDED_CONTENT_DESCRIPTION, extended),
+        ] {
+            file.extend_from_slice(guid);
+            file.extend_from_slice(&(data.len() as u64 + 24).to_le_bytes());
+            file.extend_from_slice(data);
+        }
+
+        assert_eq!(read_asf(&mut Cursor::new(file)), Ok(complete_tags()));
+    }
+
+    #[test]
+    fn test_read_ape() {
+        let mut items = Vec::new();
+        for (key, value) in &[
+            ("Title", "Title"),
+            ("Artist", "Artist"),
+            ("Album", "Album"),
+            ("Track", "3"),
+            ("Year", "2001"),
+        ] {
+            items.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            items.extend_from_slice(&0u32.to_le_bytes());
+            items.extend_from_slice(key.as_bytes());
+            items.push(0);
+            items.extend_from_slice(value.as_bytes());
+        }
+
+        let mut file = b"MAC audio data".to_vec();
+        file.extend_from_slice(&items);
+        file.extend_from_slice(b"APETAGEX");
+        file.extend_from_slice(&2000u32.to_le_bytes());
+        file.extend_from_slice(&(items.len() as u32 + 32).to_le_bytes());
+        file.extend_from_slice(&5u32.to_le_bytes());
+        file.extend_from_slice(&[0; 12]);
+
+        assert_eq!(
+            read_ape(&mut Cursor::new(file.clone())),
+            Ok(complete_tags())
+        );
+
+        // followed by an ID3v1 tag
+        file.extend_from_slice(b"TAG");
+        file.extend_from_slice(&[0; 125]);
+        assert_eq!(read_ape(&mut Cursor::new(file)), Ok(complete_tags()));
+    }
+
+    #[test]
+    fn test_sniff_bytes() {
+        assert_eq!(sniff_bytes(b"fLaC\0\0\0\x22"), Some("flac"));
+        assert_eq!(sniff_bytes(&[0xFF, 0xFB, 0x90, 0x64]), Some("mp3"));
+        assert_eq!(sniff_bytes(&[0xFF, 0xFB, 0xF0, 0x64]), None);
+        assert_eq!(sniff_bytes(&ogg_page(b"OpusHead")), Some("opus"));
+        assert_eq!(sniff_bytes(&ogg_page(b"\x01vorbis")), Some("ogg"));
+        assert_eq!(sniff_bytes(b"\0\0\0\x20ftypM4A \0\0\0\0"), Some("m4a"));
+        assert_eq!(
+            sniff_bytes(b"\0\0\0\x18ftypmp42\0\0\0\0isomM4A "),
+            Some("m4a")
+        );
+        // no audio brand: it might as well be a video
+        assert_eq!(sniff_bytes(b"\0\0\0\x14ftypisom\0\0\0\0mp41"), None);
+        // only brands within the box count
+        assert_eq!(sniff_bytes(b"\0\0\0\x10ftypisom\0\0\0\0M4A "), None);
+        assert_eq!(sniff_bytes(b"RIFF\0\0\0\0WAVEfmt "), Some("wav"));
+        assert_eq!(sniff_bytes(b"FORM\0\0\0\0AIFFCOMM"), Some("aiff"));
+        assert_eq!(sniff_bytes(&ASF_HEADER), Some("wma"));
+        assert_eq!(sniff_bytes(b"wvpk"), Some("wv"));
+        assert_eq!(sniff_bytes(b"PK\x03\x04"), None);
+        assert_eq!(sniff_bytes(b""), None);
+    }
+
+    #[test]
+    fn test_parse_number_and_year() {
+        assert_eq!(parse_number("3"), Some(3));
+        assert_eq!(parse_number(" 3/12"), Some(3));
+        assert_eq!(parse_number("three"), None);
+        assert_eq!(parse_total(" 3/12"), Some(12));
+        assert_eq!(parse_total("3"), None);
+        assert_eq!(parse_year("2001-05-17"), Some(2001));
+        assert_eq!(parse_year("01"), None);
+    }
+}
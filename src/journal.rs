@@ -0,0 +1,492 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use serde::{Deserialize, Serialize};
+
+use crate::collisions;
+use crate::file_system::FileSystem;
+use crate::metadata_source::MetadataSource;
+use crate::output::{Outcome, Output};
+use crate::plan::Tags;
+
+/// What happened to a file or directory
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Rename,
+    Move,
+    /// The file was moved into the journal's trash directory
+    Remove,
+    RemoveDirectory,
+    /// Tag values were written into the file, which keeps its path
+    WriteTags,
+}
+
+/// One change to the disk, together with the state of the changed entry
+/// right after the change to detect later modifications
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    pub action: Action,
+    pub old_path: PathBuf,
+    pub new_path: PathBuf,
+    pub size: Option<u64>,
+    pub modified: Option<u64>,
+    /// The format of a file whose tags were written, as lowercase extension
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub extension: String,
+    /// The tag values replaced by a tag write
+    #[serde(default, skip_serializing_if = "Tags::is_empty")]
+    pub before: Tags,
+    /// The tag values written
+    #[serde(default, skip_serializing_if = "Tags::is_empty")]
+    pub after: Tags,
+}
+
+impl Entry {
+    fn new(
+        action: Action,
+        old_path: &Path,
+        new_path: &Path,
+        file_system: &dyn FileSystem,
+    ) -> Entry {
+        let (size, modified) = file_system.file_state(new_path);
+        Entry {
+            action,
+            old_path: old_path.to_path_buf(),
+            new_path: new_path.to_path_buf(),
+            size,
+            modified,
+            extension: String::new(),
+            before: Tags::new(),
+            after: Tags::new(),
+        }
+    }
+
+    /// Is the changed entry still in the state it was left in?
+    fn is_unchanged(&self, file_system: &dyn FileSystem) -> bool {
+        match self.action {
+            Action::RemoveDirectory => !file_system.exists(&self.old_path),
+            _ => {
+                file_system.exists(&self.new_path)
+                    && file_system.file_state(&self.new_path) == (self.size, self.modified)
+            }
+        }
+    }
+}
+
+/// Records every change to the disk in a file with one JSON entry per line.
+/// The file is only created with the first entry, and an existing journal is
+/// continued instead of overwritten.
+pub struct Journal {
+    path: Option<PathBuf>,
+    is_created: bool,
+    number_of_removed_files: usize,
+}
+
+impl Journal {
+    pub fn new(path: PathBuf) -> Journal {
+        Journal {
+            path: Some(path),
+            is_created: false,
+            number_of_removed_files: 0,
+        }
+    }
+
+    /// A journal that doesn't record anything, e. g. for dry runs
+    pub fn disabled() -> Journal {
+        Journal {
+            path: None,
+            is_created: false,
+            number_of_removed_files: 0,
+        }
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    pub fn record(
+        &mut self,
+        action: Action,
+        old_path: &Path,
+        new_path: &Path,
+        file_system: &dyn FileSystem,
+    ) {
+        let entry = Entry::new(action, old_path, new_path, file_system);
+        if let Err(err) = self.write(&entry, file_system) {
+            eprintln!("Error writing the journal: {}", err);
+        }
+    }
+
+    /// Records tag values written into a file together with the values they
+    /// replaced, so the latter can be written back
+    pub fn record_tags(
+        &mut self,
+        path: &Path,
+        extension: &str,
+        before: &Tags,
+        after: &Tags,
+        file_system: &dyn FileSystem,
+    ) {
+        let entry = Entry {
+            extension: extension.to_string(),
+            before: before.clone(),
+            after: after.clone(),
+            ..Entry::new(Action::WriteTags, path, path, file_system)
+        };
+        if let Err(err) = self.write(&entry, file_system) {
+            eprintln!("Error writing the journal: {}", err);
+        }
+    }
+
+    /// Removes a file by moving it into the journal's trash directory so it
+    /// can be restored later. Without a journal, the file is deleted. If the
+    /// removal can't be recorded, the file is put back and the removal fails.
+    pub fn remove_file(&mut self, path: &Path, file_system: &dyn FileSystem) -> io::Result<()> {
+        let trash_dir = match &self.path {
+            None => return file_system.remove_file(path),
+            Some(journal_path) => journal_path.with_extension("trash"),
+        };
+
+        file_system.create_dir_all(&trash_dir)?;
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        // never overwrite files removed earlier, e. g. by another run using the same journal
+        let trash_path = loop {
+            self.number_of_removed_files += 1;
+            let trash_path =
+                trash_dir.join(format!("{}-{}", self.number_of_removed_files, file_name));
+            if !file_system.exists(&trash_path) {
+                break trash_path;
+            }
+        };
+        file_system.move_file(path, &trash_path)?;
+        let entry = Entry::new(Action::Remove, path, &trash_path, file_system);
+        if let Err(err) = self.write(&entry, file_system) {
+            file_system.move_file(&trash_path, path)?;
+            return Err(err);
+        }
+
+        Ok(())
+    }
+
+    fn write(&mut self, entry: &Entry, file_system: &dyn FileSystem) -> io::Result<()> {
+        let path = match &self.path {
+            None => return Ok(()),
+            Some(path) => path,
+        };
+
+        // write every entry right away so the journal is complete even if the run is aborted
+        let line = format!("{}\n", serde_json::to_string(entry)?);
+        if !self.is_created {
+            if let Some(parent) = path.parent() {
+                file_system.create_dir_all(parent)?;
+            }
+            self.is_created = true;
+        }
+        file_system.append(path, line.as_bytes())
+    }
+}
+
+/// Returns the default location for a new journal, unique to this run
+pub fn default_path() -> Option<PathBuf> {
+    let nanos = UNIX_EPOCH.elapsed().ok()?.as_nanos();
+    dirs::data_local_dir().map(|dir| {
+        dir.join("mp3rename").join("journals").join(format!(
+            "{}-{}.jsonl",
+            nanos,
+            std::process::id()
+        ))
+    })
+}
+
+pub fn read(path: &Path, file_system: &dyn FileSystem) -> io::Result<Vec<Entry>> {
+    let contents = file_system.read_to_string(path)?;
+    let mut entries = Vec::new();
+
+    for line in contents.lines() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        entries.push(serde_json::from_str(line)?);
+    }
+
+    Ok(entries)
+}
+
+/// Reverts all changes recorded in a journal, starting with the last one.
+/// Entries whose files have changed since the run are skipped. Tags that
+/// were added rather than replaced are kept.
+pub fn undo(
+    path: &Path,
+    dry_run: bool,
+    output: &mut Output,
+    source: &dyn MetadataSource,
+    file_system: &dyn FileSystem,
+) {
+    let entries = match read(path, file_system) {
+        Ok(entries) => entries,
+        Err(err) => {
+            eprintln!("Couldn't read journal {}: {}", path.to_string_lossy(), err);
+            return;
+        }
+    };
+
+    for entry in entries.iter().rev() {
+        if !entry.is_unchanged(file_system) {
+            output.skipped_restore(entry, "it has changed since the run");
+            continue;
+        }
+        if entry.action == Action::WriteTags && entry.before.is_empty() {
+            output.skipped_restore(entry, "it only got tags that didn't exist before");
+            continue;
+        }
+        // on case-insensitive file systems, the old path of a rename that
+        // only changed the case exists
+        if matches!(entry.action, Action::Rename | Action::Move | Action::Remove)
+            && file_system.exists(&entry.old_path)
+            && !collisions::is_same_path(&entry.old_path, &entry.new_path)
+        {
+            let reason = format!("\"{}\" exists", entry.old_path.to_string_lossy());
+            output.skipped_restore(entry, &reason);
+            continue;
+        }
+
+        let outcome = if dry_run {
+            Outcome::Planned
+        } else {
+            match undo_entry(entry, source, file_system) {
+                Ok(()) => Outcome::Done,
+                Err(err) => Outcome::Failed(err.to_string()),
+            }
+        };
+        output.restore(entry, &outcome);
+    }
+    output.finish();
+}
+
+fn undo_entry(
+    entry: &Entry,
+    source: &dyn MetadataSource,
+    file_system: &dyn FileSystem,
+) -> io::Result<()> {
+    match entry.action {
+        Action::RemoveDirectory => file_system.create_dir_all(&entry.old_path),
+        Action::Rename => file_system.rename(&entry.new_path, &entry.old_path),
+        Action::Move | Action::Remove => {
+            if let Some(parent) = entry.old_path.parent() {
+                file_system.create_dir_all(parent)?;
+            }
+            file_system.move_file(&entry.new_path, &entry.old_path)
+        }
+        Action::WriteTags => source
+            .write(&entry.new_path, &entry.extension, &entry.before)
+            .map_err(io::Error::other),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_system::{InMemoryFileSystem, RealFileSystem};
+    use crate::metadata_source::InMemory;
+    use crate::output::OutputFormat;
+    use std::fs;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("mp3rename-journal-{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn text_output() -> Output {
+        Output::new(OutputFormat::Text, false)
+    }
+
+    #[test]
+    fn test_record_and_undo() {
+        let dir = temp_dir("undo");
+        let journal_path = dir.join("journal.jsonl");
+        let mut journal = Journal::new(journal_path.clone());
+
+        let old_path = dir.join("old.mp3");
+        let new_path = dir.join("new.mp3");
+        fs::write(&old_path, "music").unwrap();
+        fs::rename(&old_path, &new_path).unwrap();
+        journal.record(Action::Rename, &old_path, &new_path, &RealFileSystem);
+
+        let cover = dir.join("cover.jpg");
+        fs::write(&cover, "picture").unwrap();
+        journal.remove_file(&cover, &RealFileSystem).unwrap();
+        assert!(!cover.exists());
+
+        let entries = read(&journal_path, &RealFileSystem).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].action, Action::Rename);
+        assert_eq!(entries[0].size, Some(5));
+        assert_eq!(entries[1].action, Action::Remove);
+
+        undo(
+            &journal_path,
+            false,
+            &mut text_output(),
+            &InMemory::new(),
+            &RealFileSystem,
+        );
+        assert!(old_path.exists());
+        assert!(!new_path.exists());
+        assert_eq!(fs::read_to_string(&cover).unwrap(), "picture");
+    }
+
+    #[test]
+    fn test_undo_skips_changed_entries() {
+        let dir = temp_dir("changed");
+        let journal_path = dir.join("journal.jsonl");
+        let mut journal = Journal::new(journal_path.clone());
+
+        let old_path = dir.join("old.mp3");
+        let new_path = dir.join("new.mp3");
+        fs::write(&new_path, "music").unwrap();
+        journal.record(Action::Rename, &old_path, &new_path, &RealFileSystem);
+        fs::write(&new_path, "other music").unwrap();
+
+        undo(
+            &journal_path,
+            false,
+            &mut text_output(),
+            &InMemory::new(),
+            &RealFileSystem,
+        );
+        assert!(!old_path.exists());
+        assert!(new_path.exists());
+    }
+
+    #[test]
+    fn test_continue_journal() {
+        let file_system = InMemoryFileSystem::new();
+        let journal_path = PathBuf::from("/journals/journal.jsonl");
+        let first = PathBuf::from("/music/a/cover.jpg");
+        let second = PathBuf::from("/music/b/cover.jpg");
+        file_system.add_file(&first, "first");
+        file_system.add_file(&second, "second");
+
+        // a second run with the same journal keeps what the first one removed
+        for path in &[&first, &second] {
+            let mut journal = Journal::new(journal_path.clone());
+            journal.remove_file(path, &file_system).unwrap();
+        }
+        let entries = read(&journal_path, &file_system).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_ne!(entries[0].new_path, entries[1].new_path);
+
+        undo(
+            &journal_path,
+            false,
+            &mut text_output(),
+            &InMemory::new(),
+            &file_system,
+        );
+        assert_eq!(file_system.read_to_string(&first).unwrap(), "first");
+        assert_eq!(file_system.read_to_string(&second).unwrap(), "second");
+    }
+
+    #[test]
+    fn test_remove_file_without_journal_entry() {
+        let file_system = InMemoryFileSystem::new();
+        let journal_path = PathBuf::from("/journal");
+        let path = PathBuf::from("/music/cover.jpg");
+        file_system.add_file(&path, "picture");
+        // the journal can't be written as a directory has its name
+        file_system.create_dir_all(&journal_path).unwrap();
+
+        let mut journal = Journal::new(journal_path);
+        assert!(journal.remove_file(&path, &file_system).is_err());
+        assert_eq!(file_system.read_to_string(&path).unwrap(), "picture");
+    }
+
+    /// Finds files regardless of the case of their names, like on Windows or macOS
+    struct CaseInsensitive(InMemoryFileSystem);
+
+    impl FileSystem for CaseInsensitive {
+        fn directories(&self, start_dir: &Path) -> Vec<PathBuf> {
+            self.0.directories(start_dir)
+        }
+        fn read_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+            self.0.read_dir(dir)
+        }
+        fn exists(&self, path: &Path) -> bool {
+            self.0
+                .files()
+                .iter()
+                .any(|file| collisions::is_same_path(file, path))
+        }
+        fn is_file(&self, path: &Path) -> bool {
+            self.0.is_file(path)
+        }
+        fn file_state(&self, path: &Path) -> (Option<u64>, Option<u64>) {
+            self.0.file_state(path)
+        }
+        fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+            self.0.rename(from, to)
+        }
+        fn move_file(&self, from: &Path, to: &Path) -> io::Result<()> {
+            self.0.move_file(from, to)
+        }
+        fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+            self.0.create_dir_all(path)
+        }
+        fn remove_file(&self, path: &Path) -> io::Result<()> {
+            self.0.remove_file(path)
+        }
+        fn remove_dir(&self, path: &Path) -> io::Result<()> {
+            self.0.remove_dir(path)
+        }
+        fn read_to_string(&self, path: &Path) -> io::Result<String> {
+            self.0.read_to_string(path)
+        }
+        fn read_at(&self, path: &Path, offset: u64, length: usize) -> io::Result<Vec<u8>> {
+            self.0.read_at(path, offset, length)
+        }
+        fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+            self.0.write(path, contents)
+        }
+        fn append(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+            self.0.append(path, contents)
+        }
+    }
+
+    #[test]
+    fn test_undo_case_only_rename() {
+        let file_system = CaseInsensitive(InMemoryFileSystem::new());
+        let journal_path = PathBuf::from("/journal.jsonl");
+        let mut journal = Journal::new(journal_path.clone());
+
+        let old_path = PathBuf::from("/music/foo.mp3");
+        let new_path = PathBuf::from("/music/Foo.mp3");
+        file_system.0.add_file(&new_path, "music");
+        journal.record(Action::Rename, &old_path, &new_path, &file_system);
+        assert!(file_system.exists(&old_path));
+
+        undo(
+            &journal_path,
+            false,
+            &mut text_output(),
+            &InMemory::new(),
+            &file_system,
+        );
+        assert_eq!(file_system.0.files(), vec![journal_path, old_path]);
+    }
+
+    #[test]
+    fn test_disabled_journal() {
+        let mut journal = Journal::disabled();
+        journal.record(
+            Action::Rename,
+            Path::new("a"),
+            Path::new("b"),
+            &RealFileSystem,
+        );
+        assert_eq!(journal.path(), None);
+    }
+}
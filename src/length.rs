@@ -0,0 +1,196 @@
+use std::cmp;
+use std::fmt;
+use std::fmt::Formatter;
+use std::str::FromStr;
+
+use unicode_segmentation::UnicodeSegmentation;
+
+/// What the length of a name is counted in
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LengthUnit {
+    /// Unicode code points
+    Chars,
+    /// What users perceive as characters, e. g. a letter with combining
+    /// accents or an emoji made of several code points
+    Graphemes,
+    /// Bytes of the UTF-8 encoding, as limited by most Unix file systems
+    Utf8,
+    /// Code units of the UTF-16 encoding, as limited by Windows and macOS
+    Utf16,
+}
+
+impl LengthUnit {
+    /// Returns the length of a text in this unit
+    pub fn measure(self, text: &str) -> usize {
+        match self {
+            LengthUnit::Chars => text.chars().count(),
+            LengthUnit::Graphemes => text.graphemes(true).count(),
+            LengthUnit::Utf8 => text.len(),
+            LengthUnit::Utf16 => text.encode_utf16().count(),
+        }
+    }
+}
+
+impl FromStr for LengthUnit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "chars" => Ok(LengthUnit::Chars),
+            "graphemes" => Ok(LengthUnit::Graphemes),
+            "utf8" => Ok(LengthUnit::Utf8),
+            "utf16" => Ok(LengthUnit::Utf16),
+            _ => Err(format!(
+                "Unknown length unit \"{}\", use chars, graphemes, utf8, or utf16",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for LengthUnit {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            LengthUnit::Chars => write!(f, "chars"),
+            LengthUnit::Graphemes => write!(f, "graphemes"),
+            LengthUnit::Utf8 => write!(f, "utf8"),
+            LengthUnit::Utf16 => write!(f, "utf16"),
+        }
+    }
+}
+
+/// How names exceeding the length limit are shortened
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ShortenStrategy {
+    /// Cut the name at the end
+    Truncate,
+    /// Drop the artist, suffixes in parentheses, and abbreviate words before
+    /// cutting the title
+    Smart,
+}
+
+impl FromStr for ShortenStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "truncate" => Ok(ShortenStrategy::Truncate),
+            "smart" => Ok(ShortenStrategy::Smart),
+            _ => Err(format!(
+                "Unknown shorten strategy \"{}\", use truncate or smart",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ShortenStrategy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ShortenStrategy::Truncate => write!(f, "truncate"),
+            ShortenStrategy::Smart => write!(f, "smart"),
+        }
+    }
+}
+
+/// Cuts a text to at most `limit` in the given unit, never within a grapheme
+/// cluster. Trailing whitespace is removed. If something other than
+/// whitespace is cut off, the text ends with `ellipsis` as long as it fits.
+pub fn truncate(text: &str, limit: usize, unit: LengthUnit, ellipsis: &str) -> String {
+    if unit.measure(text) <= limit {
+        return text.trim_end().to_string();
+    }
+
+    let ellipsis_length = unit.measure(ellipsis);
+    let cut = |limit: usize| {
+        let mut length = 0;
+        let mut end = 0;
+        for (index, grapheme) in text.grapheme_indices(true) {
+            length += unit.measure(grapheme);
+            if length > limit {
+                break;
+            }
+            end = index + grapheme.len();
+        }
+        (&text[..end], &text[end..])
+    };
+
+    let (kept, rest) = cut(limit);
+    if ellipsis.is_empty() || ellipsis_length > limit || rest.trim().is_empty() {
+        return kept.trim_end().to_string();
+    }
+    let (kept, _) = cut(limit - ellipsis_length);
+    format!("{}{}", kept.trim_end(), ellipsis)
+}
+
+/// Returns the largest length the names of the given lengths may keep so
+/// that they fit in `limit` together with `fixed`, cutting the longest names
+/// first. Names aren't cut to nothing, so it returns None if that's not
+/// enough.
+pub fn common_cap(lengths: &[usize], fixed: usize, limit: usize) -> Option<usize> {
+    let longest = lengths.iter().copied().max().unwrap_or(0);
+    (1..=cmp::max(longest, 1)).rev().find(|cap| {
+        fixed
+            + lengths
+                .iter()
+                .map(|length| cmp::min(*length, *cap))
+                .sum::<usize>()
+            <= limit
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_measure() {
+        // "e" with a combining acute accent, and a family emoji
+        let text = "Caf\u{65}\u{301} \u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467}";
+        assert_eq!(LengthUnit::Chars.measure(text), 11);
+        assert_eq!(LengthUnit::Graphemes.measure(text), 6);
+        assert_eq!(LengthUnit::Utf8.measure(text), 25);
+        assert_eq!(LengthUnit::Utf16.measure(text), 14);
+    }
+
+    #[test]
+    fn test_truncate() {
+        assert_eq!(truncate("Über", 2, LengthUnit::Chars, ""), "Üb");
+        // the cut doesn't land within "Ü"
+        assert_eq!(truncate("Über", 2, LengthUnit::Utf8, ""), "Ü");
+        assert_eq!(truncate("Über", 1, LengthUnit::Utf8, ""), "");
+        assert_eq!(truncate("東京タワー", 7, LengthUnit::Utf8, ""), "東京");
+        // nor between a letter and its accent
+        assert_eq!(truncate("Cafe\u{301}s", 4, LengthUnit::Chars, ""), "Caf");
+        assert_eq!(
+            truncate("Cafe\u{301}s", 4, LengthUnit::Graphemes, ""),
+            "Cafe\u{301}"
+        );
+
+        assert_eq!(truncate("Foo Bar", 7, LengthUnit::Chars, "…"), "Foo Bar");
+        assert_eq!(truncate("Foo Bar", 6, LengthUnit::Chars, "…"), "Foo B…");
+        assert_eq!(truncate("Foo Bar", 5, LengthUnit::Chars, "…"), "Foo…");
+        assert_eq!(truncate("Foo Bar", 5, LengthUnit::Utf8, "…"), "Fo…");
+        // only whitespace is cut off, or the ellipsis doesn't fit
+        assert_eq!(truncate("Foo   ", 4, LengthUnit::Chars, "…"), "Foo");
+        assert_eq!(truncate("Foo Bar", 2, LengthUnit::Utf8, "…"), "Fo");
+    }
+
+    #[test]
+    fn test_common_cap() {
+        assert_eq!(common_cap(&[10, 4], 2, 20), Some(10));
+        assert_eq!(common_cap(&[10, 4], 2, 12), Some(6));
+        assert_eq!(common_cap(&[10, 4], 2, 9), Some(3));
+        assert_eq!(common_cap(&[10, 4], 2, 3), None);
+        assert_eq!(common_cap(&[], 2, 3), Some(1));
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("graphemes".parse(), Ok(LengthUnit::Graphemes));
+        assert_eq!(LengthUnit::Utf16.to_string(), "utf16");
+        assert!("bytes".parse::<LengthUnit>().is_err());
+        assert_eq!("smart".parse(), Ok(ShortenStrategy::Smart));
+        assert!("clever".parse::<ShortenStrategy>().is_err());
+    }
+}
@@ -1,67 +1,210 @@
-use std::collections::HashMap;
-use std::ffi::OsString;
-use std::fs;
-use std::path::PathBuf;
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::{Path, PathBuf};
 
+use crate::collisions::Rename;
 use crate::config::Config;
+use crate::file_system::FileSystem;
+use crate::journal::{Action, Journal};
+use crate::metadata_source::MetadataSource;
 use crate::music_file::MusicFile;
 use crate::ordinary_file::OrdinaryFile;
+use crate::output::{Outcome, Output};
+use crate::plan::{DirectoryPlan, Operation, Plan, Tags};
+use crate::tagging::TagSources;
 
+mod collisions;
 pub mod config;
+pub mod fallback;
+pub mod file_system;
+mod formats;
+pub mod journal;
+pub mod length;
+pub mod metadata_source;
 mod music_file;
-mod music_metadata;
+pub mod music_metadata;
+pub mod normalize;
 mod ordinary_file;
+pub mod output;
+mod path_length;
+pub mod path_pattern;
+pub mod plan;
+pub mod sanitize;
+pub mod settings;
+mod shortening;
+mod tag_writer;
+pub mod tagging;
+pub mod target_fs;
+mod template;
 mod util;
 
-pub fn rename_music_files(config: &Config) {
-    let all_files_and_directories = util::get_list_of_dirs(&config);
+/// Renames all music files below the start directory according to the tags
+/// from `source`, saving the plan first if requested
+pub fn rename_music_files(
+    config: &Config,
+    source: &dyn MetadataSource,
+    file_system: &dyn FileSystem,
+) {
+    let plan = plan(config, source, file_system);
+    save_and_execute(&plan, config, source, file_system);
+}
+
+/// Writes the tags taken from `sources` into the music files below the start
+/// directory, saving the plan first if requested
+pub fn tag_music_files(
+    config: &Config,
+    sources: &TagSources,
+    source: &dyn MetadataSource,
+    file_system: &dyn FileSystem,
+) {
+    match tagging::plan(config, sources, source, file_system) {
+        Ok(plan) => save_and_execute(&plan, config, source, file_system),
+        Err(err) => eprintln!("{}", err),
+    }
+}
+
+/// Reports each rule that changes a text on its way to a file or directory
+/// name, together with the resulting name
+pub fn explain_name(text: &str, config: &Config) {
+    let mut output = Output::new(config.output, config.verbose);
+    output.explanation(text, &sanitize::explain(text, config));
+    output.finish();
+}
+
+fn save_and_execute(
+    plan: &Plan,
+    config: &Config,
+    source: &dyn MetadataSource,
+    file_system: &dyn FileSystem,
+) {
+    if let Some(path) = &config.save_plan {
+        if let Err(err) = plan.save(path, file_system) {
+            eprintln!("Couldn't save plan {}: {}", path.to_string_lossy(), err);
+            return;
+        }
+    }
+    execute(plan, config, source, file_system);
+}
+
+/// Carries out a plan saved by an earlier run unless any of its files have
+/// changed since. Tags are written through `source`.
+pub fn apply(
+    path: &Path,
+    config: &Config,
+    source: &dyn MetadataSource,
+    file_system: &dyn FileSystem,
+) {
+    match Plan::load(path, file_system) {
+        Ok(plan) => execute(&plan, config, source, file_system),
+        Err(err) => eprintln!("{}", err),
+    }
+}
+
+/// Finds out what to do with the music files below the start directory
+/// without changing anything
+pub fn plan(config: &Config, source: &dyn MetadataSource, file_system: &dyn FileSystem) -> Plan {
+    let mut plan = Plan {
+        start_dir: config.start_dir.clone(),
+        directories: Vec::new(),
+    };
+    // the new paths of everything planned so far are taken as well
+    let mut planned_paths = HashSet::new();
+    // directories created when organizing, shortened to the maximum path length
+    let mut shortened_dirs = HashMap::new();
 
     // iterate over directories containing at least one music file
-    for dir in all_files_and_directories {
-        if dir.file_type().is_dir() {
-            if let Ok(readdir) = fs::read_dir(dir.path()) {
-                let (music, others): (Vec<fs::DirEntry>, Vec<fs::DirEntry>) = readdir
-                    .filter(|dir_entry| dir_entry.as_ref().unwrap().path().is_file())
-                    .map(|dir_entry| dir_entry.unwrap())
-                    .partition(|dir_entry| util::is_music_file(dir_entry));
-
-                // only use directories containing music files
-                if !music.is_empty() {
-                    let mut music_files: Vec<MusicFile> = music
-                        .into_iter()
-                        .map(MusicFile::new)
-                        .filter(|music_file| music_file.music_metadata.is_some())
-                        .collect();
-                    // by now we can be sure all music_files *have* metadata, else we would have filtered them out above
-                    music_files.sort_by(MusicFile::sort_func);
-
-                    let ordinary_files: Vec<OrdinaryFile> =
-                        others.into_iter().map(OrdinaryFile::new).collect();
-
-                    handle_directory(dir, music_files, ordinary_files, config);
+    for dir in file_system.directories(&config.start_dir) {
+        if let Some((music, others)) = list_directory(&dir, config, file_system) {
+            // only use directories containing music files
+            if !music.is_empty() {
+                let mut directory_plan = DirectoryPlan::new(&dir);
+
+                let mut music_files = Vec::new();
+                for (index, (path, extension)) in music.into_iter().enumerate() {
+                    match MusicFile::new(
+                        path.clone(),
+                        extension,
+                        index + 1,
+                        config,
+                        source,
+                        &mut directory_plan,
+                    ) {
+                        Ok(music_file) => music_files.push(music_file),
+                        Err(reason) => directory_plan
+                            .operations
+                            .push(Operation::Skipped { path, reason }),
+                    }
                 }
+                // by now we can be sure all music_files *have* metadata, else we would have skipped them above
+                music_files.sort_by(MusicFile::sort_func);
+
+                let ordinary_files: Vec<OrdinaryFile> =
+                    others.into_iter().map(OrdinaryFile::new).collect();
+
+                plan_directory(
+                    &mut directory_plan,
+                    music_files,
+                    ordinary_files,
+                    config,
+                    &mut planned_paths,
+                    &mut shortened_dirs,
+                    file_system,
+                );
+                plan.directories.push(directory_plan);
             }
         }
     }
+
+    plan
+}
+
+/// Paths of music files together with their format as lowercase extension
+type MusicPaths = Vec<(PathBuf, String)>;
+
+/// Returns the music files of a directory and the other files, both sorted
+/// by name
+fn list_directory(
+    dir: &Path,
+    config: &Config,
+    file_system: &dyn FileSystem,
+) -> Option<(MusicPaths, Vec<PathBuf>)> {
+    let mut paths: Vec<PathBuf> = file_system
+        .read_dir(dir)
+        .ok()?
+        .into_iter()
+        .filter(|path| file_system.is_file(path))
+        .collect();
+    // keep the order independent of the file system
+    paths.sort();
+
+    let mut music = Vec::new();
+    let mut others = Vec::new();
+    for path in paths {
+        match util::music_extension(&path, config, file_system) {
+            Some(extension) => music.push((path, extension)),
+            None => others.push(path),
+        }
+    }
+
+    Some((music, others))
 }
 
-fn handle_directory(
-    dir_entry: walkdir::DirEntry,
+fn plan_directory(
+    directory_plan: &mut DirectoryPlan,
     music_files: Vec<MusicFile>,
     ordinary_files: Vec<OrdinaryFile>,
     config: &Config,
+    planned_paths: &mut HashSet<PathBuf>,
+    shortened_dirs: &mut HashMap<PathBuf, PathBuf>,
+    file_system: &dyn FileSystem,
 ) {
-    println!("==============");
-    println!(
-        "Entering directory \"{}\"",
-        dir_entry.path().to_string_lossy()
-    );
-
     let same_artist = music_file::same_artists(&music_files);
-    if config.verbose {
-        println!("Same artist: {}", same_artist);
-    }
+    directory_plan
+        .notes
+        .push(format!("Same artist: {}", same_artist));
     let same_album_title = music_file::same_album_title(&music_files);
+    let same_values = music_file::same_values(&music_files, config);
 
     // partition music files by an Option of their disk number to be able to
     // zero-pad the track numbers individually per *disk* instead of per *directory*
@@ -70,7 +213,7 @@ fn handle_directory(
         if let Some(music_metadata) = &music_file.music_metadata {
             let vec = music_files_by_disk_number_map
                 .entry(music_metadata.disk_number)
-                .or_insert_with(Vec::new);
+                .or_default();
             vec.push(music_file);
         }
     }
@@ -84,109 +227,1158 @@ fn handle_directory(
     // Getting keys out of HashMaps is unstable. To always produce the same output,
     // we need to add stability by sorting the keys and producing the output according
     // to this order.
-    let mut sorted_keys: Vec<&Option<u16>> =
-        music_files_by_disk_number_map.keys().into_iter().collect();
+    let mut sorted_keys: Vec<&Option<u16>> = music_files_by_disk_number_map.keys().collect();
     sorted_keys.sort_by(MusicFile::sort_by_disk_number);
 
     // rename music files
+    let mut renames = Vec::new();
+    let mut tags: HashMap<PathBuf, Tags> = HashMap::new();
+    // the music file each rename comes from, with the number of files on its disc
+    let mut sources: HashMap<PathBuf, (&MusicFile, usize)> = HashMap::new();
     for disk_number in sorted_keys {
         if let Some(music_files_by_disk_number) = music_files_by_disk_number_map.get(disk_number) {
             for music_file in music_files_by_disk_number {
+                let from = music_file.path.clone();
                 match music_file.canonical_name(
                     config,
                     same_artist,
                     number_of_digits_for_disc_number,
                     music_files_by_disk_number.len(),
                 ) {
-                    Some(canonical_name) => {
-                        if config.verbose {
-                            println!("Canonical name: {}", canonical_name);
+                    Ok(canonical_name) => {
+                        directory_plan
+                            .notes
+                            .push(format!("Canonical name: {}", canonical_name));
+                        let to_dir = match &config.organize_into {
+                            None => from.parent().map(Path::to_path_buf),
+                            Some(library_root) => music_file
+                                .organized_directory(config)
+                                .map(|dir| library_root.join(dir)),
+                        };
+                        match to_dir {
+                            Some(to_dir) => {
+                                // the extension might be fixed according to the content
+                                let named_as = from.with_extension(music_file.target_extension());
+                                let to = to_dir.join(target_name(
+                                    &named_as,
+                                    config,
+                                    &canonical_name,
+                                    config.name_limit(),
+                                ));
+                                tags.insert(from.clone(), music_file.tags());
+                                sources.insert(
+                                    from.clone(),
+                                    (music_file, music_files_by_disk_number.len()),
+                                );
+                                renames.push(Rename { from, to });
+                            }
+                            None => directory_plan.operations.push(Operation::Skipped {
+                                path: from,
+                                reason: "Couldn't build the target path".to_string(),
+                            }),
                         }
-                        rename_file_or_directory(
-                            music_file.dir_entry.path(),
-                            config,
-                            &canonical_name,
-                        )
                     }
-                    None => eprintln!("Couldn't retrieve canonical name"),
+                    Err(reason) => directory_plan
+                        .operations
+                        .push(Operation::Skipped { path: from, reason }),
                 }
             }
         }
     }
+    if let Some(max_path_length) = config.max_path_length {
+        // build names that are too long again, shortened to the length left
+        let name_within = |rename: &Rename, limit: usize| {
+            let (music_file, number_of_music_files) = sources.get(&rename.from)?;
+            let limit = config
+                .name_limit()
+                .map_or(limit, |name_limit| cmp::min(name_limit, limit));
+            let name = music_file
+                .canonical_name_within(
+                    config,
+                    same_artist,
+                    number_of_digits_for_disc_number,
+                    *number_of_music_files,
+                    Some(limit),
+                )
+                .ok()?;
+            let named_as = rename.from.with_extension(music_file.target_extension());
+            Some(target_name(&named_as, config, &name, Some(limit)))
+        };
+        let (fitting, skipped) = path_length::fit(
+            renames,
+            max_path_length as usize,
+            config,
+            shortened_dirs,
+            name_within,
+        );
+        for (rename, reason) in skipped {
+            directory_plan.operations.push(Operation::Skipped {
+                path: rename.from,
+                reason,
+            });
+        }
+        renames = fitting;
+    }
+    // the longest name in the directory after renaming, limiting its own name
+    let longest_name = renames
+        .iter()
+        .filter_map(|rename| rename.to.file_name())
+        .chain(
+            ordinary_files
+                .iter()
+                .filter(|_| !config.remove_ordinary_files)
+                .filter_map(|file| file.path.file_name()),
+        )
+        .map(|name| name.to_string_lossy().to_string())
+        .max_by_key(|name| config.length_unit.measure(name))
+        .unwrap_or_default();
+    if !plan_renames(
+        directory_plan,
+        renames,
+        config,
+        planned_paths,
+        file_system,
+        |from, to| Operation::RenameFile {
+            tags: tags.get(&from).cloned().unwrap_or_default(),
+            from,
+            to,
+        },
+    ) {
+        return;
+    }
 
     // remove ordinary files
-    if !ordinary_files.is_empty() && config.remove_ordinary_files {
+    if config.remove_ordinary_files {
         for file in &ordinary_files {
-            println!("Removing {}", file.dir_entry.path().to_string_lossy());
-            if !config.dry_run {
-                if let Err(err) = fs::remove_file(file.dir_entry.path()) {
-                    eprintln!(
-                        "Couldn't remove {}: {}",
-                        file.dir_entry.path().to_string_lossy(),
-                        err
-                    );
-                };
-            }
+            directory_plan.operations.push(Operation::RemoveFile {
+                path: file.path.clone(),
+            });
         }
     }
 
     // rename the directory
-    if let Some(album_title) = same_album_title {
-        if config.verbose {
-            println!("Same album title: {}", album_title);
+    match same_album_title {
+        Some(album_title) => directory_plan
+            .notes
+            .push(format!("Same album title: {}", album_title)),
+        None => directory_plan
+            .notes
+            .push("Multiple album names.".to_string()),
+    }
+    // organizing moves the files out of the directory, so there's nothing left to rename
+    if config.rename_directory && config.organize_into.is_none() {
+        match config.dir_template.render(&same_values) {
+            Some(dir_name) => {
+                let from = directory_plan.path.clone();
+                let mut to =
+                    from.with_file_name(target_name(&from, config, &dir_name, config.name_limit()));
+                if let Some(max_path_length) = config.max_path_length {
+                    match path_length::fit_directory(
+                        &to,
+                        &longest_name,
+                        max_path_length as usize,
+                        config,
+                    ) {
+                        Some(fitting) => to = fitting,
+                        None => {
+                            directory_plan.notes.push(format!(
+                                "Not renaming the directory: \"{}\" doesn't fit in the maximum path length of {}",
+                                to.to_string_lossy(),
+                                max_path_length
+                            ));
+                            to = from.clone();
+                        }
+                    }
+                }
+                plan_renames(
+                    directory_plan,
+                    vec![Rename { from, to }],
+                    config,
+                    planned_paths,
+                    file_system,
+                    |from, to| Operation::RenameDirectory {
+                        from,
+                        to,
+                        tags: music_file::to_tags(&same_values),
+                    },
+                );
+            }
+            None => directory_plan.notes.push(format!(
+                "Not renaming the directory: \"{}\" needs values that differ between the files.",
+                config.dir_template
+            )),
+        }
+    }
+
+    if config.organize_into.is_some() {
+        directory_plan
+            .operations
+            .push(Operation::RemoveDirectoryIfEmpty {
+                path: directory_plan.path.clone(),
+            });
+    }
+}
+
+/// Checks a set of renames for collisions and adds the safe ones to the plan.
+/// Returns false if the renames were aborted due to a collision.
+fn plan_renames<F>(
+    directory_plan: &mut DirectoryPlan,
+    renames: Vec<Rename>,
+    config: &Config,
+    planned_paths: &mut HashSet<PathBuf>,
+    file_system: &dyn FileSystem,
+    operation: F,
+) -> bool
+where
+    F: Fn(PathBuf, PathBuf) -> Operation,
+{
+    let mut existing = collisions::existing_paths(&renames, file_system);
+    existing.extend(planned_paths.iter().cloned());
+    let resolution = collisions::resolve(renames, &existing, config.on_collision, |path, n| {
+        util::with_number_suffix(path, n, config)
+    });
+
+    if let Some(reason) = resolution.aborted {
+        directory_plan.operations.push(Operation::Skipped {
+            path: directory_plan.path.clone(),
+            reason: format!("Collision, leaving the directory alone: {}", reason),
+        });
+        return false;
+    }
+    for (rename, reason) in resolution.skipped {
+        directory_plan.operations.push(Operation::Skipped {
+            path: rename.from,
+            reason: format!("Collision: {}", reason),
+        });
+    }
+    for rename in resolution.renames {
+        if rename.from != rename.to {
+            planned_paths.insert(rename.to.clone());
+            directory_plan
+                .operations
+                .push(operation(rename.from, rename.to));
+        }
+    }
+
+    true
+}
+
+/// Carries out a plan, recording every change in a journal. In dry-run
+/// mode, the operations are only reported. Tags are written through `source`.
+pub fn execute(
+    plan: &Plan,
+    config: &Config,
+    source: &dyn MetadataSource,
+    file_system: &dyn FileSystem,
+) {
+    let mut output = Output::new(config.output, config.verbose);
+    let mut journal = if config.dry_run {
+        Journal::disabled()
+    } else {
+        match config.journal.clone().or_else(journal::default_path) {
+            Some(path) => Journal::new(path),
+            None => {
+                eprintln!("Couldn't determine where to write the journal, continuing without one");
+                Journal::disabled()
+            }
+        }
+    };
+
+    for directory_plan in &plan.directories {
+        output.directory(directory_plan);
+
+        // file renames have to be carried out together to handle cycles
+        let mut renames = Vec::new();
+        for operation in &directory_plan.operations {
+            if let Operation::RenameFile { .. } = operation {
+                renames.push(operation);
+                continue;
+            }
+            execute_renames(&renames, config, &mut journal, &mut output, file_system);
+            renames.clear();
+            execute_operation(
+                operation,
+                plan,
+                config,
+                &mut journal,
+                &mut output,
+                source,
+                file_system,
+            );
+        }
+        execute_renames(&renames, config, &mut journal, &mut output, file_system);
+    }
+
+    if let Some(path) = journal.path() {
+        if file_system.exists(path) {
+            output.journal(path);
+        }
+    }
+    output.finish();
+}
+
+fn execute_operation(
+    operation: &Operation,
+    plan: &Plan,
+    config: &Config,
+    journal: &mut Journal,
+    output: &mut Output,
+    source: &dyn MetadataSource,
+    file_system: &dyn FileSystem,
+) {
+    let outcome = match operation {
+        Operation::RenameFile { .. } => {
+            execute_renames(&[operation], config, journal, output, file_system);
+            return;
+        }
+        Operation::RenameDirectory { from, to, .. } => outcome_of(config, || {
+            rename_file_or_directory(from, to, journal, file_system)
+        }),
+        Operation::RemoveFile { path } => {
+            outcome_of(config, || journal.remove_file(path, file_system))
         }
-        if config.rename_directory {
-            rename_file_or_directory(dir_entry.path().to_path_buf(), config, &album_title)
+        Operation::WriteTags {
+            path,
+            extension,
+            before,
+            after,
+        } => outcome_of(config, || {
+            source
+                .write(path, extension, after)
+                .map_err(io::Error::other)?;
+            journal.record_tags(path, extension, before, after, file_system);
+            Ok(())
+        }),
+        // only the directories actually removed are reported
+        Operation::RemoveDirectoryIfEmpty { .. } if config.dry_run => Outcome::Planned,
+        Operation::RemoveDirectoryIfEmpty { path } => {
+            remove_empty_directories(path, &plan.start_dir, journal, output, file_system);
+            return;
         }
-    } else if config.verbose {
-        println!("Multiple album names.")
+        Operation::Skipped { .. } => Outcome::Skipped,
+    };
+    output.operation(operation, &outcome);
+}
+
+/// Carries out a change unless in dry-run mode
+fn outcome_of<F>(config: &Config, change: F) -> Outcome
+where
+    F: FnOnce() -> io::Result<()>,
+{
+    if config.dry_run {
+        return Outcome::Planned;
+    }
+    match change() {
+        Ok(()) => Outcome::Done,
+        Err(e) => Outcome::Failed(e.to_string()),
     }
 }
 
-/// Rename a file or directory name in a path
-fn rename_file_or_directory(old_path: PathBuf, config: &Config, to_name: &str) {
-    let old_name = old_path
-        .file_name()
-        .unwrap_or_else(|| {
-            panic!(
-                "Cannot retrieve name part from {}",
-                old_path.to_string_lossy()
-            )
+/// Carries out a set of file renames. Renames that would overwrite anything fail.
+fn execute_renames(
+    operations: &[&Operation],
+    config: &Config,
+    journal: &mut Journal,
+    output: &mut Output,
+    file_system: &dyn FileSystem,
+) {
+    let mut renamed_operations = Vec::new();
+    let mut renames = Vec::new();
+    for operation in operations {
+        if let Operation::RenameFile { from, to, .. } = operation {
+            if from != to {
+                renamed_operations.push(*operation);
+                renames.push(Rename {
+                    from: from.clone(),
+                    to: to.clone(),
+                });
+            }
+        }
+    }
+
+    let outcomes = if config.dry_run {
+        vec![Outcome::Planned; renames.len()]
+    } else if needs_temporary_names(&renames) {
+        output.note("Renaming via temporary names as new names are still in use.");
+        rename_in_two_phases(&renames, journal, file_system)
+    } else {
+        renames
+            .iter()
+            .map(|rename| {
+                match rename_file_or_directory(&rename.from, &rename.to, journal, file_system) {
+                    Ok(()) => Outcome::Done,
+                    Err(e) => Outcome::Failed(e.to_string()),
+                }
+            })
+            .collect()
+    };
+
+    for (operation, outcome) in renamed_operations.iter().zip(&outcomes) {
+        output.operation(operation, outcome);
+    }
+}
+
+/// Is a new name still in use by another file of the set, e. g. when swapping names?
+fn needs_temporary_names(renames: &[Rename]) -> bool {
+    renames.iter().any(|rename| {
+        renames.iter().any(|other| {
+            other.from != rename.from && collisions::is_same_path(&rename.to, &other.from)
         })
-        .to_string_lossy();
+    })
+}
+
+/// Renames all files to temporary names first and then to their new names.
+/// This way, permutations and cycles of names always succeed. If a rename
+/// fails in either phase, everything renamed so far is renamed back.
+/// Returns the outcome of every rename.
+fn rename_in_two_phases(
+    renames: &[Rename],
+    journal: &mut Journal,
+    file_system: &dyn FileSystem,
+) -> Vec<Outcome> {
+    let mut temporary_paths: Vec<PathBuf> = Vec::new();
 
+    for (index, rename) in renames.iter().enumerate() {
+        let temporary_path =
+            rename
+                .from
+                .with_file_name(format!(".mp3rename-{}-{}.tmp", std::process::id(), index));
+        if let Err(e) =
+            rename_file_or_directory(&rename.from, &temporary_path, journal, file_system)
+        {
+            let error = roll_back(renames, &temporary_paths, 0, journal, file_system);
+            return vec![Outcome::Failed(format!("{}{}", e, error)); renames.len()];
+        }
+        temporary_paths.push(temporary_path);
+    }
+
+    for (index, (rename, temporary_path)) in renames.iter().zip(&temporary_paths).enumerate() {
+        if let Err(e) = rename_file_or_directory(temporary_path, &rename.to, journal, file_system) {
+            let error = roll_back(renames, &temporary_paths, index, journal, file_system);
+            return vec![Outcome::Failed(format!("{}{}", e, error)); renames.len()];
+        }
+    }
+
+    vec![Outcome::Done; renames.len()]
+}
+
+/// Renames the first `finished` files back from their new names to their
+/// temporary names, and then all files with temporary names back to their
+/// original names, last first, so that the original names are free again
+/// when they're needed. Returns the files that were left under another name.
+fn roll_back(
+    renames: &[Rename],
+    temporary_paths: &[PathBuf],
+    finished: usize,
+    journal: &mut Journal,
+    file_system: &dyn FileSystem,
+) -> String {
+    let mut error = String::new();
+    let mut left_behind = vec![false; temporary_paths.len()];
+    let mut rename_back = |index: usize, from: &Path, to: &Path, journal: &mut Journal| {
+        if left_behind[index] {
+            return;
+        }
+        if let Err(e) = rename_file_or_directory(from, to, journal, file_system) {
+            left_behind[index] = true;
+            error = format!(
+                "{}; couldn't rename \"{}\" back to \"{}\": {}",
+                error,
+                from.to_string_lossy(),
+                to.to_string_lossy(),
+                e
+            );
+        }
+    };
+
+    for index in (0..finished).rev() {
+        rename_back(index, &renames[index].to, &temporary_paths[index], journal);
+    }
+    for index in (0..temporary_paths.len()).rev() {
+        rename_back(
+            index,
+            &temporary_paths[index],
+            &renames[index].from,
+            journal,
+        );
+    }
+    error
+}
+
+/// Rename a file or directory, or move it to another directory, and record
+/// the change in the journal
+fn rename_file_or_directory(
+    from: &Path,
+    to: &Path,
+    journal: &mut Journal,
+    file_system: &dyn FileSystem,
+) -> io::Result<()> {
+    // never overwrite anything, but allow changing the case of a name
+    if file_system.exists(to) && !collisions::is_same_path(from, to) {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("\"{}\" already exists", to.to_string_lossy()),
+        ));
+    }
+
+    if from.parent() == to.parent() {
+        file_system.rename(from, to)?;
+        journal.record(Action::Rename, from, to, file_system);
+    } else {
+        if let Some(to_dir) = to.parent() {
+            file_system.create_dir_all(to_dir)?;
+        }
+        file_system.move_file(from, to)?;
+        journal.record(Action::Move, from, to, file_system);
+    }
+
+    Ok(())
+}
+
+/// Remove a directory if it is empty, and continue with its parents up to
+/// (but excluding) the start directory
+fn remove_empty_directories(
+    dir: &Path,
+    start_dir: &Path,
+    journal: &mut Journal,
+    output: &mut Output,
+    file_system: &dyn FileSystem,
+) {
+    let mut dir = dir.to_path_buf();
+
+    while dir.starts_with(start_dir) && dir != start_dir {
+        let is_empty = match file_system.read_dir(&dir) {
+            Ok(paths) => paths.is_empty(),
+            Err(_) => false,
+        };
+        if !is_empty {
+            return;
+        }
+
+        if let Err(e) = file_system.remove_dir(&dir) {
+            eprintln!(
+                "Couldn't remove directory \"{}\": {}",
+                dir.to_string_lossy(),
+                e
+            );
+            return;
+        }
+        output.removed_directory(&dir);
+        journal.record(Action::RemoveDirectory, &dir, &dir, file_system);
+        if !dir.pop() {
+            return;
+        }
+    }
+}
+
+/// Returns the sanitized name a file or directory is to be renamed to,
+/// shortened to `limit` if given, and fitting the target file system
+fn target_name(old_path: &Path, config: &Config, to_name: &str, limit: Option<usize>) -> String {
     // sanitize the canonical name *without* extension to catch cases like
     // "Foo....mp3" which should become "Foo.mp3"
-    let (extension, _): (String, usize) = util::get_extension(&old_path);
+    let (extension, _): (String, usize) = util::get_extension(old_path);
     let mut short_name_stem = util::get_name_stem(to_name, &extension); // both parameters use lowercase for the extension
-    short_name_stem = util::sanitize_file_or_directory_name(&short_name_stem);
+    short_name_stem = util::sanitize_file_or_directory_name(&short_name_stem, config);
 
     // now rebuild the name *with* the extension to be able to shorten the canonical name
     let mut to_name = format!("{}{}", short_name_stem, extension);
-    if config.shorten_names {
-        to_name = util::shorten_names(&old_path, &to_name, config);
-    }
-
-    let new_path = old_path.with_file_name(OsString::from(to_name));
-    let new_name = &new_path
-        .file_name()
-        .unwrap_or_else(|| {
-            panic!(
-                "Cannot retrieve name part from {}",
-                new_path.to_string_lossy()
-            )
-        })
-        .to_string_lossy();
+    if let Some(limit) = limit {
+        to_name = util::shorten_name_to(old_path, &to_name, limit, config);
+    }
 
-    if old_name.eq(new_name) {
-        return;
+    util::fit_target_fs(old_path, &to_name, config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collisions::CollisionStrategy;
+    use crate::file_system::InMemoryFileSystem;
+    use crate::length::ShortenStrategy;
+    use crate::metadata_source::InMemory;
+    use crate::music_metadata::{MusicMetadata, RawTags};
+    use crate::normalize::Replacement;
+    use crate::output::OutputFormat;
+    use crate::path_pattern::PathPattern;
+
+    fn rename(dir: &Path, from: &str, to: &str) -> Operation {
+        Operation::RenameFile {
+            from: dir.join(from),
+            to: dir.join(to),
+            tags: Tags::new(),
+        }
+    }
+
+    fn music_metadata(title: &str, track_number: u16) -> MusicMetadata {
+        MusicMetadata {
+            album: "The Foos are Back".to_string(),
+            artist: "The Foos".to_string(),
+            disk_number: None,
+            title: title.to_string(),
+            track_number,
+            ..MusicMetadata::default()
+        }
+    }
+
+    fn files_below(file_system: &InMemoryFileSystem, dir: &str) -> Vec<PathBuf> {
+        file_system
+            .files()
+            .into_iter()
+            .filter(|path| path.starts_with(dir))
+            .collect()
     }
 
-    println!("Renaming \"{}\" to \"{}\"", old_name, new_name);
+    #[test]
+    fn test_plan_with_in_memory_tags() {
+        let file_system = InMemoryFileSystem::new();
+        let dir = PathBuf::from("/music/album");
+        for name in &["a.mp3", "b.mp3", "c.mp3", "cover.jpg"] {
+            file_system.add_file(&dir.join(name), "");
+        }
+        let mut source = InMemory::new();
+        source.insert(&dir.join("a.mp3"), music_metadata("Foo", 2));
+        source.insert(&dir.join("b.mp3"), music_metadata("Bar", 1));
+
+        let config = Config {
+            remove_artist: true,
+            remove_ordinary_files: true,
+            rename_directory: true,
+            start_dir: PathBuf::from("/music"),
+            ..Config::default()
+        };
+        let plan = plan(&config, &source, &file_system);
+
+        assert_eq!(plan.directories.len(), 1);
+        let operations = &plan.directories[0].operations;
+        assert_eq!(operations.len(), 5);
+        assert_eq!(
+            operations[0],
+            Operation::Skipped {
+                path: dir.join("c.mp3"),
+                reason: "No tags found".to_string(),
+            }
+        );
+        assert!(
+            matches!(&operations[1], Operation::RenameFile { from, to, .. } if from == &dir.join("b.mp3") && to == &dir.join("1 Bar.mp3"))
+        );
+        assert!(
+            matches!(&operations[2], Operation::RenameFile { from, to, .. } if from == &dir.join("a.mp3") && to == &dir.join("2 Foo.mp3"))
+        );
+        assert_eq!(
+            operations[3],
+            Operation::RemoveFile {
+                path: dir.join("cover.jpg"),
+            }
+        );
+        assert!(
+            matches!(&operations[4], Operation::RenameDirectory { to, .. } if to == &dir.with_file_name("The Foos are Back"))
+        );
+        // planning doesn't change anything
+        assert_eq!(file_system.files().len(), 4);
+    }
+
+    #[test]
+    fn test_plan_detecting_content_in_memory() {
+        let file_system = InMemoryFileSystem::new();
+        let dir = PathBuf::from("/music/album");
+        file_system.create_dir_all(&dir).unwrap();
+        file_system
+            .write(&dir.join("a.ogg"), b"fLaC\0\0\0\x22")
+            .unwrap();
+        file_system
+            .write(&dir.join("b"), b"ID3\x04\0\0\0\0\0\0\xFF\xFB\x90\x64")
+            .unwrap();
+        file_system.add_file(&dir.join("notes.mp3"), "not music");
+        let mut source = InMemory::new();
+        source.insert(&dir.join("a.ogg"), music_metadata("Foo", 2));
+        source.insert(&dir.join("b"), music_metadata("Bar", 1));
+
+        let config = Config {
+            detect_content: true,
+            start_dir: PathBuf::from("/music"),
+            ..Config::default()
+        };
+        let plan = plan(&config, &source, &file_system);
+
+        let renames: Vec<(PathBuf, PathBuf)> = plan.directories[0]
+            .operations
+            .iter()
+            .filter_map(|operation| match operation {
+                Operation::RenameFile { from, to, .. } => Some((from.clone(), to.clone())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            renames,
+            vec![
+                (dir.join("b"), dir.join("1 The Foos - Bar.mp3")),
+                (dir.join("a.ogg"), dir.join("2 The Foos - Foo.flac")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_plan_detecting_content_keeps_unrecognized_music_files() {
+        let file_system = InMemoryFileSystem::new();
+        let dir = PathBuf::from("/music/album");
+        file_system.create_dir_all(&dir).unwrap();
+        // an MP4 file with a generic brand and an MP3 file with padding
+        // before the first frame
+        file_system
+            .write(&dir.join("a.m4a"), b"\0\0\0\x14ftypisom\0\0\0\0mp41")
+            .unwrap();
+        file_system
+            .write(&dir.join("b.mp3"), b"\0\0\0\0\xFF\xFB\x90\x64")
+            .unwrap();
+        file_system.add_file(&dir.join("cover.jpg"), "picture");
+        let source = InMemory::new();
+
+        let config = Config {
+            detect_content: true,
+            remove_ordinary_files: true,
+            start_dir: PathBuf::from("/music"),
+            ..Config::default()
+        };
+        let plan = plan(&config, &source, &file_system);
+
+        let removed: Vec<&PathBuf> = plan.directories[0]
+            .operations
+            .iter()
+            .filter_map(|operation| match operation {
+                Operation::RemoveFile { path } => Some(path),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(removed, vec![&dir.join("cover.jpg")]);
+    }
 
-    if !config.dry_run {
-        if let Err(e) = fs::rename(&old_path, new_path) {
-            eprintln!("Error renaming \"{}\": {}", old_name, e);
+    #[test]
+    fn test_plan_with_fallbacks() {
+        let file_system = InMemoryFileSystem::new();
+        let dir = PathBuf::from("/music/The Foos/Hits");
+        for name in &["07 Foo.mp3", "Bar.mp3", "Baz.mp3"] {
+            file_system.add_file(&dir.join(name), "");
         }
+        let mut source = InMemory::new();
+        source.insert_tags(
+            &dir.join("07 Foo.mp3"),
+            RawTags {
+                title: Some("Foo".to_string()),
+                ..RawTags::default()
+            },
+        );
+        source.insert_tags(
+            &dir.join("Bar.mp3"),
+            RawTags {
+                artist: Some("The Bars".to_string()),
+                ..RawTags::default()
+            },
+        );
+
+        let config = Config {
+            fallbacks: "album,artist,title,track".parse().unwrap(),
+            start_dir: PathBuf::from("/music"),
+            ..Config::default()
+        };
+        let plan = plan(&config, &source, &file_system);
+
+        // even a file without any tags is renamed, and each guess is reported
+        let directory_plan = &plan.directories[0];
+        let renames: Vec<(PathBuf, PathBuf)> = directory_plan
+            .operations
+            .iter()
+            .filter_map(|operation| match operation {
+                Operation::RenameFile { from, to, .. } => Some((from.clone(), to.clone())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            renames,
+            vec![
+                (dir.join("Bar.mp3"), dir.join("2 The Bars - Bar.mp3")),
+                (dir.join("Baz.mp3"), dir.join("3 The Foos - Baz.mp3")),
+                (dir.join("07 Foo.mp3"), dir.join("7 The Foos - Foo.mp3")),
+            ]
+        );
+        assert!(directory_plan
+            .guesses
+            .contains(&"Guessed track number 7 from the file name for \"07 Foo.mp3\"".to_string()));
+        assert!(directory_plan.guesses.contains(
+            &"Guessed track number 2 from the position in the directory for \"Bar.mp3\""
+                .to_string()
+        ));
+        assert_eq!(directory_plan.guesses.len(), 10);
+
+        // without fallbacks, incomplete tags are skipped
+        let config = Config {
+            start_dir: PathBuf::from("/music"),
+            ..Config::default()
+        };
+        let plan = super::plan(&config, &source, &file_system);
+        assert!(plan.directories[0]
+            .operations
+            .iter()
+            .all(|operation| matches!(operation, Operation::Skipped { .. })));
+    }
+
+    #[test]
+    fn test_rename_nested_directories_and_undo() {
+        // contents first: "cd" is renamed before the directory containing it
+        let file_system = InMemoryFileSystem::new();
+        let mut source = InMemory::new();
+        for (path, title, track_number) in &[
+            ("/music/foos/x.mp3", "Intro", 1),
+            ("/music/foos/cd/a.mp3", "Foo", 2),
+            ("/music/foos/cd/b.mp3", "Bar", 1),
+        ] {
+            file_system.add_file(Path::new(path), title);
+            source.insert(Path::new(path), music_metadata(title, *track_number));
+        }
+        let mut intro = music_metadata("Intro", 1);
+        intro.album = "Foo Singles".to_string();
+        source.insert(Path::new("/music/foos/x.mp3"), intro);
+
+        let journal_path = PathBuf::from("/journals/run.jsonl");
+        let config = Config {
+            journal: Some(journal_path.clone()),
+            output: OutputFormat::Json,
+            remove_artist: true,
+            rename_directory: true,
+            start_dir: PathBuf::from("/music"),
+            ..Config::default()
+        };
+        rename_music_files(&config, &source, &file_system);
+
+        assert_eq!(
+            files_below(&file_system, "/music"),
+            vec![
+                PathBuf::from("/music/Foo Singles/1 Intro.mp3"),
+                PathBuf::from("/music/Foo Singles/The Foos are Back/1 Bar.mp3"),
+                PathBuf::from("/music/Foo Singles/The Foos are Back/2 Foo.mp3"),
+            ]
+        );
+        assert_eq!(
+            file_system
+                .read_to_string(Path::new("/music/Foo Singles/The Foos are Back/1 Bar.mp3"))
+                .unwrap(),
+            "Bar"
+        );
+
+        journal::undo(
+            &journal_path,
+            false,
+            &mut Output::new(OutputFormat::Text, false),
+            &source,
+            &file_system,
+        );
+        assert_eq!(
+            files_below(&file_system, "/music"),
+            vec![
+                PathBuf::from("/music/foos/cd/a.mp3"),
+                PathBuf::from("/music/foos/cd/b.mp3"),
+                PathBuf::from("/music/foos/x.mp3"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_organize_and_remove_empty_directories() {
+        let file_system = InMemoryFileSystem::new();
+        let mut source = InMemory::new();
+        let dir = PathBuf::from("/music/incoming/album");
+        file_system.add_file(&dir.join("a.mp3"), "Foo");
+        file_system.add_file(&dir.join("cover.jpg"), "picture");
+        source.insert(&dir.join("a.mp3"), music_metadata("Foo", 1));
+
+        let journal_path = PathBuf::from("/journals/run.jsonl");
+        let config = Config {
+            journal: Some(journal_path.clone()),
+            organize_into: Some(PathBuf::from("/library")),
+            output: OutputFormat::Json,
+            remove_artist: true,
+            remove_ordinary_files: true,
+            start_dir: PathBuf::from("/music"),
+            ..Config::default()
+        };
+        rename_music_files(&config, &source, &file_system);
+
+        assert_eq!(
+            files_below(&file_system, "/library"),
+            vec![PathBuf::from(
+                "/library/The Foos/The Foos are Back/1 Foo.mp3"
+            )]
+        );
+        // the removed cover is kept next to the journal
+        assert_eq!(
+            files_below(&file_system, "/journals/run.trash"),
+            vec![PathBuf::from("/journals/run.trash/1-cover.jpg")]
+        );
+        // everything below the start directory is gone, but not the start directory itself
+        assert!(!file_system.exists(Path::new("/music/incoming")));
+        assert!(file_system.exists(Path::new("/music")));
+
+        journal::undo(
+            &journal_path,
+            false,
+            &mut Output::new(OutputFormat::Text, false),
+            &source,
+            &file_system,
+        );
+        assert_eq!(
+            files_below(&file_system, "/music"),
+            vec![dir.join("a.mp3"), dir.join("cover.jpg")]
+        );
+    }
+
+    #[test]
+    fn test_path_pattern_and_write_tags() {
+        let file_system = InMemoryFileSystem::new();
+        let dir = PathBuf::from("/music/The Foos/Hits (1999)");
+        file_system.add_file(&dir.join("03 - Foo.mp3"), "");
+        file_system.add_file(&dir.join("04 - Bar.mp3"), "");
+        let mut source = InMemory::new();
+        source.insert_tags(
+            &dir.join("04 - Bar.mp3"),
+            RawTags {
+                title: Some("Bar (Live)".to_string()),
+                ..RawTags::default()
+            },
+        );
+
+        let pattern = PathPattern::parse("{artist}/{album} ({year})/{track} - {title}").unwrap();
+
+        // the values from the path may replace existing tags
+        let config = Config {
+            override_tags: true,
+            path_pattern: Some(pattern.clone()),
+            start_dir: PathBuf::from("/music"),
+            ..Config::default()
+        };
+        let plan = plan(&config, &source, &file_system);
+        assert!(plan.operations().any(|operation| matches!(
+            operation,
+            Operation::RenameFile { to, .. } if to == &dir.join("4 The Foos - Bar.mp3")
+        )));
+
+        // otherwise, they only fill in missing tags
+        let config = Config {
+            output: OutputFormat::Json,
+            path_pattern: Some(pattern),
+            start_dir: PathBuf::from("/music"),
+            write_tags: true,
+            ..Config::default()
+        };
+        rename_music_files(&config, &source, &file_system);
+        assert_eq!(
+            files_below(&file_system, "/music"),
+            vec![
+                dir.join("3 The Foos - Foo.mp3"),
+                dir.join("4 The Foos - Bar (Live).mp3"),
+            ]
+        );
+        // the tags are written before renaming
+        let tags = source.read(&dir.join("03 - Foo.mp3"), "mp3").unwrap();
+        assert_eq!(tags.album, Some("Hits".to_string()));
+        assert_eq!(tags.year, Some(1999));
+        let tags = source.read(&dir.join("04 - Bar.mp3"), "mp3").unwrap();
+        assert_eq!(tags.title, Some("Bar (Live)".to_string()));
+        assert_eq!(tags.track, Some(4));
+    }
+
+    #[test]
+    fn test_plan_with_normalized_tags() {
+        let file_system = InMemoryFileSystem::new();
+        let dir = PathBuf::from("/music/Hits");
+        file_system.add_file(&dir.join("a.mp3"), "");
+        let mut source = InMemory::new();
+        source.insert_tags(
+            &dir.join("a.mp3"),
+            RawTags {
+                album: Some("Hits".to_string()),
+                artist: Some("The  Foos".to_string()),
+                title: Some("DON’T STOP (FEAT. BAR)".to_string()),
+                track: Some(1),
+                ..RawTags::default()
+            },
+        );
+
+        let mut config = Config {
+            normalize: "all".parse().unwrap(),
+            replacements: vec![Replacement::new(r"(?i)\s*\(feat\. [^)]*\)", "").unwrap()],
+            start_dir: PathBuf::from("/music"),
+            ..Config::default()
+        };
+        let plan = plan(&config, &source, &file_system);
+        let operations: Vec<&Operation> = plan.operations().collect();
+        assert_eq!(operations.len(), 1);
+        assert_eq!(
+            operations[0].to_string(),
+            "Renaming \"a.mp3\" to \"1 The Foos - Don't Stop.mp3\""
+        );
+
+        // the normalized values may be written back
+        config.write_normalized = true;
+        let plan = super::plan(&config, &source, &file_system);
+        let operations: Vec<&Operation> = plan.operations().collect();
+        assert_eq!(operations.len(), 2);
+        assert_eq!(
+            operations[0].to_string(),
+            "Writing tags to \"a.mp3\": artist \"The  Foos\" -> \"The Foos\", \
+            title \"DON’T STOP (FEAT. BAR)\" -> \"Don't Stop\""
+        );
+
+        // undo writes back the replaced values
+        let journal_path = PathBuf::from("/journals/run.jsonl");
+        config.journal = Some(journal_path.clone());
+        config.output = OutputFormat::Json;
+        rename_music_files(&config, &source, &file_system);
+        let tags = source.read(&dir.join("a.mp3"), "mp3").unwrap();
+        assert_eq!(tags.title, Some("Don't Stop".to_string()));
+
+        journal::undo(
+            &journal_path,
+            false,
+            &mut Output::new(OutputFormat::Json, false),
+            &source,
+            &file_system,
+        );
+        assert_eq!(files_below(&file_system, "/music"), vec![dir.join("a.mp3")]);
+        let tags = source.read(&dir.join("a.mp3"), "mp3").unwrap();
+        assert_eq!(tags.artist, Some("The  Foos".to_string()));
+        assert_eq!(tags.title, Some("DON’T STOP (FEAT. BAR)".to_string()));
+    }
+
+    #[test]
+    fn test_plan_with_max_path_length() {
+        let file_system = InMemoryFileSystem::new();
+        let dir = PathBuf::from("/music/ab");
+        let mut source = InMemory::new();
+        for (name, title) in &[("a.mp3", "Foo Bar One"), ("b.mp3", "Foo Bar Two")] {
+            file_system.add_file(&dir.join(name), "");
+            source.insert_tags(
+                &dir.join(name),
+                RawTags {
+                    album: Some("Hits".to_string()),
+                    artist: Some("X".to_string()),
+                    title: Some(title.to_string()),
+                    track: Some(1),
+                    ..RawTags::default()
+                },
+            );
+        }
+        let renamed = |config: &Config| -> Vec<PathBuf> {
+            plan(config, &source, &file_system)
+                .operations()
+                .filter_map(|operation| match operation {
+                    Operation::RenameFile { to, .. } => Some(to.clone()),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        // cutting the names makes them collide, and the number has to fit as well
+        let config = Config {
+            max_path_length: Some(18),
+            on_collision: CollisionStrategy::Suffix,
+            start_dir: PathBuf::from("/music"),
+            ..Config::default()
+        };
+        assert_eq!(
+            renamed(&config),
+            vec![dir.join("1 X - Foo B.mp3"), dir.join("1 X - F (2).mp3")]
+        );
+
+        // the smart strategy drops the artist first
+        let config = Config {
+            shorten_strategy: ShortenStrategy::Smart,
+            ..config
+        };
+        let paths = renamed(&config);
+        assert_eq!(
+            paths,
+            vec![dir.join("1 Foo Bar O.mp3"), dir.join("1 Foo Bar T.mp3")]
+        );
+        for path in &paths {
+            assert!(path_length::measure(path, &config) <= 18);
+        }
+    }
+
+    #[test]
+    fn test_execute_renames_with_cycles() {
+        let file_system = InMemoryFileSystem::new();
+        let dir = PathBuf::from("/music");
+        for name in &["a", "b", "c", "d"] {
+            file_system.add_file(&dir.join(name), name);
+        }
+        let journal_path = dir.join("journal.jsonl");
+        let mut journal = Journal::new(journal_path.clone());
+
+        // swap "a" and "b", rotate "c" to "d" to "e"
+        let operations = [
+            rename(&dir, "a", "b"),
+            rename(&dir, "b", "a"),
+            rename(&dir, "c", "d"),
+            rename(&dir, "d", "e"),
+        ];
+        let operations: Vec<&Operation> = operations.iter().collect();
+        let mut output = Output::new(OutputFormat::Json, false);
+        execute_renames(
+            &operations,
+            &Config::default(),
+            &mut journal,
+            &mut output,
+            &file_system,
+        );
+
+        let contents = |name: &str| file_system.read_to_string(&dir.join(name)).unwrap();
+        assert_eq!(contents("a"), "b");
+        assert_eq!(contents("b"), "a");
+        assert!(!file_system.exists(&dir.join("c")));
+        assert_eq!(contents("d"), "c");
+        assert_eq!(contents("e"), "d");
+
+        // the journal contains every single step, so undo works as well
+        journal::undo(
+            &journal_path,
+            false,
+            &mut Output::new(OutputFormat::Text, false),
+            &InMemory::new(),
+            &file_system,
+        );
+        for name in &["a", "b", "c", "d"] {
+            assert_eq!(&contents(name), name);
+        }
+        assert!(!file_system.exists(&dir.join("e")));
+    }
+
+    #[test]
+    fn test_roll_back_swap() {
+        let file_system = InMemoryFileSystem::new();
+        let dir = PathBuf::from("/music");
+        for name in &["a", "b", "cover.jpg"] {
+            file_system.add_file(&dir.join(name), name);
+        }
+        let mut journal = Journal::disabled();
+
+        // "b" can't be moved below a file after "a" has taken its name
+        let renames = [
+            Rename {
+                from: dir.join("a"),
+                to: dir.join("b"),
+            },
+            Rename {
+                from: dir.join("b"),
+                to: dir.join("cover.jpg").join("a"),
+            },
+        ];
+        let outcomes = rename_in_two_phases(&renames, &mut journal, &file_system);
+        assert!(matches!(&outcomes[0], Outcome::Failed(error) if !error.contains("back")));
+        assert_eq!(outcomes[1], outcomes[0]);
+
+        // both files are back under their original names
+        let contents = |name: &str| file_system.read_to_string(&dir.join(name)).unwrap();
+        assert_eq!(contents("a"), "a");
+        assert_eq!(contents("b"), "b");
+        assert_eq!(file_system.files().len(), 3);
     }
 }
@@ -1,17 +1,38 @@
-use mp3rename::config::Config;
-use mp3rename::rename_music_files;
+use mp3rename::config::{Command, Config};
+use mp3rename::file_system::RealFileSystem;
+use mp3rename::metadata_source::EmbeddedTags;
+use mp3rename::output::{Output, OutputFormat};
+use mp3rename::{apply, explain_name, journal, rename_music_files, tag_music_files};
 
 fn main() {
     let config = Config::new();
+    // keep machine-readable output free of anything else
+    let is_text = config.output == OutputFormat::Text;
 
-    if config.dry_run {
+    if config.dry_run && is_text {
         println!("*** Dry run mode ***");
     }
 
-    if config.verbose {
+    if config.verbose && is_text {
         println!("==============");
         println!("Configuration:");
         println!("{}", config);
     }
-    rename_music_files(&config);
+
+    match &config.command {
+        Command::Rename => rename_music_files(&config, &EmbeddedTags, &RealFileSystem),
+        Command::Apply(plan_path) => apply(plan_path, &config, &EmbeddedTags, &RealFileSystem),
+        Command::ExplainName(text) => explain_name(text, &config),
+        Command::Tag(sources) => tag_music_files(&config, sources, &EmbeddedTags, &RealFileSystem),
+        Command::Undo(journal_path) => {
+            let mut output = Output::new(config.output, config.verbose);
+            journal::undo(
+                journal_path,
+                config.dry_run,
+                &mut output,
+                &EmbeddedTags,
+                &RealFileSystem,
+            )
+        }
+    }
 }
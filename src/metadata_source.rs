@@ -0,0 +1,68 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::music_metadata;
+use crate::music_metadata::{MusicMetadata, RawTags};
+use crate::plan::Tags;
+use crate::tag_writer;
+
+/// Where the tags of the music files come from
+pub trait MetadataSource {
+    /// Returns the tags of a music file whose format is given as lowercase
+    /// extension, complete or not. Returns the reason if they are missing.
+    fn read(&self, path: &Path, extension: &str) -> Result<RawTags, String>;
+
+    /// Sets the tags given by placeholder name, leaving the others alone
+    fn write(&self, path: &Path, extension: &str, values: &Tags) -> Result<(), String>;
+}
+
+/// Reads the tags embedded in the music files, the default source
+pub struct EmbeddedTags;
+
+impl MetadataSource for EmbeddedTags {
+    fn read(&self, path: &Path, extension: &str) -> Result<RawTags, String> {
+        music_metadata::read_tags(path, extension)
+    }
+
+    fn write(&self, path: &Path, extension: &str, values: &Tags) -> Result<(), String> {
+        tag_writer::write(path, extension, values)
+    }
+}
+
+/// Returns tags kept in memory, e. g. to test the renaming logic without
+/// real music files
+#[derive(Default)]
+pub struct InMemory {
+    tags: RefCell<HashMap<PathBuf, RawTags>>,
+}
+
+impl InMemory {
+    pub fn new() -> InMemory {
+        InMemory::default()
+    }
+
+    pub fn insert(&mut self, path: &Path, music_metadata: MusicMetadata) {
+        self.insert_tags(path, music_metadata.into());
+    }
+
+    /// Inserts tags that may be incomplete
+    pub fn insert_tags(&mut self, path: &Path, tags: RawTags) {
+        self.tags.borrow_mut().insert(path.to_path_buf(), tags);
+    }
+}
+
+impl MetadataSource for InMemory {
+    fn read(&self, path: &Path, _extension: &str) -> Result<RawTags, String> {
+        self.tags
+            .borrow()
+            .get(path)
+            .cloned()
+            .ok_or_else(|| "No tags found".to_string())
+    }
+
+    fn write(&self, path: &Path, _extension: &str, values: &Tags) -> Result<(), String> {
+        let mut tags = self.tags.borrow_mut();
+        tag_writer::apply(tags.entry(path.to_path_buf()).or_default(), values)
+    }
+}
@@ -2,68 +2,363 @@ use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::fmt;
 use std::fmt::Formatter;
-use std::fs;
+use std::path::PathBuf;
 
 use crate::config::Config;
-use crate::music_metadata::MusicMetadata;
+use crate::fallback::Fallbacks;
+use crate::length::ShortenStrategy;
+use crate::metadata_source::MetadataSource;
+use crate::music_metadata::{MusicMetadata, RawTags};
+use crate::normalize;
+use crate::plan::{DirectoryPlan, Operation, Tags};
+use crate::shortening;
+use crate::tag_writer;
+use crate::template::Value;
+use crate::util;
+
+/// The file name template reproducing the classic naming scheme
+pub const DEFAULT_FILE_TEMPLATE: &str = "<{disc} - >{track} <{artist} - >{title}";
+
+/// The directory name template reproducing the classic naming scheme
+pub const DEFAULT_DIR_TEMPLATE: &str = "{album}";
+
+/// The template for the directories below the library root when organizing files,
+/// with "/" separating the directory levels
+pub const DEFAULT_ORGANIZE_TEMPLATE: &str = "{artist}/<{year} - >{album}";
+
+/// The artist in directory names of compilations
+pub const DEFAULT_VARIOUS_ARTISTS: &str = "Various Artists";
+
+/// The placeholders available in file and directory name templates
+pub const PLACEHOLDERS: &[&str] = &[
+    "album",
+    "album_artist",
+    "artist",
+    "composer",
+    "conductor",
+    "date",
+    "disc",
+    "format",
+    "genre",
+    "original_year",
+    "title",
+    "total_discs",
+    "total_tracks",
+    "track",
+    "year",
+];
 
 pub struct MusicFile {
-    pub dir_entry: fs::DirEntry,
+    pub path: PathBuf,
     pub music_metadata: Option<MusicMetadata>,
+    /// The lowercase extension matching the file's format, if it is known.
+    /// It differs from the actual one for files recognized by their content.
+    pub extension: Option<String>,
 }
 
 impl MusicFile {
-    pub fn new(dir_entry: fs::DirEntry) -> MusicFile {
-        let music_metadata = MusicMetadata::new(&dir_entry);
+    /// Reads the music file's tags according to its format given as
+    /// extension. Returns the reason if they are missing or incomplete.
+    /// The tags from `source` are combined with the values taken from the
+    /// path pattern and normalized. Missing tags are guessed according to the
+    /// fallbacks. Guesses and the tags to write back are added to the
+    /// directory plan. `position` is the place of the file in its directory,
+    /// counting from 1.
+    pub fn new(
+        path: PathBuf,
+        extension: String,
+        position: usize,
+        config: &Config,
+        source: &dyn MetadataSource,
+        directory_plan: &mut DirectoryPlan,
+    ) -> Result<MusicFile, String> {
+        let may_lack_tags =
+            config.path_pattern.is_some() || config.fallbacks != Fallbacks::default();
+        let found_tags = match source.read(&path, &extension) {
+            Ok(tags) => tags,
+            // a file without any tags may still be named after its path
+            Err(_) if may_lack_tags => RawTags::default(),
+            Err(reason) => return Err(reason),
+        };
+        let file_name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        let mut tags = found_tags.clone();
+        // the values written into the file
+        let mut written = found_tags.clone();
+        if let Some(pattern) = &config.path_pattern {
+            match pattern.extract(&path) {
+                Some(mut path_tags) if config.override_tags => {
+                    path_tags.merge(tags);
+                    tags = path_tags;
+                }
+                Some(path_tags) => tags.merge(path_tags),
+                None => directory_plan
+                    .notes
+                    .push(format!("\"{}\" doesn't match the path pattern", file_name)),
+            }
+            if config.write_tags {
+                written = tags.clone();
+            }
+        }
+        normalize::normalize_tags(&mut tags, config);
+        if config.write_normalized {
+            normalize::normalize_tags(&mut written, config);
+        }
+        let (before, after) = tag_writer::changes(&found_tags, &written);
+        if !after.is_empty() {
+            directory_plan.operations.push(Operation::WriteTags {
+                path: path.clone(),
+                extension: extension.clone(),
+                before,
+                after,
+            });
+        }
+        for guess in config.fallbacks.apply(&mut tags, &path, position) {
+            directory_plan
+                .guesses
+                .push(format!("Guessed {} for \"{}\"", guess, file_name));
+        }
+        let music_metadata = MusicMetadata::from_tags(tags)?;
+
+        Ok(MusicFile {
+            path,
+            music_metadata: Some(music_metadata),
+            extension: Some(extension),
+        })
+    }
 
-        MusicFile {
-            dir_entry,
-            music_metadata,
+    /// Returns the lowercase extension (without the dot) the file should have
+    pub fn target_extension(self: &MusicFile) -> String {
+        match &self.extension {
+            Some(extension) => extension.clone(),
+            None => self
+                .path
+                .extension()
+                .map(|ext| ext.to_string_lossy().to_lowercase())
+                .unwrap_or_default(),
         }
     }
 
+    /// Returns the new file name built from the file template, or the reason
+    /// why there is none
     pub fn canonical_name(
         self: &MusicFile,
         config: &Config,
         is_same_artist_for_whole_album: bool,
         number_of_digits_for_disc_number: usize,
         number_of_music_files_in_this_disk: usize,
-    ) -> Option<String> {
-        if let Some(metadata) = &self.music_metadata {
-            let disk_number = match metadata.disk_number {
-                None => String::new(),
-                Some(num) => format!(
-                    "{:0width$} - ",
-                    num,
-                    width = number_of_digits_for_disc_number
-                ),
-            };
-
-            // number of digits to zero-pad the track number
-            let num_digits = number_of_music_files_in_this_disk.to_string().len();
-            let track_number = format!("{:0width$}", metadata.track_number, width = num_digits);
-
-            let artist =
-                if (config.remove_artist && is_same_artist_for_whole_album) || config.omit_artist {
-                    String::new()
-                } else {
-                    format!(" {} -", &metadata.artist)
+    ) -> Result<String, String> {
+        self.canonical_name_within(
+            config,
+            is_same_artist_for_whole_album,
+            number_of_digits_for_disc_number,
+            number_of_music_files_in_this_disk,
+            config.name_limit(),
+        )
+    }
+
+    /// Like `canonical_name`, but with the smart shortening strategy fitting
+    /// the name in `limit` instead of the configured length, if given
+    pub fn canonical_name_within(
+        self: &MusicFile,
+        config: &Config,
+        is_same_artist_for_whole_album: bool,
+        number_of_digits_for_disc_number: usize,
+        number_of_music_files_in_this_disk: usize,
+        limit: Option<usize>,
+    ) -> Result<String, String> {
+        let missing = || "Couldn't retrieve canonical name".to_string();
+        let values = self
+            .template_values(
+                config,
+                is_same_artist_for_whole_album,
+                number_of_digits_for_disc_number,
+                number_of_music_files_in_this_disk,
+            )
+            .ok_or_else(missing)?;
+        let extension = match self.target_extension().as_str() {
+            "" => String::new(),
+            ext => format!(".{}", ext),
+        };
+
+        let name = match limit {
+            Some(limit) if config.shorten_strategy == ShortenStrategy::Smart => {
+                let unit = config.length_unit;
+                // measured like the sanitized name the file is finally renamed to
+                let fits = |name: &str| {
+                    unit.measure(&util::sanitize_file_or_directory_name(name, config))
+                        + unit.measure(&extension)
+                        <= limit
                 };
+                if config.file_template.render(&values).is_none() {
+                    return Err(missing());
+                }
+                // numbers are never cut, so the name might not fit at all
+                shortening::shorten(&config.file_template, values, fits, &config.ellipsis)
+                    .ok_or_else(|| {
+                        format!(
+                            "No name built from the file template fits in {} {}",
+                            limit, unit
+                        )
+                    })?
+            }
+            _ => config.file_template.render(&values).ok_or_else(missing)?,
+        };
 
-            let extension = match self.dir_entry.path().extension() {
-                None => String::new(),
-                Some(ext) => format!(".{}", ext.to_string_lossy().to_lowercase()),
-            };
+        Ok(format!("{}{}", name, extension))
+    }
 
-            let result = format!(
-                "{}{}{} {}{}",
-                disk_number, track_number, artist, metadata.title, extension
-            );
+    /// Returns the path relative to the library root this file is moved to
+    /// when organizing files
+    pub fn organized_directory(self: &MusicFile, config: &Config) -> Option<PathBuf> {
+        let mut values = self.directory_values(config)?;
 
-            return Some(result);
+        // values must not introduce directory levels of their own, e. g. for "AC/DC"
+        for value in values.values_mut() {
+            if let Value::Text(text) = value {
+                *text = util::sanitize_file_or_directory_name(text, config);
+            }
+        }
+
+        let mut path = PathBuf::new();
+        for component in config.organize_template.render(&values)?.split('/') {
+            let mut name = util::sanitize_file_or_directory_name(component, config);
+            if config.shorten_names {
+                name = util::shorten_names(&PathBuf::from(&name), &name, config);
+            }
+            name = util::fit_target_fs(&PathBuf::from(&name), &name, config);
+            if !name.is_empty() {
+                path.push(name);
+            }
         }
 
-        None
+        Some(path)
+    }
+
+    /// Returns the values for the placeholders of the file name template.
+    /// Fields which are not set (or left out intentionally) are missing.
+    fn template_values(
+        self: &MusicFile,
+        config: &Config,
+        is_same_artist_for_whole_album: bool,
+        number_of_digits_for_disc_number: usize,
+        number_of_music_files_in_this_disk: usize,
+    ) -> Option<HashMap<&'static str, Value>> {
+        let metadata = self.music_metadata.as_ref()?;
+        let mut values = self.tag_values()?;
+
+        // compilations keep the artist of each track unless it is omitted explicitly
+        if config.omit_artist
+            || (!metadata.compilation && config.remove_artist && is_same_artist_for_whole_album)
+        {
+            values.remove("artist");
+        }
+        if let Some(Value::Number { width, .. }) = values.get_mut("disc") {
+            *width = number_of_digits_for_disc_number;
+        }
+        // number of digits to zero-pad the track number
+        if let Some(Value::Number { width, .. }) = values.get_mut("track") {
+            *width = number_of_music_files_in_this_disk.to_string().len();
+        }
+
+        Some(values)
+    }
+
+    /// Returns the tag values of this file as plain text
+    pub fn tags(self: &MusicFile) -> Tags {
+        self.tag_values()
+            .map(|values| to_tags(&values))
+            .unwrap_or_default()
+    }
+
+    /// Returns the values for the placeholders of directory names. The artist
+    /// is the album artist if there is one, or the "Various Artists" label
+    /// for compilations.
+    fn directory_values(self: &MusicFile, config: &Config) -> Option<HashMap<&'static str, Value>> {
+        let metadata = self.music_metadata.as_ref()?;
+        let mut values = self.tag_values()?;
+
+        if metadata.compilation {
+            values.insert("artist", Value::Text(config.various_artists.clone()));
+        } else if let Some(album_artist) = &metadata.album_artist {
+            values.insert("artist", Value::Text(album_artist.clone()));
+        }
+
+        Some(values)
+    }
+
+    /// Returns the plain values of all fields known for this file
+    fn tag_values(self: &MusicFile) -> Option<HashMap<&'static str, Value>> {
+        let metadata = self.music_metadata.as_ref()?;
+        let mut values = HashMap::new();
+
+        values.insert("album", Value::Text(metadata.album.clone()));
+        if let Some(album_artist) = &metadata.album_artist {
+            values.insert("album_artist", Value::Text(album_artist.clone()));
+        }
+        values.insert("artist", Value::Text(metadata.artist.clone()));
+        if let Some(composer) = &metadata.composer {
+            values.insert("composer", Value::Text(composer.clone()));
+        }
+        if let Some(conductor) = &metadata.conductor {
+            values.insert("conductor", Value::Text(conductor.clone()));
+        }
+        if let Some(date) = &metadata.date {
+            values.insert("date", Value::Text(date.clone()));
+        }
+        if let Some(disk_number) = metadata.disk_number {
+            values.insert(
+                "disc",
+                Value::Number {
+                    value: u32::from(disk_number),
+                    width: 0,
+                },
+            );
+        }
+        let extension = self.target_extension();
+        if !extension.is_empty() {
+            values.insert("format", Value::Text(extension.to_uppercase()));
+        }
+        if let Some(genre) = &metadata.genre {
+            values.insert("genre", Value::Text(genre.clone()));
+        }
+        if let Some(original_year) = metadata.original_year {
+            values.insert("original_year", Value::Text(original_year.to_string()));
+        }
+        values.insert("title", Value::Text(metadata.title.clone()));
+        if let Some(total_discs) = metadata.total_discs {
+            values.insert(
+                "total_discs",
+                Value::Number {
+                    value: u32::from(total_discs),
+                    width: 0,
+                },
+            );
+        }
+        if let Some(total_tracks) = metadata.total_tracks {
+            values.insert(
+                "total_tracks",
+                Value::Number {
+                    value: u32::from(total_tracks),
+                    width: 0,
+                },
+            );
+        }
+        values.insert(
+            "track",
+            Value::Number {
+                value: u32::from(metadata.track_number),
+                width: 0,
+            },
+        );
+        if let Some(year) = metadata.year {
+            values.insert("year", Value::Text(year.to_string()));
+        }
+
+        Some(values)
     }
 
     pub fn sort_func(left: &MusicFile, right: &MusicFile) -> Ordering {
@@ -71,17 +366,13 @@ impl MusicFile {
     }
 
     pub fn sort_by_disk_number(left: &&Option<u16>, right: &&Option<u16>) -> Ordering {
-        MusicMetadata::sort_by_disk_number_func(*left, *right)
+        MusicMetadata::sort_by_disk_number_func(left, right)
     }
 }
 
 impl fmt::Display for MusicFile {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        writeln!(
-            f,
-            "File Name:    {}",
-            self.dir_entry.path().to_string_lossy()
-        )?;
+        writeln!(f, "File Name:    {}", self.path.to_string_lossy())?;
         match &self.music_metadata {
             None => writeln!(f, "No tags found.")?,
             Some(tags) => writeln!(f, "{}", tags)?,
@@ -91,14 +382,31 @@ impl fmt::Display for MusicFile {
     }
 }
 
-/// Has the whole directory the same artist for every music file?
+/// Has the whole directory the same artist for every music file? A common
+/// album artist counts as well, unless the files are part of a compilation.
 pub fn same_artists(music_files: &[MusicFile]) -> bool {
-    let artists: Vec<&String> = music_files
+    let metadata: Vec<&MusicMetadata> = music_files
         .iter()
         .filter_map(|m| m.music_metadata.as_ref())
-        .map(|m| &m.artist)
         .collect();
 
+    // compilations keep the artist of each track
+    if metadata.iter().any(|m| m.compilation) {
+        return false;
+    }
+    let album_artists: Vec<Option<&String>> =
+        metadata.iter().map(|m| m.album_artist.as_ref()).collect();
+    if let Some(Some(first_album_artist)) = album_artists.first() {
+        if album_artists
+            .iter()
+            .all(|album_artist| *album_artist == Some(*first_album_artist))
+        {
+            return true;
+        }
+    }
+
+    let artists: Vec<&String> = metadata.iter().map(|m| &m.artist).collect();
+
     if !artists.is_empty() {
         let first_artist = artists[0];
         for artist in artists {
@@ -109,7 +417,7 @@ pub fn same_artists(music_files: &[MusicFile]) -> bool {
         return true;
     }
 
-    // an error for missing tags has already been reported in MusicMetadata::new()
+    // music files without tags have already been skipped in MusicFile::new()
     false
 }
 
@@ -134,14 +442,39 @@ pub fn same_album_title(music_files: &[MusicFile]) -> Option<String> {
     None
 }
 
+/// Returns the values of all fields that are the same for every music file in
+/// a directory, as used for directory names. Fields differing between the
+/// files are missing.
+pub fn same_values(music_files: &[MusicFile], config: &Config) -> HashMap<&'static str, Value> {
+    let mut all_values = music_files
+        .iter()
+        .filter_map(|music_file| music_file.directory_values(config));
+
+    let mut values = match all_values.next() {
+        None => return HashMap::new(),
+        Some(values) => values,
+    };
+    for other_values in all_values {
+        values.retain(|name, value| other_values.get(name) == Some(value));
+    }
+
+    values
+}
+
+/// Converts placeholder values to plain text
+pub fn to_tags(values: &HashMap<&str, Value>) -> Tags {
+    values
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect()
+}
+
 pub fn largest_disc_number(music_files: &HashMap<Option<u16>, Vec<MusicFile>>) -> Option<u16> {
     let mut largest: u16 = 0;
 
-    for disk_number in music_files.keys() {
-        if let Some(disk_number) = disk_number {
-            if *disk_number > largest {
-                largest = *disk_number;
-            }
+    for disk_number in music_files.keys().flatten() {
+        if *disk_number > largest {
+            largest = *disk_number;
         }
     }
 
@@ -155,14 +488,14 @@ pub fn largest_disc_number(music_files: &HashMap<Option<u16>, Vec<MusicFile>>) -
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::template::Template;
 
     const DEFAULT_ALBUM: &str = "The Foos are Back";
     const DEFAULT_ARTIST: &str = "The Foos";
     const DEFAULT_TITLE: &str = "Foo de Foo";
 
-    fn get_dir_entry() -> fs::DirEntry {
-        let mut readdir = fs::read_dir("testfiles").unwrap();
-        readdir.next().unwrap().unwrap()
+    fn get_path() -> PathBuf {
+        PathBuf::from("testfiles/foo.mp3")
     }
 
     fn get_music_metadata() -> MusicMetadata {
@@ -172,6 +505,7 @@ mod tests {
             disk_number: None,
             title: DEFAULT_TITLE.to_string(),
             track_number: 1,
+            ..MusicMetadata::default()
         }
     }
 
@@ -179,8 +513,9 @@ mod tests {
     fn test_canonical_name_for_default_config() {
         let config = Config::default();
         let music_file = MusicFile {
-            dir_entry: get_dir_entry(),
+            path: get_path(),
             music_metadata: Some(get_music_metadata()),
+            extension: None,
         };
         let same_artist = false;
 
@@ -194,15 +529,16 @@ mod tests {
                 number_of_digits_for_disc_number,
                 number_of_music_files_in_this_directory
             ),
-            Some(format!("1 {} - {}.mp3", DEFAULT_ARTIST, DEFAULT_TITLE))
+            Ok(format!("1 {} - {}.mp3", DEFAULT_ARTIST, DEFAULT_TITLE))
         );
 
         let music_file = MusicFile {
-            dir_entry: get_dir_entry(),
+            path: get_path(),
             music_metadata: Some(MusicMetadata {
                 track_number: 9,
                 ..get_music_metadata()
             }),
+            extension: None,
         };
         assert_eq!(
             music_file.canonical_name(
@@ -211,13 +547,14 @@ mod tests {
                 number_of_digits_for_disc_number,
                 number_of_music_files_in_this_directory
             ),
-            Some(format!("9 {} - {}.mp3", DEFAULT_ARTIST, DEFAULT_TITLE))
+            Ok(format!("9 {} - {}.mp3", DEFAULT_ARTIST, DEFAULT_TITLE))
         );
 
         // two digits
         let music_file = MusicFile {
-            dir_entry: get_dir_entry(),
+            path: get_path(),
             music_metadata: Some(get_music_metadata()),
+            extension: None,
         };
         let number_of_music_files_in_this_directory = 10;
         assert_eq!(
@@ -227,14 +564,15 @@ mod tests {
                 number_of_digits_for_disc_number,
                 number_of_music_files_in_this_directory
             ),
-            Some(format!("01 {} - {}.mp3", DEFAULT_ARTIST, DEFAULT_TITLE))
+            Ok(format!("01 {} - {}.mp3", DEFAULT_ARTIST, DEFAULT_TITLE))
         );
         let music_file = MusicFile {
-            dir_entry: get_dir_entry(),
+            path: get_path(),
             music_metadata: Some(MusicMetadata {
                 track_number: 9,
                 ..get_music_metadata()
             }),
+            extension: None,
         };
         assert_eq!(
             music_file.canonical_name(
@@ -243,14 +581,15 @@ mod tests {
                 number_of_digits_for_disc_number,
                 number_of_music_files_in_this_directory
             ),
-            Some(format!("09 {} - {}.mp3", DEFAULT_ARTIST, DEFAULT_TITLE))
+            Ok(format!("09 {} - {}.mp3", DEFAULT_ARTIST, DEFAULT_TITLE))
         );
         let music_file = MusicFile {
-            dir_entry: get_dir_entry(),
+            path: get_path(),
             music_metadata: Some(MusicMetadata {
                 track_number: 10,
                 ..get_music_metadata()
             }),
+            extension: None,
         };
         assert_eq!(
             music_file.canonical_name(
@@ -259,13 +598,14 @@ mod tests {
                 number_of_digits_for_disc_number,
                 number_of_music_files_in_this_directory
             ),
-            Some(format!("10 {} - {}.mp3", DEFAULT_ARTIST, DEFAULT_TITLE))
+            Ok(format!("10 {} - {}.mp3", DEFAULT_ARTIST, DEFAULT_TITLE))
         );
 
         // three digits
         let music_file = MusicFile {
-            dir_entry: get_dir_entry(),
+            path: get_path(),
             music_metadata: Some(get_music_metadata()),
+            extension: None,
         };
         let number_of_music_files_in_this_directory = 100;
         assert_eq!(
@@ -275,15 +615,16 @@ mod tests {
                 number_of_digits_for_disc_number,
                 number_of_music_files_in_this_directory
             ),
-            Some(format!("001 {} - {}.mp3", DEFAULT_ARTIST, DEFAULT_TITLE))
+            Ok(format!("001 {} - {}.mp3", DEFAULT_ARTIST, DEFAULT_TITLE))
         );
 
         let music_file = MusicFile {
-            dir_entry: get_dir_entry(),
+            path: get_path(),
             music_metadata: Some(MusicMetadata {
                 track_number: 10,
                 ..get_music_metadata()
             }),
+            extension: None,
         };
         assert_eq!(
             music_file.canonical_name(
@@ -292,14 +633,15 @@ mod tests {
                 number_of_digits_for_disc_number,
                 number_of_music_files_in_this_directory
             ),
-            Some(format!("010 {} - {}.mp3", DEFAULT_ARTIST, DEFAULT_TITLE))
+            Ok(format!("010 {} - {}.mp3", DEFAULT_ARTIST, DEFAULT_TITLE))
         );
         let music_file = MusicFile {
-            dir_entry: get_dir_entry(),
+            path: get_path(),
             music_metadata: Some(MusicMetadata {
                 track_number: 99,
                 ..get_music_metadata()
             }),
+            extension: None,
         };
         assert_eq!(
             music_file.canonical_name(
@@ -308,14 +650,15 @@ mod tests {
                 number_of_digits_for_disc_number,
                 number_of_music_files_in_this_directory
             ),
-            Some(format!("099 {} - {}.mp3", DEFAULT_ARTIST, DEFAULT_TITLE))
+            Ok(format!("099 {} - {}.mp3", DEFAULT_ARTIST, DEFAULT_TITLE))
         );
         let music_file = MusicFile {
-            dir_entry: get_dir_entry(),
+            path: get_path(),
             music_metadata: Some(MusicMetadata {
                 track_number: 100,
                 ..get_music_metadata()
             }),
+            extension: None,
         };
         assert_eq!(
             music_file.canonical_name(
@@ -324,13 +667,14 @@ mod tests {
                 number_of_digits_for_disc_number,
                 number_of_music_files_in_this_directory
             ),
-            Some(format!("100 {} - {}.mp3", DEFAULT_ARTIST, DEFAULT_TITLE))
+            Ok(format!("100 {} - {}.mp3", DEFAULT_ARTIST, DEFAULT_TITLE))
         );
 
         // four digits
         let music_file = MusicFile {
-            dir_entry: get_dir_entry(),
+            path: get_path(),
             music_metadata: Some(get_music_metadata()),
+            extension: None,
         };
         let number_of_music_files_in_this_directory = 1000;
         assert_eq!(
@@ -340,15 +684,16 @@ mod tests {
                 number_of_digits_for_disc_number,
                 number_of_music_files_in_this_directory
             ),
-            Some(format!("0001 {} - {}.mp3", DEFAULT_ARTIST, DEFAULT_TITLE))
+            Ok(format!("0001 {} - {}.mp3", DEFAULT_ARTIST, DEFAULT_TITLE))
         );
 
         let music_file = MusicFile {
-            dir_entry: get_dir_entry(),
+            path: get_path(),
             music_metadata: Some(MusicMetadata {
                 track_number: 10,
                 ..get_music_metadata()
             }),
+            extension: None,
         };
         assert_eq!(
             music_file.canonical_name(
@@ -357,14 +702,15 @@ mod tests {
                 number_of_digits_for_disc_number,
                 number_of_music_files_in_this_directory
             ),
-            Some(format!("0010 {} - {}.mp3", DEFAULT_ARTIST, DEFAULT_TITLE))
+            Ok(format!("0010 {} - {}.mp3", DEFAULT_ARTIST, DEFAULT_TITLE))
         );
         let music_file = MusicFile {
-            dir_entry: get_dir_entry(),
+            path: get_path(),
             music_metadata: Some(MusicMetadata {
                 track_number: 99,
                 ..get_music_metadata()
             }),
+            extension: None,
         };
         assert_eq!(
             music_file.canonical_name(
@@ -373,14 +719,15 @@ mod tests {
                 number_of_digits_for_disc_number,
                 number_of_music_files_in_this_directory
             ),
-            Some(format!("0099 {} - {}.mp3", DEFAULT_ARTIST, DEFAULT_TITLE))
+            Ok(format!("0099 {} - {}.mp3", DEFAULT_ARTIST, DEFAULT_TITLE))
         );
         let music_file = MusicFile {
-            dir_entry: get_dir_entry(),
+            path: get_path(),
             music_metadata: Some(MusicMetadata {
                 track_number: 100,
                 ..get_music_metadata()
             }),
+            extension: None,
         };
         assert_eq!(
             music_file.canonical_name(
@@ -389,14 +736,15 @@ mod tests {
                 number_of_digits_for_disc_number,
                 number_of_music_files_in_this_directory
             ),
-            Some(format!("0100 {} - {}.mp3", DEFAULT_ARTIST, DEFAULT_TITLE))
+            Ok(format!("0100 {} - {}.mp3", DEFAULT_ARTIST, DEFAULT_TITLE))
         );
         let music_file = MusicFile {
-            dir_entry: get_dir_entry(),
+            path: get_path(),
             music_metadata: Some(MusicMetadata {
                 track_number: 999,
                 ..get_music_metadata()
             }),
+            extension: None,
         };
         assert_eq!(
             music_file.canonical_name(
@@ -405,14 +753,15 @@ mod tests {
                 number_of_digits_for_disc_number,
                 number_of_music_files_in_this_directory,
             ),
-            Some(format!("0999 {} - {}.mp3", DEFAULT_ARTIST, DEFAULT_TITLE))
+            Ok(format!("0999 {} - {}.mp3", DEFAULT_ARTIST, DEFAULT_TITLE))
         );
         let music_file = MusicFile {
-            dir_entry: get_dir_entry(),
+            path: get_path(),
             music_metadata: Some(MusicMetadata {
                 track_number: 1000,
                 ..get_music_metadata()
             }),
+            extension: None,
         };
         assert_eq!(
             music_file.canonical_name(
@@ -421,7 +770,7 @@ mod tests {
                 number_of_digits_for_disc_number,
                 number_of_music_files_in_this_directory
             ),
-            Some(format!("1000 {} - {}.mp3", DEFAULT_ARTIST, DEFAULT_TITLE))
+            Ok(format!("1000 {} - {}.mp3", DEFAULT_ARTIST, DEFAULT_TITLE))
         );
     }
 
@@ -432,8 +781,9 @@ mod tests {
             ..Config::default()
         };
         let music_file = MusicFile {
-            dir_entry: get_dir_entry(),
+            path: get_path(),
             music_metadata: Some(get_music_metadata()),
+            extension: None,
         };
         let same_artist = true;
 
@@ -447,15 +797,16 @@ mod tests {
                 number_of_digits_for_disc_number,
                 number_of_music_files_in_this_directory
             ),
-            Some(format!("1 {}.mp3", DEFAULT_TITLE))
+            Ok(format!("1 {}.mp3", DEFAULT_TITLE))
         );
 
         let music_file = MusicFile {
-            dir_entry: get_dir_entry(),
+            path: get_path(),
             music_metadata: Some(MusicMetadata {
                 track_number: 9,
                 ..get_music_metadata()
             }),
+            extension: None,
         };
         assert_eq!(
             music_file.canonical_name(
@@ -464,13 +815,14 @@ mod tests {
                 number_of_digits_for_disc_number,
                 number_of_music_files_in_this_directory
             ),
-            Some(format!("9 {}.mp3", DEFAULT_TITLE))
+            Ok(format!("9 {}.mp3", DEFAULT_TITLE))
         );
 
         // two digits
         let music_file = MusicFile {
-            dir_entry: get_dir_entry(),
+            path: get_path(),
             music_metadata: Some(get_music_metadata()),
+            extension: None,
         };
         let number_of_music_files_in_this_directory = 10;
         assert_eq!(
@@ -480,14 +832,15 @@ mod tests {
                 number_of_digits_for_disc_number,
                 number_of_music_files_in_this_directory
             ),
-            Some(format!("01 {}.mp3", DEFAULT_TITLE))
+            Ok(format!("01 {}.mp3", DEFAULT_TITLE))
         );
         let music_file = MusicFile {
-            dir_entry: get_dir_entry(),
+            path: get_path(),
             music_metadata: Some(MusicMetadata {
                 track_number: 9,
                 ..get_music_metadata()
             }),
+            extension: None,
         };
         assert_eq!(
             music_file.canonical_name(
@@ -496,14 +849,15 @@ mod tests {
                 number_of_digits_for_disc_number,
                 number_of_music_files_in_this_directory
             ),
-            Some(format!("09 {}.mp3", DEFAULT_TITLE))
+            Ok(format!("09 {}.mp3", DEFAULT_TITLE))
         );
         let music_file = MusicFile {
-            dir_entry: get_dir_entry(),
+            path: get_path(),
             music_metadata: Some(MusicMetadata {
                 track_number: 10,
                 ..get_music_metadata()
             }),
+            extension: None,
         };
         assert_eq!(
             music_file.canonical_name(
@@ -512,13 +866,14 @@ mod tests {
                 number_of_digits_for_disc_number,
                 number_of_music_files_in_this_directory
             ),
-            Some(format!("10 {}.mp3", DEFAULT_TITLE))
+            Ok(format!("10 {}.mp3", DEFAULT_TITLE))
         );
 
         // three digits
         let music_file = MusicFile {
-            dir_entry: get_dir_entry(),
+            path: get_path(),
             music_metadata: Some(get_music_metadata()),
+            extension: None,
         };
         let number_of_music_files_in_this_directory = 100;
         assert_eq!(
@@ -528,15 +883,16 @@ mod tests {
                 number_of_digits_for_disc_number,
                 number_of_music_files_in_this_directory
             ),
-            Some(format!("001 {}.mp3", DEFAULT_TITLE))
+            Ok(format!("001 {}.mp3", DEFAULT_TITLE))
         );
 
         let music_file = MusicFile {
-            dir_entry: get_dir_entry(),
+            path: get_path(),
             music_metadata: Some(MusicMetadata {
                 track_number: 10,
                 ..get_music_metadata()
             }),
+            extension: None,
         };
         assert_eq!(
             music_file.canonical_name(
@@ -545,14 +901,15 @@ mod tests {
                 number_of_digits_for_disc_number,
                 number_of_music_files_in_this_directory
             ),
-            Some(format!("010 {}.mp3", DEFAULT_TITLE))
+            Ok(format!("010 {}.mp3", DEFAULT_TITLE))
         );
         let music_file = MusicFile {
-            dir_entry: get_dir_entry(),
+            path: get_path(),
             music_metadata: Some(MusicMetadata {
                 track_number: 99,
                 ..get_music_metadata()
             }),
+            extension: None,
         };
         assert_eq!(
             music_file.canonical_name(
@@ -561,14 +918,15 @@ mod tests {
                 number_of_digits_for_disc_number,
                 number_of_music_files_in_this_directory
             ),
-            Some(format!("099 {}.mp3", DEFAULT_TITLE))
+            Ok(format!("099 {}.mp3", DEFAULT_TITLE))
         );
         let music_file = MusicFile {
-            dir_entry: get_dir_entry(),
+            path: get_path(),
             music_metadata: Some(MusicMetadata {
                 track_number: 100,
                 ..get_music_metadata()
             }),
+            extension: None,
         };
         assert_eq!(
             music_file.canonical_name(
@@ -577,13 +935,14 @@ mod tests {
                 number_of_digits_for_disc_number,
                 number_of_music_files_in_this_directory
             ),
-            Some(format!("100 {}.mp3", DEFAULT_TITLE))
+            Ok(format!("100 {}.mp3", DEFAULT_TITLE))
         );
 
         // four digits
         let music_file = MusicFile {
-            dir_entry: get_dir_entry(),
+            path: get_path(),
             music_metadata: Some(get_music_metadata()),
+            extension: None,
         };
         let number_of_music_files_in_this_directory = 1000;
         assert_eq!(
@@ -593,15 +952,16 @@ mod tests {
                 number_of_digits_for_disc_number,
                 number_of_music_files_in_this_directory
             ),
-            Some(format!("0001 {}.mp3", DEFAULT_TITLE))
+            Ok(format!("0001 {}.mp3", DEFAULT_TITLE))
         );
 
         let music_file = MusicFile {
-            dir_entry: get_dir_entry(),
+            path: get_path(),
             music_metadata: Some(MusicMetadata {
                 track_number: 10,
                 ..get_music_metadata()
             }),
+            extension: None,
         };
         assert_eq!(
             music_file.canonical_name(
@@ -610,14 +970,15 @@ mod tests {
                 number_of_digits_for_disc_number,
                 number_of_music_files_in_this_directory
             ),
-            Some(format!("0010 {}.mp3", DEFAULT_TITLE))
+            Ok(format!("0010 {}.mp3", DEFAULT_TITLE))
         );
         let music_file = MusicFile {
-            dir_entry: get_dir_entry(),
+            path: get_path(),
             music_metadata: Some(MusicMetadata {
                 track_number: 99,
                 ..get_music_metadata()
             }),
+            extension: None,
         };
         assert_eq!(
             music_file.canonical_name(
@@ -626,14 +987,15 @@ mod tests {
                 number_of_digits_for_disc_number,
                 number_of_music_files_in_this_directory
             ),
-            Some(format!("0099 {}.mp3", DEFAULT_TITLE))
+            Ok(format!("0099 {}.mp3", DEFAULT_TITLE))
         );
         let music_file = MusicFile {
-            dir_entry: get_dir_entry(),
+            path: get_path(),
             music_metadata: Some(MusicMetadata {
                 track_number: 100,
                 ..get_music_metadata()
             }),
+            extension: None,
         };
         assert_eq!(
             music_file.canonical_name(
@@ -642,14 +1004,15 @@ mod tests {
                 number_of_digits_for_disc_number,
                 number_of_music_files_in_this_directory
             ),
-            Some(format!("0100 {}.mp3", DEFAULT_TITLE))
+            Ok(format!("0100 {}.mp3", DEFAULT_TITLE))
         );
         let music_file = MusicFile {
-            dir_entry: get_dir_entry(),
+            path: get_path(),
             music_metadata: Some(MusicMetadata {
                 track_number: 999,
                 ..get_music_metadata()
             }),
+            extension: None,
         };
         assert_eq!(
             music_file.canonical_name(
@@ -658,14 +1021,15 @@ mod tests {
                 number_of_digits_for_disc_number,
                 number_of_music_files_in_this_directory,
             ),
-            Some(format!("0999 {}.mp3", DEFAULT_TITLE))
+            Ok(format!("0999 {}.mp3", DEFAULT_TITLE))
         );
         let music_file = MusicFile {
-            dir_entry: get_dir_entry(),
+            path: get_path(),
             music_metadata: Some(MusicMetadata {
                 track_number: 1000,
                 ..get_music_metadata()
             }),
+            extension: None,
         };
         assert_eq!(
             music_file.canonical_name(
@@ -674,7 +1038,7 @@ mod tests {
                 number_of_digits_for_disc_number,
                 number_of_music_files_in_this_directory
             ),
-            Some(format!("1000 {}.mp3", DEFAULT_TITLE))
+            Ok(format!("1000 {}.mp3", DEFAULT_TITLE))
         );
     }
 
@@ -685,13 +1049,14 @@ mod tests {
             ..Config::default()
         };
         let music_file = MusicFile {
-            dir_entry: get_dir_entry(),
+            path: get_path(),
             music_metadata: Some(get_music_metadata()),
+            extension: None,
         };
 
         assert_eq!(
             music_file.canonical_name(&config, false, 0, 1),
-            Some(format!("1 {}.mp3", DEFAULT_TITLE))
+            Ok(format!("1 {}.mp3", DEFAULT_TITLE))
         );
 
         let config = Config {
@@ -700,7 +1065,98 @@ mod tests {
         };
         assert_eq!(
             music_file.canonical_name(&config, false, 0, 1),
-            Some(format!("1 {} - {}.mp3", DEFAULT_ARTIST, DEFAULT_TITLE))
+            Ok(format!("1 {} - {}.mp3", DEFAULT_ARTIST, DEFAULT_TITLE))
+        );
+    }
+
+    #[test]
+    fn test_same_artists_with_album_artist() {
+        let music_file = |artist: &str, album_artist: Option<&str>| MusicFile {
+            path: get_path(),
+            music_metadata: Some(MusicMetadata {
+                artist: artist.to_string(),
+                album_artist: album_artist.map(String::from),
+                ..get_music_metadata()
+            }),
+            extension: None,
+        };
+
+        assert!(same_artists(&[
+            music_file("The Foos", None),
+            music_file("The Foos", None)
+        ]));
+        assert!(same_artists(&[
+            music_file("The Foos", Some("The Foos")),
+            music_file("The Foos feat. Bar", Some("The Foos"))
+        ]));
+        assert!(!same_artists(&[
+            music_file("The Foos", Some("The Foos")),
+            music_file("The Foos feat. Bar", None)
+        ]));
+
+        let values = same_values(
+            &[
+                music_file("The Foos", Some("The Foos")),
+                music_file("The Foos feat. Bar", Some("The Foos")),
+            ],
+            &Config::default(),
+        );
+        assert_eq!(
+            values.get("artist"),
+            Some(&Value::Text("The Foos".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_compilation() {
+        let config = Config {
+            remove_artist: true,
+            various_artists: "VA".to_string(),
+            ..Config::default()
+        };
+        let music_file = |artist: &str| MusicFile {
+            path: get_path(),
+            music_metadata: Some(MusicMetadata {
+                artist: artist.to_string(),
+                album_artist: Some("Various Artists".to_string()),
+                compilation: true,
+                ..get_music_metadata()
+            }),
+            extension: None,
+        };
+        let music_files = [music_file("The Foos"), music_file("The Bars")];
+
+        // the file names keep the artist of each track ...
+        assert!(!same_artists(&music_files));
+        assert_eq!(
+            music_files[0].canonical_name(&config, true, 0, 1),
+            Ok(format!("1 The Foos - {}.mp3", DEFAULT_TITLE))
+        );
+
+        // ... while the directories use the label
+        assert_eq!(
+            same_values(&music_files, &config).get("artist"),
+            Some(&Value::Text("VA".to_string()))
+        );
+        assert_eq!(
+            music_files[1].organized_directory(&config),
+            Some(PathBuf::from("VA").join(DEFAULT_ALBUM))
+        );
+
+        // the same artist on every track is kept as well
+        assert_eq!(
+            music_files[0].canonical_name(&config, true, 0, 1),
+            music_files[0].canonical_name(&config, false, 0, 1)
+        );
+
+        // --omit-artist drops it even for compilations
+        let config = Config {
+            omit_artist: true,
+            ..config
+        };
+        assert_eq!(
+            music_files[0].canonical_name(&config, false, 0, 1),
+            Ok(format!("1 {}.mp3", DEFAULT_TITLE))
         );
     }
 
@@ -708,11 +1164,12 @@ mod tests {
     fn test_canonical_name_with_disc_numbers() {
         let config = Config::default();
         let music_file = MusicFile {
-            dir_entry: get_dir_entry(),
+            path: get_path(),
             music_metadata: Some(MusicMetadata {
                 disk_number: Some(1),
                 ..get_music_metadata()
             }),
+            extension: None,
         };
         let same_artist = false;
 
@@ -725,15 +1182,16 @@ mod tests {
                 number_of_digits_for_disc_number,
                 number_of_music_files_in_this_directory
             ),
-            Some(format!("1 - 1 {} - {}.mp3", DEFAULT_ARTIST, DEFAULT_TITLE))
+            Ok(format!("1 - 1 {} - {}.mp3", DEFAULT_ARTIST, DEFAULT_TITLE))
         );
 
         let music_file = MusicFile {
-            dir_entry: get_dir_entry(),
+            path: get_path(),
             music_metadata: Some(MusicMetadata {
                 disk_number: Some(1),
                 ..get_music_metadata()
             }),
+            extension: None,
         };
         let number_of_digits_for_disc_number = 2;
         assert_eq!(
@@ -743,7 +1201,7 @@ mod tests {
                 number_of_digits_for_disc_number,
                 number_of_music_files_in_this_directory
             ),
-            Some(format!("01 - 1 {} - {}.mp3", DEFAULT_ARTIST, DEFAULT_TITLE))
+            Ok(format!("01 - 1 {} - {}.mp3", DEFAULT_ARTIST, DEFAULT_TITLE))
         );
 
         let number_of_digits_for_disc_number = 3;
@@ -755,10 +1213,194 @@ mod tests {
                 number_of_digits_for_disc_number,
                 number_of_music_files_in_this_directory
             ),
-            Some(format!(
+            Ok(format!(
                 "001 - 001 {} - {}.mp3",
                 DEFAULT_ARTIST, DEFAULT_TITLE
             ))
         );
     }
+
+    #[test]
+    fn test_canonical_name_with_template() {
+        let config = Config {
+            file_template: Template::parse("{artist} - {album} - {track:02} - {title}").unwrap(),
+            ..Config::default()
+        };
+        let music_file = MusicFile {
+            path: get_path(),
+            music_metadata: Some(get_music_metadata()),
+            extension: None,
+        };
+        assert_eq!(
+            music_file.canonical_name(&config, false, 0, 1),
+            Ok(format!(
+                "{} - {} - 01 - {}.mp3",
+                DEFAULT_ARTIST, DEFAULT_ALBUM, DEFAULT_TITLE
+            ))
+        );
+
+        // optional sections vanish for missing fields, mandatory ones spoil the name
+        let config = Config {
+            file_template: Template::parse("<{year} - >{title}").unwrap(),
+            ..Config::default()
+        };
+        assert_eq!(
+            music_file.canonical_name(&config, false, 0, 1),
+            Ok(format!("{}.mp3", DEFAULT_TITLE))
+        );
+        let music_file = MusicFile {
+            path: get_path(),
+            music_metadata: Some(MusicMetadata {
+                year: Some(1999),
+                ..get_music_metadata()
+            }),
+            extension: None,
+        };
+        assert_eq!(
+            music_file.canonical_name(&config, false, 0, 1),
+            Ok(format!("1999 - {}.mp3", DEFAULT_TITLE))
+        );
+
+        let config = Config {
+            file_template: Template::parse("{disc} - {title}").unwrap(),
+            ..Config::default()
+        };
+        assert!(music_file.canonical_name(&config, false, 0, 1).is_err());
+    }
+
+    #[test]
+    fn test_canonical_name_with_optional_tags() {
+        let config = Config {
+            file_template: Template::parse(
+                "<[{genre}] >{track} of {total_tracks:02} {title}< ({composer}, {original_year})>",
+            )
+            .unwrap(),
+            ..Config::default()
+        };
+        let music_file = MusicFile {
+            path: get_path(),
+            music_metadata: Some(MusicMetadata {
+                composer: Some("Bach".to_string()),
+                genre: Some("Baroque".to_string()),
+                original_year: Some(1721),
+                total_tracks: Some(6),
+                ..get_music_metadata()
+            }),
+            extension: None,
+        };
+        assert_eq!(
+            music_file.canonical_name(&config, false, 0, 1),
+            Ok(format!(
+                "[Baroque] 1 of 06 {} (Bach, 1721).mp3",
+                DEFAULT_TITLE
+            ))
+        );
+
+        // missing optional tags only drop their sections
+        let config = Config {
+            file_template: Template::parse("<[{genre}] >{track} {title}< ({composer})>").unwrap(),
+            ..Config::default()
+        };
+        let music_file = MusicFile {
+            path: get_path(),
+            music_metadata: Some(get_music_metadata()),
+            extension: None,
+        };
+        assert_eq!(
+            music_file.canonical_name(&config, false, 0, 1),
+            Ok(format!("1 {}.mp3", DEFAULT_TITLE))
+        );
+    }
+
+    #[test]
+    fn test_canonical_name_with_smart_shortening() {
+        let music_file = MusicFile {
+            path: get_path(),
+            music_metadata: Some(MusicMetadata {
+                disk_number: Some(2),
+                ..get_music_metadata()
+            }),
+            extension: None,
+        };
+        let config = Config {
+            ellipsis: "…".to_string(),
+            file_template: Template::parse("{title} - {disc}-{track}").unwrap(),
+            name_length: 16,
+            shorten_names: true,
+            shorten_strategy: ShortenStrategy::Smart,
+            ..Config::default()
+        };
+        assert_eq!(
+            music_file.canonical_name(&config, false, 0, 12),
+            Ok("Foo… - 2-01.mp3".to_string())
+        );
+
+        // the numbers are never cut, not even after the title
+        let config = Config {
+            name_length: 10,
+            ..config
+        };
+        assert_eq!(
+            music_file.canonical_name(&config, false, 0, 12),
+            Err("No name built from the file template fits in 10 chars".to_string())
+        );
+    }
+
+    #[test]
+    fn test_same_values() {
+        let first = MusicFile {
+            path: get_path(),
+            music_metadata: Some(get_music_metadata()),
+            extension: None,
+        };
+        let second = MusicFile {
+            path: get_path(),
+            music_metadata: Some(MusicMetadata {
+                artist: "Somebody Else".to_string(),
+                track_number: 2,
+                year: Some(1999),
+                ..get_music_metadata()
+            }),
+            extension: None,
+        };
+        let values = same_values(&[first, second], &Config::default());
+
+        assert_eq!(
+            values.get("album"),
+            Some(&Value::Text(DEFAULT_ALBUM.to_string()))
+        );
+        assert_eq!(values.get("format"), Some(&Value::Text("MP3".to_string())));
+        assert_eq!(values.get("artist"), None);
+        assert_eq!(values.get("track"), None);
+        assert_eq!(values.get("year"), None);
+
+        assert!(same_values(&[], &Config::default()).is_empty());
+    }
+
+    #[test]
+    fn test_organized_directory() {
+        let config = Config::default();
+        let music_file = MusicFile {
+            path: get_path(),
+            music_metadata: Some(MusicMetadata {
+                artist: "AC/DC".to_string(),
+                year: Some(1980),
+                ..get_music_metadata()
+            }),
+            extension: None,
+        };
+        assert_eq!(
+            music_file.organized_directory(&config),
+            Some(PathBuf::from("AC & DC").join(format!("1980 - {}", DEFAULT_ALBUM)))
+        );
+
+        let config = Config {
+            organize_template: Template::parse("{album}//<{disc}>").unwrap(),
+            ..Config::default()
+        };
+        assert_eq!(
+            music_file.organized_directory(&config),
+            Some(PathBuf::from(DEFAULT_ALBUM))
+        );
+    }
 }
@@ -1,47 +1,81 @@
 use std::cmp::Ordering;
 use std::fmt;
 use std::fmt::Formatter;
+use std::path::Path;
 
+use crate::formats;
+pub use crate::formats::RawTags;
+
+/// The tags of a music file. Album, artist, title, and track number are
+/// required, all other fields are optional.
+#[derive(Clone, Debug, Default, PartialEq)]
 pub struct MusicMetadata {
     pub album: String,
+    pub album_artist: Option<String>,
     pub artist: String,
+    /// Is the file part of a compilation of different artists?
+    pub compilation: bool,
+    pub composer: Option<String>,
+    pub conductor: Option<String>,
+    /// The release date as tagged, e. g. "2001" or "2001-05-17"
+    pub date: Option<String>,
     pub disk_number: Option<u16>,
+    pub genre: Option<String>,
+    pub original_year: Option<i32>,
     pub title: String,
+    pub total_discs: Option<u16>,
+    pub total_tracks: Option<u16>,
     pub track_number: u16,
+    /// The release year
+    pub year: Option<i32>,
 }
 
 impl MusicMetadata {
-    pub fn new(music_file: &std::fs::DirEntry) -> Option<MusicMetadata> {
-        let tag = match audiotags::Tag::new().read_from_path(music_file.path()) {
-            Ok(t) => t,
-            Err(e) => {
-                eprintln!("{}: {}", music_file.path().to_string_lossy(), e);
-                return None;
-            }
-        };
+    /// Reads the tags of a music file in the format the extension stands for
+    pub fn new(path: &Path, extension: &str) -> Result<MusicMetadata, String> {
+        MusicMetadata::from_tags(read_tags(path, extension)?)
+    }
 
-        // we only accept *complete* metadata
-        if let Some(album) = tag.album_title() {
-            if let Some(artist) = tag.artist() {
-                if let Some(title) = tag.title() {
-                    if let Some(track_number) = tag.track_number() {
-                        return Some(MusicMetadata {
-                            album: album.to_string(),
-                            artist: artist.to_string(),
-                            disk_number: tag.disc_number(),
-                            title: title.to_string(),
-                            track_number,
-                        });
-                    }
-                }
-            }
+    /// Accepts tags only if they are complete
+    pub fn from_tags(tags: RawTags) -> Result<MusicMetadata, String> {
+        match tags {
+            RawTags {
+                album: Some(album),
+                album_artist,
+                artist: Some(artist),
+                compilation,
+                composer,
+                conductor,
+                date,
+                disc,
+                genre,
+                original_year,
+                title: Some(title),
+                total_discs,
+                total_tracks,
+                track: Some(track_number),
+                year,
+            } => Ok(MusicMetadata {
+                album,
+                album_artist,
+                artist,
+                compilation,
+                composer,
+                conductor,
+                date,
+                disk_number: disc,
+                genre,
+                original_year,
+                title,
+                total_discs,
+                total_tracks,
+                track_number,
+                year,
+            }),
+            _ => Err(
+                "Incomplete tags found -- need album, artist, title, and track number.".to_string(),
+            ),
         }
-
-        eprintln!(
-            "Error: Incomplete tags found in {} -- need album, artist, title, and track number.",
-            music_file.path().to_string_lossy()
-        );
-        None
     }
 
     pub fn sort_func(a: &Option<MusicMetadata>, b: &Option<MusicMetadata>) -> Ordering {
@@ -79,15 +113,114 @@ impl MusicMetadata {
     }
 }
 
+/// Reads the tags of a music file as they are, complete or not
+pub fn read_tags(path: &Path, extension: &str) -> Result<RawTags, String> {
+    match formats::read(path, extension) {
+        Some(tags) => tags,
+        None => read_with_audiotags(path, extension),
+    }
+}
+
+/// Reads MP3, FLAC, and MP4 files. The format is chosen by the given
+/// extension as the actual one might be wrong.
+fn read_with_audiotags(path: &Path, extension: &str) -> Result<RawTags, String> {
+    // the values audiotags doesn't provide come from the underlying tag
+    let (mut tags, other_tags) = match extension {
+        "flac" => {
+            let tag = audiotags::FlacTag::read_from_path(path).map_err(|e| e.to_string())?;
+            (common_tags(&tag), formats::from_flac(&tag.into()))
+        }
+        "mp3" => {
+            let tag = audiotags::Id3v2Tag::read_from_path(path).map_err(|e| e.to_string())?;
+            (common_tags(&tag), formats::from_id3(&tag.into()))
+        }
+        _ => {
+            let tag = audiotags::Mp4Tag::read_from_path(path).map_err(|e| e.to_string())?;
+            (common_tags(&tag), formats::from_mp4(&tag.into()))
+        }
+    };
+    tags.merge(other_tags);
+
+    Ok(tags)
+}
+
+impl From<MusicMetadata> for RawTags {
+    fn from(metadata: MusicMetadata) -> RawTags {
+        RawTags {
+            album: Some(metadata.album),
+            album_artist: metadata.album_artist,
+            artist: Some(metadata.artist),
+            compilation: metadata.compilation,
+            composer: metadata.composer,
+            conductor: metadata.conductor,
+            date: metadata.date,
+            disc: metadata.disk_number,
+            genre: metadata.genre,
+            original_year: metadata.original_year,
+            title: Some(metadata.title),
+            total_discs: metadata.total_discs,
+            total_tracks: metadata.total_tracks,
+            track: Some(metadata.track_number),
+            year: metadata.year,
+        }
+    }
+}
+
+fn common_tags(tag: &dyn audiotags::AudioTag) -> RawTags {
+    RawTags {
+        album: tag.album_title().map(String::from),
+        album_artist: tag.album_artist().map(String::from),
+        artist: tag.artist().map(String::from),
+        disc: tag.disc_number(),
+        title: tag.title().map(String::from),
+        total_discs: tag.total_discs(),
+        total_tracks: tag.total_tracks(),
+        track: tag.track_number(),
+        year: tag.year(),
+        ..RawTags::default()
+    }
+}
+
 impl fmt::Display for MusicMetadata {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         writeln!(f, "Album:        {}", self.album)?;
+        if let Some(album_artist) = &self.album_artist {
+            writeln!(f, "Album Artist: {}", album_artist)?;
+        }
+        if self.compilation {
+            writeln!(f, "Compilation:  yes")?;
+        }
         if let Some(disk_number) = self.disk_number {
             writeln!(f, "Disk Number:  {}", disk_number)?;
         }
+        if let Some(total_discs) = self.total_discs {
+            writeln!(f, "Total Discs:  {}", total_discs)?;
+        }
         writeln!(f, "Track Number: {}", self.track_number)?;
+        if let Some(total_tracks) = self.total_tracks {
+            writeln!(f, "Total Tracks: {}", total_tracks)?;
+        }
         writeln!(f, "Artist:       {}", self.artist)?;
-        writeln!(f, "Title:        {}", self.title)
+        writeln!(f, "Title:        {}", self.title)?;
+        if let Some(year) = self.year {
+            writeln!(f, "Year:         {}", year)?;
+        }
+        if let Some(date) = &self.date {
+            writeln!(f, "Date:         {}", date)?;
+        }
+        if let Some(original_year) = self.original_year {
+            writeln!(f, "Original Year: {}", original_year)?;
+        }
+        if let Some(genre) = &self.genre {
+            writeln!(f, "Genre:        {}", genre)?;
+        }
+        if let Some(composer) = &self.composer {
+            writeln!(f, "Composer:     {}", composer)?;
+        }
+        if let Some(conductor) = &self.conductor {
+            writeln!(f, "Conductor:    {}", conductor)?;
+        }
+        Ok(())
     }
 }
 
@@ -107,6 +240,7 @@ mod tests {
                 disk_number: None,
                 title: "".to_string(),
                 track_number: 0,
+                ..MusicMetadata::default()
             }),
         );
         MusicMetadata::sort_func(
@@ -116,6 +250,7 @@ mod tests {
                 disk_number: None,
                 title: "".to_string(),
                 track_number: 0,
+                ..MusicMetadata::default()
             }),
             &None,
         );
@@ -131,6 +266,7 @@ mod tests {
                     disk_number: None,
                     title: "".to_string(),
                     track_number: 0,
+                    ..MusicMetadata::default()
                 }),
                 &Some(MusicMetadata {
                     album: "".to_string(),
@@ -138,6 +274,7 @@ mod tests {
                     disk_number: None,
                     title: "".to_string(),
                     track_number: 0,
+                    ..MusicMetadata::default()
                 }),
             ),
             Ordering::Equal
@@ -153,14 +290,16 @@ mod tests {
                     artist: "".to_string(),
                     disk_number: Some(1),
                     title: "".to_string(),
-                    track_number: 0
+                    track_number: 0,
+                    ..MusicMetadata::default()
                 }),
                 &Some(MusicMetadata {
                     album: "".to_string(),
                     artist: "".to_string(),
                     disk_number: None,
                     title: "".to_string(),
-                    track_number: 0
+                    track_number: 0,
+                    ..MusicMetadata::default()
                 }),
             ),
             Ordering::Greater,
@@ -172,14 +311,16 @@ mod tests {
                     artist: "".to_string(),
                     disk_number: None,
                     title: "".to_string(),
-                    track_number: 0
+                    track_number: 0,
+                    ..MusicMetadata::default()
                 }),
                 &Some(MusicMetadata {
                     album: "".to_string(),
                     artist: "".to_string(),
                     disk_number: Some(1),
                     title: "".to_string(),
-                    track_number: 0
+                    track_number: 0,
+                    ..MusicMetadata::default()
                 }),
             ),
             Ordering::Less,
@@ -191,14 +332,16 @@ mod tests {
                     artist: "".to_string(),
                     disk_number: Some(1),
                     title: "".to_string(),
-                    track_number: 0
+                    track_number: 0,
+                    ..MusicMetadata::default()
                 }),
                 &Some(MusicMetadata {
                     album: "".to_string(),
                     artist: "".to_string(),
                     disk_number: Some(2),
                     title: "".to_string(),
-                    track_number: 0
+                    track_number: 0,
+                    ..MusicMetadata::default()
                 }),
             ),
             Ordering::Less,
@@ -210,14 +353,16 @@ mod tests {
                     artist: "".to_string(),
                     disk_number: Some(2),
                     title: "".to_string(),
-                    track_number: 0
+                    track_number: 0,
+                    ..MusicMetadata::default()
                 }),
                 &Some(MusicMetadata {
                     album: "".to_string(),
                     artist: "".to_string(),
                     disk_number: Some(1),
                     title: "".to_string(),
-                    track_number: 0
+                    track_number: 0,
+                    ..MusicMetadata::default()
                 }),
             ),
             Ordering::Greater,
@@ -233,14 +378,16 @@ mod tests {
                     artist: "".to_string(),
                     disk_number: None,
                     title: "".to_string(),
-                    track_number: 1
+                    track_number: 1,
+                    ..MusicMetadata::default()
                 }),
                 &Some(MusicMetadata {
                     album: "".to_string(),
                     artist: "".to_string(),
                     disk_number: None,
                     title: "".to_string(),
-                    track_number: 2
+                    track_number: 2,
+                    ..MusicMetadata::default()
                 })
             ),
             Ordering::Less
@@ -252,14 +399,16 @@ mod tests {
                     artist: "".to_string(),
                     disk_number: None,
                     title: "".to_string(),
-                    track_number: 2
+                    track_number: 2,
+                    ..MusicMetadata::default()
                 }),
                 &Some(MusicMetadata {
                     album: "".to_string(),
                     artist: "".to_string(),
                     disk_number: None,
                     title: "".to_string(),
-                    track_number: 1
+                    track_number: 1,
+                    ..MusicMetadata::default()
                 })
             ),
             Ordering::Greater
@@ -271,14 +420,16 @@ mod tests {
                     artist: "".to_string(),
                     disk_number: None,
                     title: "".to_string(),
-                    track_number: 1
+                    track_number: 1,
+                    ..MusicMetadata::default()
                 }),
                 &Some(MusicMetadata {
                     album: "".to_string(),
                     artist: "".to_string(),
                     disk_number: None,
                     title: "".to_string(),
-                    track_number: 1
+                    track_number: 1,
+                    ..MusicMetadata::default()
                 })
             ),
             Ordering::Equal
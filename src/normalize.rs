@@ -0,0 +1,354 @@
+use std::fmt;
+use std::fmt::Formatter;
+use std::str::FromStr;
+
+use regex::Regex;
+
+use crate::config::Config;
+use crate::music_metadata::RawTags;
+
+/// Which clean-up steps are applied to tag values before building names
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Steps {
+    /// Trim the values and collapse runs of whitespace into a single space
+    pub whitespace: bool,
+    /// Replace typographic quotes with straight ones
+    pub quotes: bool,
+    /// Replace en dashes, em dashes, minus signs etc. with hyphens
+    pub dashes: bool,
+    /// Capitalize the words of titles and albums, except for small words
+    pub title_case: bool,
+}
+
+impl FromStr for Steps {
+    type Err = String;
+
+    /// Parses a comma-separated list of whitespace, quotes, dashes, and
+    /// title-case, or "all", or "none"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut steps = Steps::default();
+        for name in s.split(',').map(str::trim) {
+            match name {
+                "whitespace" => steps.whitespace = true,
+                "quotes" => steps.quotes = true,
+                "dashes" => steps.dashes = true,
+                "title-case" => steps.title_case = true,
+                "all" => {
+                    steps = Steps {
+                        whitespace: true,
+                        quotes: true,
+                        dashes: true,
+                        title_case: true,
+                    }
+                }
+                "none" => steps = Steps::default(),
+                _ => {
+                    return Err(format!(
+                        "Unknown normalization \"{}\", use whitespace, quotes, dashes, title-case, all, or none",
+                        name
+                    ))
+                }
+            }
+        }
+        Ok(steps)
+    }
+}
+
+impl fmt::Display for Steps {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let names: Vec<&str> = [
+            (self.whitespace, "whitespace"),
+            (self.quotes, "quotes"),
+            (self.dashes, "dashes"),
+            (self.title_case, "title-case"),
+        ]
+        .iter()
+        .filter(|(is_on, _)| *is_on)
+        .map(|(_, name)| *name)
+        .collect();
+        if names.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(f, "{}", names.join(","))
+        }
+    }
+}
+
+/// The language whose small words stay lowercase in title case
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Language {
+    English,
+    German,
+    French,
+    Spanish,
+    Italian,
+    Dutch,
+}
+
+impl Language {
+    fn small_words(self) -> &'static [&'static str] {
+        match self {
+            Language::English => &[
+                "a", "an", "and", "as", "at", "but", "by", "for", "from", "in", "into", "nor",
+                "of", "on", "or", "over", "the", "to", "up", "vs", "with",
+            ],
+            Language::German => &[
+                "am", "an", "auf", "aus", "bei", "das", "dem", "den", "der", "des", "die", "ein",
+                "eine", "einem", "einen", "einer", "für", "im", "in", "mit", "oder", "um", "und",
+                "von", "vom", "zu", "zum", "zur",
+            ],
+            Language::French => &[
+                "à", "au", "aux", "d'", "de", "des", "du", "en", "et", "l'", "la", "le", "les",
+                "ou", "par", "pour", "sur", "un", "une",
+            ],
+            Language::Spanish => &[
+                "a", "al", "con", "de", "del", "el", "en", "la", "las", "los", "o", "para", "por",
+                "un", "una", "y",
+            ],
+            Language::Italian => &[
+                "a", "al", "con", "da", "del", "della", "di", "e", "gli", "i", "il", "in", "la",
+                "le", "lo", "o", "per", "su", "un", "una",
+            ],
+            Language::Dutch => &[
+                "de", "een", "en", "het", "in", "met", "of", "op", "te", "van", "voor",
+            ],
+        }
+    }
+}
+
+impl FromStr for Language {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "en" => Ok(Language::English),
+            "de" => Ok(Language::German),
+            "fr" => Ok(Language::French),
+            "es" => Ok(Language::Spanish),
+            "it" => Ok(Language::Italian),
+            "nl" => Ok(Language::Dutch),
+            _ => Err(format!(
+                "Unknown language \"{}\", use en, de, fr, es, it, or nl",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Language {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Language::English => write!(f, "en"),
+            Language::German => write!(f, "de"),
+            Language::French => write!(f, "fr"),
+            Language::Spanish => write!(f, "es"),
+            Language::Italian => write!(f, "it"),
+            Language::Dutch => write!(f, "nl"),
+        }
+    }
+}
+
+/// A regular expression together with the text replacing its matches, which
+/// may refer to groups like `$1`
+#[derive(Clone, Debug)]
+pub struct Replacement {
+    regex: Regex,
+    replacement: String,
+}
+
+impl Replacement {
+    pub fn new(pattern: &str, replacement: &str) -> Result<Replacement, String> {
+        let regex = Regex::new(pattern)
+            .map_err(|err| format!("Invalid replacement pattern \"{}\": {}", pattern, err))?;
+        Ok(Replacement {
+            regex,
+            replacement: replacement.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for Replacement {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "\"{}\" -> \"{}\"", self.regex, self.replacement)
+    }
+}
+
+/// Cleans up the text tags. Title case only applies to the title and the
+/// album as names of artists are better left alone.
+pub fn normalize_tags(tags: &mut RawTags, config: &Config) {
+    for (value, is_title) in [
+        (&mut tags.album, true),
+        (&mut tags.album_artist, false),
+        (&mut tags.artist, false),
+        (&mut tags.composer, false),
+        (&mut tags.conductor, false),
+        (&mut tags.genre, false),
+        (&mut tags.title, true),
+    ] {
+        if let Some(text) = value {
+            *text = normalize(text, is_title, config);
+        }
+    }
+}
+
+/// Applies the configured steps to a tag value: folding quotes and dashes,
+/// the replacements in their order, collapsing whitespace, and title case
+pub fn normalize(value: &str, is_title: bool, config: &Config) -> String {
+    let steps = &config.normalize;
+    let mut value = value.to_string();
+
+    if steps.quotes {
+        value = value
+            .chars()
+            .map(|c| match c {
+                '\u{2018}' | '\u{2019}' | '\u{201A}' | '\u{201B}' | '\u{2032}' | '`' | '´' => '\'',
+                '\u{201C}' | '\u{201D}' | '\u{201E}' | '\u{201F}' | '\u{2033}' => '"',
+                _ => c,
+            })
+            .collect();
+    }
+    if steps.dashes {
+        value = value
+            .chars()
+            .map(|c| match c {
+                '\u{2010}'..='\u{2015}' | '\u{2212}' | '\u{FE58}' | '\u{FE63}' | '\u{FF0D}' => '-',
+                _ => c,
+            })
+            .collect();
+    }
+    for replacement in &config.replacements {
+        value = replacement
+            .regex
+            .replace_all(&value, replacement.replacement.as_str())
+            .to_string();
+    }
+    if steps.whitespace {
+        value = value.split_whitespace().collect::<Vec<&str>>().join(" ");
+    }
+    if steps.title_case && is_title {
+        value = title_case(&value, config.title_case_language);
+    }
+
+    value
+}
+
+/// Capitalizes all words but the small ones of the language, unless they
+/// start the title, end it, or start a part of it like "(the Remix)".
+/// Titles in all caps are lowercased first, words with capitals inside like
+/// "McCartney" or "iPod" are kept.
+fn title_case(value: &str, language: Language) -> String {
+    let value = if value.chars().any(char::is_lowercase) {
+        value.to_string()
+    } else {
+        value.to_lowercase()
+    };
+    let words: Vec<&str> = value.split(' ').collect();
+    let small_words = language.small_words();
+
+    let mut result = Vec::new();
+    let mut starts_part = true;
+    for (index, word) in words.iter().enumerate() {
+        let is_last = index == words.len() - 1;
+        let lowercase = word.to_lowercase();
+        let is_small = small_words.contains(&lowercase.as_str());
+        let is_opening = word.starts_with(|c: char| "([{\"'".contains(c));
+        let new_word = if is_small && !starts_part && !is_last && !is_opening {
+            lowercase
+        } else if word.chars().any(char::is_uppercase) {
+            word.to_string()
+        } else {
+            capitalize(word)
+        };
+        result.push(new_word);
+        starts_part = word.ends_with(|c: char| ":.!?".contains(c)) || *word == "-";
+    }
+
+    result.join(" ")
+}
+
+/// Turns the first letter into a capital one
+fn capitalize(word: &str) -> String {
+    match word.char_indices().find(|(_, c)| c.is_alphabetic()) {
+        Some((index, c)) => format!(
+            "{}{}{}",
+            &word[..index],
+            c.to_uppercase(),
+            &word[index + c.len_utf8()..]
+        ),
+        None => word.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        let config = Config {
+            normalize: "all".parse().unwrap(),
+            replacements: vec![Replacement::new(r"(?i)\bfeat\.", "featuring").unwrap()],
+            ..Config::default()
+        };
+        assert_eq!(
+            normalize("  DON’T STOP  ME NOW – LIVE ", true, &config),
+            "Don't Stop Me Now - Live"
+        );
+        assert_eq!(
+            normalize("“Heroes” (Feat. the Foos)", true, &config),
+            "\"Heroes\" (Featuring the Foos)"
+        );
+        // artists don't get title case
+        assert_eq!(normalize("the  foos", false, &config), "the foos");
+
+        let config = Config::default();
+        assert_eq!(normalize(" as  is ", true, &config), " as  is ");
+    }
+
+    #[test]
+    fn test_title_case() {
+        assert_eq!(
+            title_case("the sound of the foos", Language::English),
+            "The Sound of the Foos"
+        );
+        assert_eq!(
+            title_case(
+                "a night at the opera: the remix (the end)",
+                Language::English
+            ),
+            "A Night at the Opera: The Remix (The End)"
+        );
+        assert_eq!(title_case("ABBA GOLD", Language::English), "Abba Gold");
+        assert_eq!(
+            title_case("my iPod and McCartney", Language::English),
+            "My iPod and McCartney"
+        );
+        assert_eq!(
+            title_case("über den wolken", Language::German),
+            "Über den Wolken"
+        );
+        assert_eq!(
+            title_case("le chant des sirènes", Language::French),
+            "Le Chant des Sirènes"
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            "quotes, dashes".parse(),
+            Ok(Steps {
+                quotes: true,
+                dashes: true,
+                ..Steps::default()
+            })
+        );
+        assert_eq!(
+            "all".parse::<Steps>().unwrap().to_string(),
+            "whitespace,quotes,dashes,title-case"
+        );
+        assert!("lowercase".parse::<Steps>().is_err());
+        assert_eq!("de".parse(), Ok(Language::German));
+        assert!(Replacement::new("(", "").is_err());
+    }
+}
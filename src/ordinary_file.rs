@@ -1,11 +1,11 @@
-use std::fs;
+use std::path::PathBuf;
 
 pub struct OrdinaryFile {
-    pub dir_entry: fs::DirEntry,
+    pub path: PathBuf,
 }
 
 impl OrdinaryFile {
-    pub fn new(dir_entry: fs::DirEntry) -> OrdinaryFile {
-        OrdinaryFile { dir_entry }
+    pub fn new(path: PathBuf) -> OrdinaryFile {
+        OrdinaryFile { path }
     }
 }
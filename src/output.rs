@@ -0,0 +1,364 @@
+use std::fmt;
+use std::fmt::Formatter;
+use std::path::Path;
+use std::str::FromStr;
+
+use serde_json::{json, Value};
+
+use crate::journal::{Action, Entry};
+use crate::plan::{DirectoryPlan, Operation};
+
+/// How to report what a run does
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    /// Human-readable messages
+    Text,
+    /// One JSON array containing all records, printed at the end of the run
+    Json,
+    /// One JSON record per line, printed as soon as it's known
+    Jsonl,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            "jsonl" => Ok(OutputFormat::Jsonl),
+            _ => Err(format!(
+                "Unknown output format \"{}\", use text, json, or jsonl",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            OutputFormat::Text => write!(f, "text"),
+            OutputFormat::Json => write!(f, "json"),
+            OutputFormat::Jsonl => write!(f, "jsonl"),
+        }
+    }
+}
+
+/// What became of an operation
+#[derive(Clone, Debug, PartialEq)]
+pub enum Outcome {
+    /// Not carried out in dry-run mode
+    Planned,
+    Done,
+    Failed(String),
+    Skipped,
+}
+
+/// Reports directories and operations either as text or as JSON records
+pub struct Output {
+    format: OutputFormat,
+    verbose: bool,
+    directory: Option<String>,
+    records: Vec<Value>,
+}
+
+impl Output {
+    pub fn new(format: OutputFormat, verbose: bool) -> Output {
+        Output {
+            format,
+            verbose,
+            directory: None,
+            records: Vec::new(),
+        }
+    }
+
+    pub fn directory(&mut self, directory_plan: &DirectoryPlan) {
+        let path = directory_plan.path.to_string_lossy().to_string();
+        match self.format {
+            OutputFormat::Text => {
+                println!("==============");
+                println!("Entering directory \"{}\"", path);
+                if self.verbose {
+                    for note in &directory_plan.notes {
+                        println!("{}", note);
+                    }
+                }
+                for guess in &directory_plan.guesses {
+                    println!("{}", guess);
+                }
+            }
+            _ => self.record(json!({
+                "type": "directory",
+                "path": path,
+                "notes": directory_plan.notes,
+                "guesses": directory_plan.guesses,
+            })),
+        }
+        self.directory = Some(path);
+    }
+
+    pub fn operation(&mut self, operation: &Operation, outcome: &Outcome) {
+        match self.format {
+            OutputFormat::Text => {
+                match outcome {
+                    Outcome::Skipped => eprintln!("{}", operation),
+                    _ => println!("{}", operation),
+                }
+                if let Outcome::Failed(error) = outcome {
+                    eprintln!("Error: {}", error);
+                }
+            }
+            _ => {
+                let mut record = serde_json::to_value(operation).unwrap_or_else(|_| json!({}));
+                record["type"] = json!("operation");
+                record["directory"] = json!(self.directory);
+                match outcome {
+                    Outcome::Planned => record["status"] = json!("planned"),
+                    Outcome::Done => record["status"] = json!("done"),
+                    Outcome::Skipped => record["status"] = json!("skipped"),
+                    Outcome::Failed(error) => {
+                        record["status"] = json!("failed");
+                        record["error"] = json!(error);
+                    }
+                }
+                self.record(record);
+            }
+        }
+    }
+
+    pub fn removed_directory(&mut self, path: &Path) {
+        match self.format {
+            OutputFormat::Text => {
+                println!("Removing empty directory \"{}\"", path.to_string_lossy())
+            }
+            _ => self.record(json!({
+                "type": "operation",
+                "directory": self.directory,
+                "operation": "remove_directory",
+                "path": path,
+                "status": "done",
+            })),
+        }
+    }
+
+    /// The rules changing a text on its way to a file or directory name,
+    /// each with the resulting name
+    pub fn explanation(&mut self, text: &str, steps: &[(String, String)]) {
+        match self.format {
+            OutputFormat::Text => {
+                if steps.is_empty() {
+                    println!("No rule changes \"{}\"", text);
+                    return;
+                }
+                println!("{:?}", text);
+                for (rule, name) in steps {
+                    println!("{}: {:?}", rule, name);
+                }
+            }
+            _ => self.record(json!({
+                "type": "explanation",
+                "text": text,
+                "steps": steps
+                    .iter()
+                    .map(|(rule, name)| json!({ "rule": rule, "name": name }))
+                    .collect::<Vec<_>>(),
+            })),
+        }
+    }
+
+    /// A journal entry being undone
+    pub fn restore(&mut self, entry: &Entry, outcome: &Outcome) {
+        match self.format {
+            OutputFormat::Text => {
+                match entry.action {
+                    Action::RemoveDirectory => println!(
+                        "Restoring directory \"{}\"",
+                        entry.old_path.to_string_lossy()
+                    ),
+                    Action::WriteTags => println!(
+                        "Restoring tags of \"{}\": {}",
+                        entry.old_path.to_string_lossy(),
+                        entry
+                            .before
+                            .iter()
+                            .map(|(name, value)| format!("{} \"{}\"", name, value))
+                            .collect::<Vec<String>>()
+                            .join(", ")
+                    ),
+                    _ => println!(
+                        "Restoring \"{}\" from \"{}\"",
+                        entry.old_path.to_string_lossy(),
+                        entry.new_path.to_string_lossy()
+                    ),
+                }
+                if let Outcome::Failed(error) = outcome {
+                    eprintln!(
+                        "Error restoring \"{}\": {}",
+                        entry.old_path.to_string_lossy(),
+                        error
+                    );
+                }
+            }
+            _ => {
+                let mut record = restore_record(entry);
+                match outcome {
+                    Outcome::Planned => record["status"] = json!("planned"),
+                    Outcome::Done => record["status"] = json!("done"),
+                    Outcome::Skipped => record["status"] = json!("skipped"),
+                    Outcome::Failed(error) => {
+                        record["status"] = json!("failed");
+                        record["error"] = json!(error);
+                    }
+                }
+                self.record(record);
+            }
+        }
+    }
+
+    /// A journal entry that isn't undone
+    pub fn skipped_restore(&mut self, entry: &Entry, reason: &str) {
+        match self.format {
+            OutputFormat::Text => eprintln!(
+                "Skipping \"{}\": {}",
+                entry.new_path.to_string_lossy(),
+                reason
+            ),
+            _ => {
+                let mut record = restore_record(entry);
+                record["status"] = json!("skipped");
+                record["reason"] = json!(reason);
+                self.record(record);
+            }
+        }
+    }
+
+    /// A diagnostic message only shown as text in verbose mode
+    pub fn note(&mut self, note: &str) {
+        if self.format == OutputFormat::Text && self.verbose {
+            println!("{}", note);
+        }
+    }
+
+    pub fn journal(&mut self, path: &Path) {
+        match self.format {
+            OutputFormat::Text => {
+                println!("==============");
+                println!(
+                    "Journal written to \"{}\", use \"mp3rename undo\" with it to revert this run",
+                    path.to_string_lossy()
+                );
+            }
+            _ => self.record(json!({
+                "type": "journal",
+                "path": path,
+            })),
+        }
+    }
+
+    /// Prints everything not printed yet
+    pub fn finish(&mut self) {
+        if self.format == OutputFormat::Json {
+            match serde_json::to_string_pretty(&self.records) {
+                Ok(json) => println!("{}", json),
+                Err(err) => eprintln!("Error writing JSON output: {}", err),
+            }
+            self.records.clear();
+        }
+    }
+
+    fn record(&mut self, record: Value) {
+        match self.format {
+            OutputFormat::Jsonl => println!("{}", record),
+            _ => self.records.push(record),
+        }
+    }
+}
+
+fn restore_record(entry: &Entry) -> Value {
+    let mut record = json!({
+        "type": "restore",
+        "action": entry.action,
+        "from": entry.new_path,
+        "to": entry.old_path,
+    });
+    if entry.action == Action::WriteTags {
+        record["tags"] = json!(entry.before);
+    }
+    record
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plan::Tags;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_operation_records() {
+        let mut output = Output::new(OutputFormat::Json, false);
+        output.directory(&DirectoryPlan::new(Path::new("/music")));
+        output.operation(
+            &Operation::RemoveFile {
+                path: PathBuf::from("/music/cover.jpg"),
+            },
+            &Outcome::Failed("Permission denied".to_string()),
+        );
+
+        assert_eq!(output.records.len(), 2);
+        assert_eq!(output.records[0]["type"], "directory");
+        assert_eq!(output.records[1]["type"], "operation");
+        assert_eq!(output.records[1]["operation"], "remove_file");
+        assert_eq!(output.records[1]["directory"], "/music");
+        assert_eq!(output.records[1]["path"], "/music/cover.jpg");
+        assert_eq!(output.records[1]["status"], "failed");
+        assert_eq!(output.records[1]["error"], "Permission denied");
+    }
+
+    #[test]
+    fn test_explanation_and_restore_records() {
+        let mut output = Output::new(OutputFormat::Json, false);
+        output.explanation(
+            "AC/DC",
+            &[(
+                "Rule 4: \"/\" -> \" & \"".to_string(),
+                "AC & DC".to_string(),
+            )],
+        );
+        let entry = Entry {
+            action: Action::Rename,
+            old_path: PathBuf::from("/music/a.mp3"),
+            new_path: PathBuf::from("/music/1 Foo.mp3"),
+            size: None,
+            modified: None,
+            extension: String::new(),
+            before: Tags::new(),
+            after: Tags::new(),
+        };
+        output.restore(&entry, &Outcome::Done);
+        output.skipped_restore(&entry, "it has changed since the run");
+        let mut before = Tags::new();
+        before.insert("title".to_string(), "Foo".to_string());
+        let entry = Entry {
+            action: Action::WriteTags,
+            old_path: PathBuf::from("/music/a.mp3"),
+            extension: "mp3".to_string(),
+            before,
+            ..entry
+        };
+        output.restore(&entry, &Outcome::Planned);
+
+        assert_eq!(output.records.len(), 4);
+        assert_eq!(output.records[0]["type"], "explanation");
+        assert_eq!(output.records[0]["steps"][0]["name"], "AC & DC");
+        assert_eq!(output.records[1]["type"], "restore");
+        assert_eq!(output.records[1]["action"], "rename");
+        assert_eq!(output.records[1]["from"], "/music/1 Foo.mp3");
+        assert_eq!(output.records[1]["to"], "/music/a.mp3");
+        assert_eq!(output.records[1]["status"], "done");
+        assert_eq!(output.records[2]["status"], "skipped");
+        assert_eq!(output.records[2]["reason"], "it has changed since the run");
+        assert_eq!(output.records[3]["action"], "write_tags");
+        assert_eq!(output.records[3]["tags"]["title"], "Foo");
+    }
+}
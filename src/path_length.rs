@@ -0,0 +1,297 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::collisions::Rename;
+use crate::config::Config;
+use crate::length;
+use crate::util;
+
+/// Returns the directory the length of paths is measured from: the mount
+/// root if given, else the library root when organizing, else the start
+/// directory
+pub fn root(config: &Config) -> &Path {
+    config
+        .mount_root
+        .as_deref()
+        .or(config.organize_into.as_deref())
+        .unwrap_or(&config.start_dir)
+}
+
+/// Returns the length of a path below the root in the configured unit,
+/// counting the separators but not a leading one
+pub fn measure(path: &Path, config: &Config) -> usize {
+    let relative = path.strip_prefix(root(config)).unwrap_or(path);
+    let names = names(relative);
+    let separators = names.len().saturating_sub(1);
+    names
+        .iter()
+        .map(|name| config.length_unit.measure(name))
+        .sum::<usize>()
+        + separators
+}
+
+/// Returns the length the name of a path may have so that the whole path
+/// fits in the maximum path length
+pub fn name_budget(path: &Path, max_length: usize, config: &Config) -> usize {
+    let dir_length = path.parent().map_or(0, |dir| measure(dir, config));
+    let separator = if dir_length == 0 { 0 } else { 1 };
+    max_length.saturating_sub(dir_length + separator)
+}
+
+/// Shortens the target paths of the renames so that they fit in the maximum
+/// path length. Files moved into the same directory are shortened together:
+/// if the directory is created when organizing, its names are cut, longest
+/// first. File names that are still too long are built again by `name_within`
+/// for the length left, using the configured shortening strategy.
+/// `shortened_dirs` keeps the directories shortened earlier so that all
+/// files moved there end up in the same place. Returns the renames that fit
+/// and those that don't with the reason.
+pub fn fit(
+    renames: Vec<Rename>,
+    max_length: usize,
+    config: &Config,
+    shortened_dirs: &mut HashMap<PathBuf, PathBuf>,
+    name_within: impl Fn(&Rename, usize) -> Option<String>,
+) -> (Vec<Rename>, Vec<(Rename, String)>) {
+    let mut by_dir: Vec<(PathBuf, Vec<Rename>)> = Vec::new();
+    for rename in renames {
+        let dir = rename
+            .to
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+        match by_dir.iter_mut().find(|(other, _)| *other == dir) {
+            Some((_, group)) => group.push(rename),
+            None => by_dir.push((dir, vec![rename])),
+        }
+    }
+
+    let mut fitting = Vec::new();
+    let mut skipped = Vec::new();
+    for (dir, group) in by_dir {
+        let dir = match shortened_dirs.get(&dir) {
+            Some(shortened) => shortened.clone(),
+            None => {
+                let longest_name = group
+                    .iter()
+                    .map(|rename| file_name(&rename.to))
+                    .max_by_key(|name| config.length_unit.measure(name))
+                    .unwrap_or_default();
+                let shortened = shorten_dir(&dir, &longest_name, max_length, config);
+                shortened_dirs.insert(dir, shortened.clone());
+                shortened
+            }
+        };
+
+        for rename in group {
+            let to = dir.join(file_name(&rename.to));
+            let budget = name_budget(&to, max_length, config);
+            let name = if config.length_unit.measure(&file_name(&to)) <= budget {
+                Some(file_name(&to))
+            } else {
+                name_within(&rename, budget)
+            };
+            let (extension, _) = util::get_extension(&rename.to);
+            match name {
+                Some(name)
+                    if config.length_unit.measure(&name) <= budget
+                        && !util::get_name_stem(&name, &extension).is_empty() =>
+                {
+                    fitting.push(Rename {
+                        from: rename.from,
+                        to: dir.join(name),
+                    })
+                }
+                _ => {
+                    let reason = format!(
+                        "\"{}\" doesn't fit in the maximum path length of {}",
+                        rename.to.to_string_lossy(),
+                        max_length
+                    );
+                    skipped.push((rename, reason));
+                }
+            }
+        }
+    }
+
+    (fitting, skipped)
+}
+
+/// Cuts the new name of a renamed directory so that the longest name of the
+/// files it contains still fits in the maximum path length. Returns None if
+/// that's not possible.
+pub fn fit_directory(
+    dir: &Path,
+    longest_name: &str,
+    max_length: usize,
+    config: &Config,
+) -> Option<PathBuf> {
+    let unit = config.length_unit;
+    let parent = dir.parent()?;
+    let parent_length = measure(parent, config);
+    let separators = if parent_length == 0 { 1 } else { 2 };
+    let budget = max_length.saturating_sub(parent_length + separators + unit.measure(longest_name));
+    let name = length::truncate(&file_name(dir), budget, unit, &config.ellipsis);
+    let name = util::sanitize_file_or_directory_name(&name, config);
+    if name.is_empty() {
+        None
+    } else {
+        Some(parent.join(name))
+    }
+}
+
+/// Cuts the names of the directories created when organizing so that a file
+/// named `longest_name` fits below them, shortening its name as well if
+/// necessary
+fn shorten_dir(dir: &Path, longest_name: &str, max_length: usize, config: &Config) -> PathBuf {
+    let (base, created) = match &config.organize_into {
+        Some(library_root) => match dir.strip_prefix(library_root) {
+            Ok(created) => (library_root.clone(), names(created)),
+            Err(_) => return dir.to_path_buf(),
+        },
+        None => return dir.to_path_buf(),
+    };
+    let unit = config.length_unit;
+    let (extension, _) = util::get_extension(Path::new(longest_name));
+    let stem = util::get_name_stem(longest_name, &extension);
+
+    let mut lengths: Vec<usize> = created.iter().map(|name| unit.measure(name)).collect();
+    lengths.push(unit.measure(&stem));
+    let base_length = measure(&base, config);
+    let fixed = base_length
+        + if base_length == 0 { 0 } else { 1 }
+        + created.len()
+        + unit.measure(&extension);
+    let cap = match length::common_cap(&lengths, fixed, max_length) {
+        Some(cap) => cap,
+        None => return dir.to_path_buf(),
+    };
+
+    let mut shortened = base;
+    for name in created {
+        let name = length::truncate(&name, cap, unit, &config.ellipsis);
+        shortened.push(util::sanitize_file_or_directory_name(&name, config));
+    }
+    shortened
+}
+
+fn names(path: &Path) -> Vec<String> {
+    path.iter()
+        .map(|name| name.to_string_lossy().to_string())
+        .filter(|name| name != "/")
+        .collect()
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Cuts the name at the end, like the truncate strategy
+    fn cut(rename: &Rename, limit: usize, config: &Config) -> Option<String> {
+        Some(util::shorten_name_to(
+            &rename.to,
+            &file_name(&rename.to),
+            limit,
+            config,
+        ))
+    }
+
+    fn rename(to: &str) -> Rename {
+        Rename {
+            from: PathBuf::from("/incoming/a.mp3"),
+            to: PathBuf::from(to),
+        }
+    }
+
+    #[test]
+    fn test_fit() {
+        let config = Config {
+            organize_into: Some(PathBuf::from("/media/usb")),
+            start_dir: PathBuf::from("/incoming"),
+            ..Config::default()
+        };
+        let renames = vec![
+            rename("/media/usb/The Foos/Greatest Hits/01 Foo.mp3"),
+            rename("/media/usb/The Foos/Greatest Hits/02 A Much Longer Title.mp3"),
+        ];
+        assert_eq!(
+            measure(
+                Path::new("/media/usb/The Foos/Greatest Hits/01 Foo.mp3"),
+                &config
+            ),
+            33
+        );
+
+        let mut shortened_dirs = HashMap::new();
+        let (fitting, skipped) = fit(renames.clone(), 30, &config, &mut shortened_dirs, |r, l| {
+            cut(r, l, &config)
+        });
+        assert!(skipped.is_empty());
+        let paths: Vec<PathBuf> = fitting.into_iter().map(|rename| rename.to).collect();
+        assert_eq!(
+            paths,
+            vec![
+                PathBuf::from("/media/usb/The Foos/Greatest/01 Foo.mp3"),
+                PathBuf::from("/media/usb/The Foos/Greatest/02 A Muc.mp3"),
+            ]
+        );
+        for path in &paths {
+            assert!(measure(path, &config) <= 30);
+        }
+
+        // later files keep the shortened directory
+        let (fitting, _) = fit(
+            vec![rename("/media/usb/The Foos/Greatest Hits/03 Bar.mp3")],
+            30,
+            &config,
+            &mut shortened_dirs,
+            |r, l| cut(r, l, &config),
+        );
+        assert_eq!(
+            fitting[0].to,
+            PathBuf::from("/media/usb/The Foos/Greatest/03 Bar.mp3")
+        );
+
+        // without organizing, only the file names are cut
+        let config = Config {
+            start_dir: PathBuf::from("/media/usb"),
+            ..Config::default()
+        };
+        let (fitting, _) = fit(renames.clone(), 29, &config, &mut HashMap::new(), |r, l| {
+            cut(r, l, &config)
+        });
+        assert_eq!(
+            fitting[1].to,
+            PathBuf::from("/media/usb/The Foos/Greatest Hits/02.mp3")
+        );
+        let (fitting, skipped) = fit(renames, 26, &config, &mut HashMap::new(), |r, l| {
+            cut(r, l, &config)
+        });
+        assert!(fitting.is_empty());
+        assert_eq!(skipped.len(), 2);
+    }
+
+    #[test]
+    fn test_fit_directory() {
+        let config = Config {
+            start_dir: PathBuf::from("/media/usb"),
+            ..Config::default()
+        };
+        let dir = Path::new("/media/usb/The Foos/Greatest Hits");
+        assert_eq!(
+            fit_directory(dir, "01 Foo.mp3", 40, &config),
+            Some(dir.to_path_buf())
+        );
+        assert_eq!(
+            fit_directory(dir, "01 Foo.mp3", 25, &config),
+            Some(PathBuf::from("/media/usb/The Foos/Great"))
+        );
+        assert_eq!(fit_directory(dir, "01 Foo.mp3", 20, &config), None);
+    }
+}
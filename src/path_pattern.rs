@@ -0,0 +1,138 @@
+use std::fmt;
+use std::fmt::Formatter;
+use std::path::Path;
+use std::str::FromStr;
+
+use regex::{Captures, Regex};
+
+use crate::music_metadata::RawTags;
+use crate::tag_writer;
+use crate::template::Template;
+
+/// Extracts tag values from the path of a music file, e. g. with the pattern
+/// `{artist}/{album} ({year})/{track} - {title}`. The last level is matched
+/// against the file name without its extension, the others against the
+/// names of the directories containing it.
+#[derive(Clone, Debug)]
+pub struct PathPattern {
+    template: Template,
+    regex: Regex,
+    levels: usize,
+}
+
+impl PathPattern {
+    pub fn parse(source: &str) -> Result<PathPattern, String> {
+        let template = Template::parse(source)?;
+        let mut names = Vec::new();
+        for name in template.placeholders() {
+            if !tag_writer::WRITABLE_FIELDS.contains(&name) {
+                return Err(format!("Unknown placeholder \"{{{}}}\"", name));
+            }
+            if names.contains(&name) {
+                return Err(format!("Placeholder \"{{{}}}\" used twice", name));
+            }
+            names.push(name);
+        }
+
+        let expression = template.to_regex(|name| match name {
+            "disc" | "track" | "year" => r"\d+",
+            _ => r"[^/]+?",
+        });
+        let regex = Regex::new(&format!("^{}$", expression)).map_err(|err| err.to_string())?;
+
+        Ok(PathPattern {
+            template,
+            regex,
+            levels: source.matches('/').count() + 1,
+        })
+    }
+
+    /// Returns the values found in the path, or None if it doesn't match
+    pub fn extract(&self, path: &Path) -> Option<RawTags> {
+        let mut names = vec![path.file_stem()?.to_string_lossy().to_string()];
+        let mut dir = path.parent();
+        while names.len() < self.levels {
+            let current = dir?;
+            names.push(current.file_name()?.to_string_lossy().to_string());
+            dir = current.parent();
+        }
+        names.reverse();
+        let names = names.join("/");
+
+        let captures = self.regex.captures(&names)?;
+        let text = |name: &str| {
+            captures
+                .name(name)
+                .map(|value| value.as_str().trim().to_string())
+                .filter(|value| !value.is_empty())
+        };
+
+        Some(RawTags {
+            album: text("album"),
+            album_artist: text("album_artist"),
+            artist: text("artist"),
+            disc: number(&captures, "disc"),
+            title: text("title"),
+            track: number(&captures, "track"),
+            year: number(&captures, "year"),
+            ..RawTags::default()
+        })
+    }
+}
+
+fn number<T: FromStr>(captures: &Captures, name: &str) -> Option<T> {
+    captures
+        .name(name)
+        .and_then(|value| value.as_str().parse().ok())
+}
+
+impl PartialEq for PathPattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.template == other.template
+    }
+}
+
+impl fmt::Display for PathPattern {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.template)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extract() {
+        let pattern = PathPattern::parse("{artist}/{album} ({year})/{track} - {title}").unwrap();
+        let tags = pattern
+            .extract(Path::new("/music/The Foos/Hits (1999)/03 - Foo - Live.mp3"))
+            .unwrap();
+        assert_eq!(tags.artist, Some("The Foos".to_string()));
+        assert_eq!(tags.album, Some("Hits".to_string()));
+        assert_eq!(tags.year, Some(1999));
+        assert_eq!(tags.track, Some(3));
+        assert_eq!(tags.title, Some("Foo - Live".to_string()));
+
+        // numbers have to be numbers, and there have to be enough levels
+        assert_eq!(
+            pattern.extract(Path::new("/music/The Foos/Hits (1999)/A - Foo.mp3")),
+            None
+        );
+        assert_eq!(pattern.extract(Path::new("Hits (1999)/03 - Foo.mp3")), None);
+
+        // optional sections may be missing
+        let pattern = PathPattern::parse("<{disc}->{track} {title}").unwrap();
+        let tags = pattern.extract(Path::new("2-07 Foo.flac")).unwrap();
+        assert_eq!((tags.disc, tags.track), (Some(2), Some(7)));
+        let tags = pattern.extract(Path::new("07 Foo.flac")).unwrap();
+        assert_eq!((tags.disc, tags.track), (None, Some(7)));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(PathPattern::parse("{genre}/{title}").is_err());
+        assert!(PathPattern::parse("{title} - {title}").is_err());
+        assert!(PathPattern::parse("{title").is_err());
+    }
+}
@@ -0,0 +1,342 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fmt::Formatter;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::file_system::FileSystem;
+
+/// The tag values a new name was built from, by placeholder name
+pub type Tags = BTreeMap<String, String>;
+
+/// A single change to the disk (or the reason why there won't be one)
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "operation", rename_all = "snake_case")]
+pub enum Operation {
+    /// Rename a music file, or move it to another directory when organizing
+    RenameFile {
+        from: PathBuf,
+        to: PathBuf,
+        #[serde(default, skip_serializing_if = "Tags::is_empty")]
+        tags: Tags,
+    },
+    RenameDirectory {
+        from: PathBuf,
+        to: PathBuf,
+        #[serde(default, skip_serializing_if = "Tags::is_empty")]
+        tags: Tags,
+    },
+    RemoveFile {
+        path: PathBuf,
+    },
+    /// Write tag values into a music file. `before` holds the values they
+    /// replace, if there were any.
+    WriteTags {
+        path: PathBuf,
+        /// The format as lowercase extension
+        extension: String,
+        before: Tags,
+        after: Tags,
+    },
+    /// Remove a directory and its parents up to the start directory as long
+    /// as they are empty, e. g. after moving all music files away
+    RemoveDirectoryIfEmpty {
+        path: PathBuf,
+    },
+    Skipped {
+        path: PathBuf,
+        reason: String,
+    },
+}
+
+/// All operations for one directory containing music files
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DirectoryPlan {
+    pub path: PathBuf,
+    /// What was found out while planning, shown in verbose mode
+    pub notes: Vec<String>,
+    /// The tags guessed from the paths of files with incomplete tags
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub guesses: Vec<String>,
+    pub operations: Vec<Operation>,
+}
+
+impl DirectoryPlan {
+    pub fn new(path: &Path) -> DirectoryPlan {
+        DirectoryPlan {
+            path: path.to_path_buf(),
+            notes: Vec::new(),
+            guesses: Vec::new(),
+            operations: Vec::new(),
+        }
+    }
+}
+
+/// Everything a run is going to do, directory by directory in the order the
+/// operations have to be executed
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Plan {
+    pub start_dir: PathBuf,
+    pub directories: Vec<DirectoryPlan>,
+}
+
+impl Plan {
+    /// Returns all operations of all directories
+    pub fn operations(&self) -> impl Iterator<Item = &Operation> {
+        self.directories
+            .iter()
+            .flat_map(|directory| directory.operations.iter())
+    }
+
+    /// Writes the plan to a JSON file, together with the current state of
+    /// every file it is going to change
+    pub fn save(&self, path: &Path, file_system: &dyn FileSystem) -> io::Result<()> {
+        let sources = self
+            .operations()
+            .filter_map(Operation::source)
+            .map(|path| {
+                let (size, modified) = file_system.file_state(path);
+                SourceState {
+                    path: path.to_path_buf(),
+                    size,
+                    modified,
+                }
+            })
+            .collect();
+        let saved_plan = SavedPlan {
+            plan: self.clone(),
+            sources,
+        };
+
+        let json = serde_json::to_string_pretty(&saved_plan)?;
+        file_system.write(path, format!("{}\n", json).as_bytes())
+    }
+
+    /// Reads a plan written by `save()`. Fails if any of the files it is
+    /// going to change is missing or has changed since.
+    pub fn load(path: &Path, file_system: &dyn FileSystem) -> Result<Plan, String> {
+        let json = file_system
+            .read_to_string(path)
+            .map_err(|err| format!("Couldn't read plan {}: {}", path.to_string_lossy(), err))?;
+        let saved_plan: SavedPlan = serde_json::from_str(&json)
+            .map_err(|err| format!("Couldn't read plan {}: {}", path.to_string_lossy(), err))?;
+
+        let stale: Vec<String> = saved_plan
+            .sources
+            .iter()
+            .filter_map(|source| source.change(file_system))
+            .collect();
+        if !stale.is_empty() {
+            return Err(format!(
+                "The plan {} is out of date, nothing has been changed:\n{}",
+                path.to_string_lossy(),
+                stale.join("\n")
+            ));
+        }
+
+        Ok(saved_plan.plan)
+    }
+}
+
+impl Operation {
+    /// Returns the path of the existing file or directory the operation changes
+    fn source(&self) -> Option<&Path> {
+        match self {
+            Operation::RenameFile { from, .. } | Operation::RenameDirectory { from, .. } => {
+                Some(from)
+            }
+            Operation::RemoveFile { path } | Operation::WriteTags { path, .. } => Some(path),
+            Operation::RemoveDirectoryIfEmpty { .. } | Operation::Skipped { .. } => None,
+        }
+    }
+}
+
+/// The file format of a saved plan
+#[derive(Serialize, Deserialize)]
+struct SavedPlan {
+    plan: Plan,
+    sources: Vec<SourceState>,
+}
+
+/// The size and modification time (in milliseconds) of a file when the plan
+/// was saved. Directories have neither.
+#[derive(Serialize, Deserialize)]
+struct SourceState {
+    path: PathBuf,
+    size: Option<u64>,
+    modified: Option<u64>,
+}
+
+impl SourceState {
+    /// Describes how the file has changed since, if at all
+    fn change(&self, file_system: &dyn FileSystem) -> Option<String> {
+        if !file_system.exists(&self.path) {
+            Some(format!(
+                "\"{}\" doesn't exist anymore",
+                self.path.to_string_lossy()
+            ))
+        } else if file_system.file_state(&self.path) != (self.size, self.modified) {
+            Some(format!("\"{}\" has changed", self.path.to_string_lossy()))
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Operation::RenameFile { from, to, .. }
+            | Operation::RenameDirectory { from, to, .. } => {
+                if from.parent() == to.parent() {
+                    write!(
+                        f,
+                        "Renaming \"{}\" to \"{}\"",
+                        file_name(from),
+                        file_name(to)
+                    )
+                } else {
+                    write!(
+                        f,
+                        "Moving \"{}\" to \"{}\"",
+                        from.to_string_lossy(),
+                        to.to_string_lossy()
+                    )
+                }
+            }
+            Operation::RemoveFile { path } => write!(f, "Removing {}", path.to_string_lossy()),
+            Operation::WriteTags {
+                path,
+                before,
+                after,
+                ..
+            } => {
+                let changes: Vec<String> = after
+                    .iter()
+                    .map(|(name, value)| match before.get(name) {
+                        Some(old_value) => format!("{} \"{}\" -> \"{}\"", name, old_value, value),
+                        None => format!("{} (none) -> \"{}\"", name, value),
+                    })
+                    .collect();
+                write!(
+                    f,
+                    "Writing tags to \"{}\": {}",
+                    file_name(path),
+                    changes.join(", ")
+                )
+            }
+            Operation::RemoveDirectoryIfEmpty { path } => {
+                write!(f, "Removing \"{}\" if it is empty", path.to_string_lossy())
+            }
+            Operation::Skipped { path, reason } => {
+                write!(f, "Skipping \"{}\": {}", path.to_string_lossy(), reason)
+            }
+        }
+    }
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .unwrap_or_else(|| panic!("Cannot retrieve name part from {}", path.to_string_lossy()))
+        .to_string_lossy()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_system::InMemoryFileSystem;
+
+    #[test]
+    fn test_display() {
+        let operation = Operation::RenameFile {
+            from: PathBuf::from("/music/a.mp3"),
+            to: PathBuf::from("/music/1 Foo.mp3"),
+            tags: Tags::new(),
+        };
+        assert_eq!(operation.to_string(), "Renaming \"a.mp3\" to \"1 Foo.mp3\"");
+
+        let operation = Operation::RenameFile {
+            from: PathBuf::from("/music/a.mp3"),
+            to: PathBuf::from("/library/Foo/1 Foo.mp3"),
+            tags: Tags::new(),
+        };
+        assert_eq!(
+            operation.to_string(),
+            "Moving \"/music/a.mp3\" to \"/library/Foo/1 Foo.mp3\""
+        );
+
+        let operation = Operation::Skipped {
+            path: PathBuf::from("/music/a.mp3"),
+            reason: "No tags".to_string(),
+        };
+        assert_eq!(operation.to_string(), "Skipping \"/music/a.mp3\": No tags");
+
+        let mut before = Tags::new();
+        before.insert("title".to_string(), "Foo".to_string());
+        let mut after = before.clone();
+        after.insert("title".to_string(), "Bar".to_string());
+        after.insert("track".to_string(), "3".to_string());
+        let operation = Operation::WriteTags {
+            path: PathBuf::from("/music/a.mp3"),
+            extension: "mp3".to_string(),
+            before,
+            after,
+        };
+        assert_eq!(
+            operation.to_string(),
+            "Writing tags to \"a.mp3\": title \"Foo\" -> \"Bar\", track (none) -> \"3\""
+        );
+    }
+
+    #[test]
+    fn test_operations() {
+        let mut first = DirectoryPlan::new(Path::new("/music/a"));
+        first.operations.push(Operation::RemoveFile {
+            path: PathBuf::from("/music/a/cover.jpg"),
+        });
+        let mut second = DirectoryPlan::new(Path::new("/music/b"));
+        second.operations.push(Operation::RemoveFile {
+            path: PathBuf::from("/music/b/cover.jpg"),
+        });
+        let plan = Plan {
+            start_dir: PathBuf::from("/music"),
+            directories: vec![first, second],
+        };
+        assert_eq!(plan.operations().count(), 2);
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let file_system = InMemoryFileSystem::new();
+        let dir = PathBuf::from("/music");
+        let music_file = dir.join("a.mp3");
+        file_system.add_file(&music_file, "music");
+
+        let mut directory = DirectoryPlan::new(&dir);
+        directory.operations.push(Operation::RenameFile {
+            from: music_file.clone(),
+            to: dir.join("1 Foo.mp3"),
+            tags: Tags::new(),
+        });
+        let plan = Plan {
+            start_dir: dir.clone(),
+            directories: vec![directory],
+        };
+        let plan_path = dir.join("plan.json");
+        plan.save(&plan_path, &file_system).unwrap();
+        assert_eq!(Plan::load(&plan_path, &file_system), Ok(plan));
+
+        file_system.add_file(&music_file, "other music");
+        assert!(Plan::load(&plan_path, &file_system)
+            .unwrap_err()
+            .contains("has changed"));
+
+        file_system.remove_file(&music_file).unwrap();
+        assert!(Plan::load(&plan_path, &file_system)
+            .unwrap_err()
+            .contains("doesn't exist anymore"));
+    }
+}
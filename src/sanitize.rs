@@ -0,0 +1,206 @@
+use std::fmt;
+use std::fmt::Formatter;
+
+use regex::Regex;
+
+use crate::config::Config;
+use crate::target_fs::TargetFs;
+
+/// What a sanitize rule replaces
+#[derive(Clone, Debug)]
+pub enum Pattern {
+    /// Every occurrence of a text
+    Literal(String),
+    /// The matches of a regular expression
+    Regex(Regex),
+}
+
+/// A replacement applied to file and directory names before the rules of
+/// the target file system
+#[derive(Clone, Debug)]
+pub struct Rule {
+    pattern: Pattern,
+    replacement: String,
+    /// The file systems the rule applies to, all if empty
+    target_fs: Vec<TargetFs>,
+}
+
+impl Rule {
+    pub fn literal(text: &str, replacement: &str) -> Rule {
+        Rule {
+            pattern: Pattern::Literal(text.to_string()),
+            replacement: replacement.to_string(),
+            target_fs: Vec::new(),
+        }
+    }
+
+    /// The replacement may refer to groups like `$1`
+    pub fn regex(pattern: &str, replacement: &str) -> Result<Rule, String> {
+        let regex = Regex::new(pattern)
+            .map_err(|err| format!("Invalid sanitize pattern \"{}\": {}", pattern, err))?;
+        Ok(Rule {
+            pattern: Pattern::Regex(regex),
+            replacement: replacement.to_string(),
+            target_fs: Vec::new(),
+        })
+    }
+
+    /// Restricts the rule to names on the given file systems
+    pub fn on(mut self, target_fs: &[TargetFs]) -> Rule {
+        self.target_fs = target_fs.to_vec();
+        self
+    }
+
+    pub fn applies_to(&self, target_fs: TargetFs) -> bool {
+        self.target_fs.is_empty() || self.target_fs.contains(&target_fs)
+    }
+
+    pub fn apply(&self, text: &str) -> String {
+        match &self.pattern {
+            Pattern::Literal(literal) => text.replace(literal.as_str(), &self.replacement),
+            Pattern::Regex(regex) => regex
+                .replace_all(text, self.replacement.as_str())
+                .to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &self.pattern {
+            Pattern::Literal(literal) => write!(f, "\"{}\" -> \"{}\"", literal, self.replacement)?,
+            Pattern::Regex(regex) => write!(f, "/{}/ -> \"{}\"", regex, self.replacement)?,
+        }
+        if !self.target_fs.is_empty() {
+            let names: Vec<String> = self.target_fs.iter().map(TargetFs::to_string).collect();
+            write!(f, " on {}", names.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the rules used unless the configuration file has its own: a few
+/// replacements everywhere, and readable replacements of the characters the
+/// target file system doesn't allow
+pub fn default_rules() -> Vec<Rule> {
+    use TargetFs::*;
+    let windows = &[Windows, Fat32, Exfat, Iso9660];
+    vec![
+        Rule::literal("$", "_"),
+        // for "Die drei ???"
+        Rule::literal("???", "Fragezeichen"),
+        Rule::literal("\\", "").on(windows),
+        Rule::literal("/", " & "),
+        Rule::literal(":", " -").on(&[Windows, Fat32, Exfat, Macos, Iso9660]),
+        Rule::literal("*", "_").on(windows),
+        Rule::literal("?", "").on(windows),
+        Rule::literal("\"", "").on(windows),
+        Rule::literal("<", "").on(windows),
+        Rule::literal(">", "").on(windows),
+        Rule::literal("|", ", ").on(windows),
+        Rule::literal(";", ",").on(&[Iso9660]),
+    ]
+}
+
+/// Turns a text into a file or directory name by applying the sanitize rules
+/// in their order and then those of the target file system
+pub fn sanitize(text: &str, config: &Config) -> String {
+    let mut name = text.to_string();
+    for rule in &config.sanitize_rules {
+        if rule.applies_to(config.target_fs) {
+            name = rule.apply(&name);
+        }
+    }
+    config.target_fs.sanitize(&name)
+}
+
+/// Returns each step of sanitizing a text that changed it: the rule and the
+/// name it resulted in
+pub fn explain(text: &str, config: &Config) -> Vec<(String, String)> {
+    let mut steps = Vec::new();
+    let mut name = text.to_string();
+    for (index, rule) in config.sanitize_rules.iter().enumerate() {
+        if !rule.applies_to(config.target_fs) {
+            continue;
+        }
+        let new_name = rule.apply(&name);
+        if new_name != name {
+            steps.push((format!("Rule {}: {}", index + 1, rule), new_name.clone()));
+            name = new_name;
+        }
+    }
+    steps.extend(config.target_fs.steps(&name));
+    steps
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::target_fs::TargetFs;
+
+    #[test]
+    fn test_sanitize() {
+        let config = Config::default();
+        assert_eq!(
+            sanitize("Die drei ??? und $1", &config),
+            "Die drei Fragezeichen und _1"
+        );
+
+        // the default rules replace only what the file system doesn't allow
+        let name = "AC/DC: Who? *Me* <Live>";
+        let on = |target_fs| Config {
+            target_fs,
+            ..Config::default()
+        };
+        assert_eq!(sanitize(name, &config), "AC & DC - Who _Me_ Live");
+        assert_eq!(
+            sanitize(name, &on(TargetFs::Macos)),
+            "AC & DC - Who? *Me* <Live>"
+        );
+        assert_eq!(
+            sanitize(name, &on(TargetFs::Posix)),
+            "AC & DC: Who? *Me* <Live>"
+        );
+        assert_eq!(sanitize("Foo; Bar", &on(TargetFs::Iso9660)), "Foo, Bar");
+
+        // the rules come before those of the file system, which replaces
+        // what they leave
+        let config = Config {
+            sanitize_rules: vec![
+                Rule::literal("/", "-"),
+                Rule::regex(r"\s*\(feat\. ([^)]*)\)", " ft. $1").unwrap(),
+                Rule::literal("?", "").on(&[TargetFs::Fat32]),
+            ],
+            target_fs: TargetFs::Windows,
+            ..Config::default()
+        };
+        assert_eq!(
+            sanitize("AC/DC (feat. Foo) ???", &config),
+            "AC-DC ft. Foo ___"
+        );
+        assert!(Rule::regex("(", "").is_err());
+    }
+
+    #[test]
+    fn test_explain() {
+        let config = Config::default();
+        let steps = explain("Die drei ???: Folge 1 ", &config);
+        assert_eq!(
+            steps,
+            vec![
+                (
+                    "Rule 2: \"???\" -> \"Fragezeichen\"".to_string(),
+                    "Die drei Fragezeichen: Folge 1 ".to_string()
+                ),
+                (
+                    "Rule 5: \":\" -> \" -\" on windows, fat32, exfat, macos, iso9660".to_string(),
+                    "Die drei Fragezeichen - Folge 1 ".to_string()
+                ),
+                (
+                    "windows: leading and trailing dots and blanks".to_string(),
+                    "Die drei Fragezeichen - Folge 1".to_string()
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,167 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fmt::Formatter;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+/// The name of the configuration file looked up in the start directory
+pub const START_DIR_FILE_NAME: &str = ".mp3rename.toml";
+
+/// Where a configuration value came from
+#[derive(Clone, Debug, PartialEq)]
+pub enum Source {
+    Default,
+    File(PathBuf),
+    /// A named profile within a configuration file
+    Profile(String, PathBuf),
+    CommandLine,
+}
+
+impl Source {
+    /// Resolves a relative path against the directory of the configuration
+    /// file it was given in
+    pub fn resolve(&self, path: &Path) -> PathBuf {
+        match self {
+            Source::File(file) | Source::Profile(_, file) => match file.parent() {
+                Some(dir) => dir.join(path),
+                None => path.to_path_buf(),
+            },
+            _ => path.to_path_buf(),
+        }
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Default => write!(f, "default"),
+            Source::File(path) => write!(f, "{}", path.to_string_lossy()),
+            Source::Profile(name, path) => {
+                write!(f, "profile \"{}\" in {}", name, path.to_string_lossy())
+            }
+            Source::CommandLine => write!(f, "command line"),
+        }
+    }
+}
+
+/// Configuration values set in a configuration file or on the command line.
+/// The names are the same as those of the long command-line options, e. g.
+/// `dir-template = "{album}"` or `limit-length = 64`.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Settings {
+    pub artist: Option<bool>,
+    pub detect_content: Option<bool>,
+    pub directory: Option<bool>,
+    pub dir_template: Option<String>,
+    pub dry_run: Option<bool>,
+    pub ellipsis: Option<String>,
+    pub fallback: Option<String>,
+    pub file_template: Option<String>,
+    pub from_path_pattern: Option<String>,
+    pub journal: Option<PathBuf>,
+    pub length_unit: Option<String>,
+    pub limit_length: Option<u32>,
+    pub max_path_length: Option<u32>,
+    pub mount_root: Option<PathBuf>,
+    pub normalize: Option<String>,
+    pub omit_artist: Option<bool>,
+    pub on_collision: Option<String>,
+    pub organize_into: Option<PathBuf>,
+    pub organize_template: Option<String>,
+    pub output: Option<String>,
+    pub override_tags: Option<bool>,
+    pub remove: Option<bool>,
+    /// Pairs of a regular expression and its replacement
+    pub replace: Option<Vec<(String, String)>>,
+    /// The replacements in file and directory names, replacing the default ones
+    pub sanitize: Option<Vec<SanitizeRule>>,
+    pub save_plan: Option<PathBuf>,
+    pub shorten: Option<String>,
+    pub target_fs: Option<String>,
+    pub title_case_language: Option<String>,
+    pub various_artists: Option<String>,
+    pub verbose: Option<bool>,
+    pub write_normalized: Option<bool>,
+    pub write_tags: Option<bool>,
+    /// Named sets of values, e. g. `[profile.car-usb]`, selected by `--profile`
+    pub profile: HashMap<String, Settings>,
+}
+
+/// A replacement in file and directory names of either a literal text or the
+/// matches of a regular expression, e. g.
+/// `sanitize = [{ literal = "???", replacement = "Fragezeichen" }]`
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct SanitizeRule {
+    pub literal: Option<String>,
+    pub regex: Option<String>,
+    pub replacement: String,
+    /// The file systems the rule applies to, all if missing
+    pub target_fs: Option<Vec<String>>,
+}
+
+impl Settings {
+    pub fn parse(source: &str) -> Result<Settings, String> {
+        toml::from_str(source).map_err(|err| err.to_string())
+    }
+
+    /// Reads a configuration file, returning `None` if there is none
+    pub fn read(path: &Path) -> Result<Option<Settings>, String> {
+        match fs::read_to_string(path) {
+            Ok(source) => Settings::parse(&source)
+                .map(Some)
+                .map_err(|err| format!("Cannot parse {}: {}", path.to_string_lossy(), err)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(format!("Cannot read {}: {}", path.to_string_lossy(), err)),
+        }
+    }
+}
+
+/// Returns the configuration files to read in the order they are applied:
+/// the one in the user's configuration directory and the one in the start directory
+pub fn file_paths(start_dir: &Path) -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    if let Some(config_dir) = dirs::config_dir() {
+        paths.push(config_dir.join("mp3rename").join("config.toml"));
+    }
+    if start_dir != Path::new("") {
+        paths.push(start_dir.join(START_DIR_FILE_NAME));
+    }
+    paths
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let settings = Settings::parse(
+            r#"
+            artist = true
+            limit-length = 64
+            file-template = "{track} {title}"
+
+            [profile.car-usb]
+            limit-length = 32
+            organize-into = "/media/usb"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(settings.artist, Some(true));
+        assert_eq!(settings.directory, None);
+        assert_eq!(settings.limit_length, Some(64));
+        assert_eq!(settings.file_template, Some("{track} {title}".to_string()));
+        let profile = &settings.profile["car-usb"];
+        assert_eq!(profile.limit_length, Some(32));
+        assert_eq!(profile.organize_into, Some(PathBuf::from("/media/usb")));
+
+        assert!(Settings::parse("unknown = true").is_err());
+        assert!(Settings::parse("artist = 1").is_err());
+    }
+}
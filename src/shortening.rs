@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+
+use regex::Regex;
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::template::{Template, Value};
+
+/// Abbreviations of common words in titles, by lowercase word
+const ABBREVIATIONS: &[(&str, &str)] = &[
+    ("and", "&"),
+    ("concerto", "Conc."),
+    ("edition", "Ed."),
+    ("feat.", "ft."),
+    ("featuring", "ft."),
+    ("number", "No."),
+    ("orchestra", "Orch."),
+    ("original", "Orig."),
+    ("part", "Pt."),
+    ("remaster", "Rem."),
+    ("remastered", "Rem."),
+    ("symphony", "Sym."),
+    ("version", "Ver."),
+    ("volume", "Vol."),
+];
+
+/// Renders a name from `template` that `fits`, giving up the least important
+/// parts of the values first: the artist is dropped (or abbreviated to its
+/// initials if the template requires it), then suffixes in parentheses or
+/// brackets like "(Remastered 2011)" are removed from the title, then common
+/// words in it are abbreviated, and only then the title is cut, ending with
+/// `ellipsis`. Numbers and other values are never changed, so returns None
+/// if no name fits.
+pub fn shorten(
+    template: &Template,
+    mut values: HashMap<&'static str, Value>,
+    fits: impl Fn(&str) -> bool,
+    ellipsis: &str,
+) -> Option<String> {
+    let name = template.render(&values)?;
+    if fits(&name) {
+        return Some(name);
+    }
+
+    if let Some(artist) = values.remove("artist") {
+        if template.render(&values).is_none() {
+            values.insert("artist", map_text(&artist, initials));
+        }
+        if let Some(name) = template.render(&values).filter(|name| fits(name)) {
+            return Some(name);
+        }
+    }
+
+    for step in &[without_suffixes as fn(&str) -> String, abbreviated] {
+        if let Some(title) = values.get_mut("title") {
+            *title = map_text(title, step);
+        }
+        if let Some(name) = template.render(&values).filter(|name| fits(name)) {
+            return Some(name);
+        }
+    }
+
+    if let Some(Value::Text(title)) = values.get("title").cloned() {
+        let cuts: Vec<usize> = title
+            .grapheme_indices(true)
+            .map(|(index, _)| index)
+            .collect();
+        for &end in cuts.iter().rev() {
+            let short_title = format!("{}{}", title[..end].trim_end(), ellipsis);
+            values.insert("title", Value::Text(short_title));
+            if let Some(name) = template.render(&values).filter(|name| fits(name)) {
+                return Some(name);
+            }
+        }
+    }
+
+    None
+}
+
+fn map_text(value: &Value, f: impl Fn(&str) -> String) -> Value {
+    match value {
+        Value::Text(text) => Value::Text(f(text)),
+        number => number.clone(),
+    }
+}
+
+/// Returns the first letters of the words, e. g. "RHCP" for "Red Hot Chili Peppers"
+fn initials(text: &str) -> String {
+    text.split_whitespace()
+        .filter_map(|word| word.chars().find(|c| c.is_alphanumeric()))
+        .collect()
+}
+
+/// Removes parts in parentheses or brackets at the end, e. g. "Foo (feat. Bar) [Live]" becomes "Foo"
+fn without_suffixes(text: &str) -> String {
+    let re = Regex::new(r"(\s*(\([^()]*\)|\[[^\[\]]*\]))+\s*$").unwrap();
+    let short = re.replace(text, "");
+    if short.is_empty() {
+        text.to_string()
+    } else {
+        short.to_string()
+    }
+}
+
+fn abbreviated(text: &str) -> String {
+    text.split(' ')
+        .map(|word| {
+            let lowercase = word.to_lowercase();
+            match ABBREVIATIONS.iter().find(|(long, _)| *long == lowercase) {
+                Some((_, short)) => short.to_string(),
+                None => word.to_string(),
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(artist: &str, title: &str) -> HashMap<&'static str, Value> {
+        let mut values = HashMap::new();
+        values.insert("artist", Value::Text(artist.to_string()));
+        values.insert("title", Value::Text(title.to_string()));
+        values.insert("track", Value::Number { value: 7, width: 2 });
+        values
+    }
+
+    #[test]
+    fn test_shorten() {
+        let template = Template::parse("{track} <{artist} - >{title}").unwrap();
+        let title = "Symphony Number 5 (Remastered 2011)";
+        let shorten_to = |length: usize| {
+            shorten(
+                &template,
+                values("The Foos", title),
+                |name| name.chars().count() <= length,
+                "…",
+            )
+            .unwrap_or_default()
+        };
+
+        assert_eq!(
+            shorten_to(50),
+            "07 The Foos - Symphony Number 5 (Remastered 2011)"
+        );
+        assert_eq!(shorten_to(40), "07 Symphony Number 5 (Remastered 2011)");
+        assert_eq!(shorten_to(30), "07 Symphony Number 5");
+        assert_eq!(shorten_to(15), "07 Sym. No. 5");
+        assert_eq!(shorten_to(10), "07 Sym. N…");
+        assert_eq!(shorten_to(4), "07 …");
+        // the track number is never cut
+        assert_eq!(shorten_to(3), "");
+
+        // numbers after the title are kept as well, and only the title is
+        // shortened
+        let template = Template::parse("{title} - {album} - {track}").unwrap();
+        let mut album_values = values("The Foos", title);
+        album_values.insert("album", Value::Text("Live (Remastered)".to_string()));
+        let name = shorten(
+            &template,
+            album_values,
+            |name| name.chars().count() <= 32,
+            "…",
+        );
+        assert_eq!(name, Some("Sym. N… - Live (Remastered) - 07".to_string()));
+
+        // a required artist is abbreviated
+        let template = Template::parse("{artist} - {title}").unwrap();
+        let name = shorten(
+            &template,
+            values("Red Hot Chili Peppers", "Californication"),
+            |name| name.len() <= 25,
+            "",
+        );
+        assert_eq!(name, Some("RHCP - Californication".to_string()));
+    }
+
+    #[test]
+    fn test_without_suffixes() {
+        assert_eq!(without_suffixes("Foo (feat. Bar) [Live]"), "Foo");
+        assert_eq!(without_suffixes("Foo (Bar) Baz"), "Foo (Bar) Baz");
+        assert_eq!(without_suffixes("(Untitled)"), "(Untitled)");
+    }
+}
@@ -0,0 +1,189 @@
+use std::path::Path;
+
+use audiotags::{AudioTag, FlacTag, Id3v2Tag, Mp4Tag};
+
+use crate::music_metadata::RawTags;
+use crate::plan::Tags;
+
+/// The tags that can be written, by placeholder name
+pub const WRITABLE_FIELDS: &[&str] = &[
+    "album",
+    "album_artist",
+    "artist",
+    "disc",
+    "title",
+    "track",
+    "year",
+];
+
+/// Can tags be written to files of the format given as lowercase extension?
+pub fn can_write(extension: &str) -> bool {
+    matches!(extension, "mp3" | "flac" | "m4a" | "m4b" | "m4p" | "m4v")
+}
+
+/// Returns the writable values of `new` that differ from those of `old`,
+/// first as they were and then as they are going to be. Values missing in
+/// `new` are left alone.
+pub fn changes(old: &RawTags, new: &RawTags) -> (Tags, Tags) {
+    let old_values = values(old);
+    let mut before = Tags::new();
+    let mut after = Tags::new();
+
+    for (name, value) in values(new) {
+        let old_value = old_values.get(&name);
+        if old_value != Some(&value) {
+            if let Some(old_value) = old_value {
+                before.insert(name.clone(), old_value.clone());
+            }
+            after.insert(name, value);
+        }
+    }
+
+    (before, after)
+}
+
+/// Sets the tags given by placeholder name, e. g. to update tags kept in memory
+pub fn apply(tags: &mut RawTags, values: &Tags) -> Result<(), String> {
+    for (name, value) in values {
+        match name.as_str() {
+            "album" => tags.album = Some(value.clone()),
+            "album_artist" => tags.album_artist = Some(value.clone()),
+            "artist" => tags.artist = Some(value.clone()),
+            "disc" => tags.disc = Some(parse(name, value)?),
+            "title" => tags.title = Some(value.clone()),
+            "track" => tags.track = Some(parse(name, value)?),
+            "year" => tags.year = Some(parse(name, value)?),
+            _ => return Err(format!("Cannot write the {} tag", name)),
+        }
+    }
+    Ok(())
+}
+
+/// Writes the tags given by placeholder name into an MP3, FLAC, or MP4 file.
+/// The format is chosen by the given extension as the actual one might be
+/// wrong. A missing ID3 tag is created, other read errors are returned.
+pub fn write(path: &Path, extension: &str, values: &Tags) -> Result<(), String> {
+    let mut tag: Box<dyn AudioTag> = match extension {
+        "mp3" => Box::new(match Id3v2Tag::read_from_path(path) {
+            Ok(tag) => tag,
+            Err(audiotags::Error::Id3TagError(id3::Error {
+                kind: id3::ErrorKind::NoTag,
+                ..
+            })) => Id3v2Tag::default(),
+            Err(e) => return Err(e.to_string()),
+        }),
+        "flac" => Box::new(FlacTag::read_from_path(path).map_err(|e| e.to_string())?),
+        _ if can_write(extension) => {
+            Box::new(Mp4Tag::read_from_path(path).map_err(|e| e.to_string())?)
+        }
+        _ => {
+            return Err(format!(
+                "Writing tags to {} files isn't supported",
+                extension.to_uppercase()
+            ))
+        }
+    };
+
+    for (name, value) in values {
+        match name.as_str() {
+            "album" => tag.set_album_title(value),
+            "album_artist" => tag.set_album_artist(value),
+            "artist" => tag.set_artist(value),
+            "disc" => tag.set_disc_number(parse(name, value)?),
+            "title" => tag.set_title(value),
+            "track" => tag.set_track_number(parse(name, value)?),
+            "year" => tag.set_year(parse(name, value)?),
+            _ => return Err(format!("Cannot write the {} tag", name)),
+        }
+    }
+
+    tag.write_to_path(&path.to_string_lossy())
+        .map_err(|e| e.to_string())
+}
+
+/// Returns the writable values that are set, by placeholder name
+fn values(tags: &RawTags) -> Tags {
+    let mut values = Tags::new();
+    let mut insert = |name: &str, value: Option<String>| {
+        if let Some(value) = value {
+            values.insert(name.to_string(), value);
+        }
+    };
+    insert("album", tags.album.clone());
+    insert("album_artist", tags.album_artist.clone());
+    insert("artist", tags.artist.clone());
+    insert("disc", tags.disc.map(|disc| disc.to_string()));
+    insert("title", tags.title.clone());
+    insert("track", tags.track.map(|track| track.to_string()));
+    insert("year", tags.year.map(|year| year.to_string()));
+    values
+}
+
+fn parse<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Invalid {} \"{}\", expected a number", name, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_changes_and_apply() {
+        let old = RawTags {
+            album: Some("Hits".to_string()),
+            title: Some("Foo".to_string()),
+            track: Some(3),
+            ..RawTags::default()
+        };
+        let new = RawTags {
+            album: Some("Hits".to_string()),
+            artist: Some("The Foos".to_string()),
+            track: Some(4),
+            ..RawTags::default()
+        };
+
+        let (before, after) = changes(&old, &new);
+        assert_eq!(before.len(), 1);
+        assert_eq!(before["track"], "3");
+        assert_eq!(after.len(), 2);
+        assert_eq!(after["artist"], "The Foos");
+        assert_eq!(after["track"], "4");
+
+        // applying the changes keeps the title missing in `new`
+        let mut tags = old.clone();
+        apply(&mut tags, &after).unwrap();
+        assert_eq!(tags.artist, Some("The Foos".to_string()));
+        assert_eq!(tags.title, Some("Foo".to_string()));
+        assert_eq!(tags.track, Some(4));
+
+        let mut values = Tags::new();
+        values.insert("track".to_string(), "four".to_string());
+        assert!(apply(&mut tags, &values).is_err());
+    }
+
+    #[test]
+    fn test_write_mp3() {
+        let dir = std::env::temp_dir().join("mp3rename-tag-writer");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let mut values = Tags::new();
+        values.insert("title".to_string(), "Foo".to_string());
+
+        // a missing tag is created ...
+        let path = dir.join("untagged.mp3");
+        fs::write(&path, b"").unwrap();
+        assert_eq!(write(&path, "mp3", &values), Ok(()));
+        assert_eq!(
+            id3::Tag::read_from_path(&path).unwrap().title(),
+            Some("Foo")
+        );
+
+        // ... but other errors are reported
+        assert!(write(&dir.join("missing.mp3"), "mp3", &values).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
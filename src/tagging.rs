@@ -0,0 +1,337 @@
+use std::path::{Component, Path, PathBuf};
+
+use crate::collisions;
+use crate::config::Config;
+use crate::file_system::FileSystem;
+use crate::metadata_source::MetadataSource;
+use crate::music_metadata::RawTags;
+use crate::path_pattern::PathPattern;
+use crate::plan::{DirectoryPlan, Operation, Plan, Tags};
+use crate::tag_writer;
+
+/// Where the `tag` subcommand takes the values it writes from. If several
+/// sources are given, the CSV file comes first, then the path pattern, and
+/// then the directory name.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TagSources {
+    /// A CSV file whose header row names the columns: "path" and any of the
+    /// writable tags
+    pub csv: Option<PathBuf>,
+    /// A pattern matching the file and directory names
+    pub pattern: Option<PathPattern>,
+    /// Take the album from the name of the directory containing the file?
+    pub album_from_dir: bool,
+}
+
+/// Finds out which tags to write into the music files below the start
+/// directory without changing anything
+pub fn plan(
+    config: &Config,
+    sources: &TagSources,
+    source: &dyn MetadataSource,
+    file_system: &dyn FileSystem,
+) -> Result<Plan, String> {
+    let rows = match &sources.csv {
+        Some(path) => read_csv(path, &config.start_dir, file_system)?,
+        None => Vec::new(),
+    };
+    let mut is_matched = vec![false; rows.len()];
+    let mut plan = Plan {
+        start_dir: config.start_dir.clone(),
+        directories: Vec::new(),
+    };
+
+    for dir in file_system.directories(&config.start_dir) {
+        let music = match crate::list_directory(&dir, config, file_system) {
+            Some((music, _)) if !music.is_empty() => music,
+            _ => continue,
+        };
+        let mut directory_plan = DirectoryPlan::new(&dir);
+
+        for (path, extension) in music {
+            if !tag_writer::can_write(&extension) {
+                directory_plan.operations.push(Operation::Skipped {
+                    path,
+                    reason: format!(
+                        "Writing tags to {} files isn't supported",
+                        extension.to_uppercase()
+                    ),
+                });
+                continue;
+            }
+
+            let mut tags = match find_row(&rows, &path) {
+                Some(index) => {
+                    is_matched[index] = true;
+                    rows[index].tags.clone()
+                }
+                None => RawTags::default(),
+            };
+            if let Some(pattern) = &sources.pattern {
+                match pattern.extract(&path) {
+                    Some(path_tags) => tags.merge(path_tags),
+                    None => directory_plan.notes.push(format!(
+                        "\"{}\" doesn't match the path pattern",
+                        path.to_string_lossy()
+                    )),
+                }
+            }
+            if sources.album_from_dir && tags.album.is_none() {
+                tags.album = dir
+                    .file_name()
+                    .map(|name| name.to_string_lossy().to_string());
+            }
+
+            // files without any tags yet get new ones
+            let old_tags = source.read(&path, &extension).unwrap_or_default();
+            let (before, after) = tag_writer::changes(&old_tags, &tags);
+            if after.is_empty() {
+                directory_plan.notes.push(format!(
+                    "Nothing to change in \"{}\"",
+                    path.to_string_lossy()
+                ));
+            } else {
+                directory_plan.operations.push(Operation::WriteTags {
+                    path,
+                    extension,
+                    before,
+                    after,
+                });
+            }
+        }
+
+        plan.directories.push(directory_plan);
+    }
+
+    // report rows with typos in their paths instead of ignoring them
+    let unmatched: Vec<Operation> = rows
+        .iter()
+        .zip(is_matched)
+        .filter(|(_, is_matched)| !is_matched)
+        .map(|(row, _)| Operation::Skipped {
+            path: row.path.clone(),
+            reason: format!("Line {} of the CSV file matches no music file", row.line),
+        })
+        .collect();
+    if !unmatched.is_empty() {
+        let mut directory_plan = DirectoryPlan::new(&config.start_dir);
+        directory_plan.operations = unmatched;
+        plan.directories.push(directory_plan);
+    }
+
+    Ok(plan)
+}
+
+/// A line of the CSV file
+struct Row {
+    line: usize,
+    path: PathBuf,
+    tags: RawTags,
+}
+
+/// Returns the index of the row for a music file. Paths differing only in
+/// case match as well unless another row matches exactly.
+fn find_row(rows: &[Row], path: &Path) -> Option<usize> {
+    let path = normalize(path);
+    rows.iter().position(|row| row.path == path).or_else(|| {
+        rows.iter()
+            .position(|row| collisions::is_same_path(&row.path, &path))
+    })
+}
+
+/// Removes "." components and resolves ".." without looking at the disk
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+/// Reads the tags by path from a CSV file. Relative paths are relative to
+/// the start directory.
+fn read_csv(
+    path: &Path,
+    start_dir: &Path,
+    file_system: &dyn FileSystem,
+) -> Result<Vec<Row>, String> {
+    let error = |message: String| format!("Cannot read {}: {}", path.to_string_lossy(), message);
+    let text = file_system
+        .read_to_string(path)
+        .map_err(|err| error(err.to_string()))?;
+    let mut records = parse_csv(&text).map_err(error)?.into_iter();
+
+    let (_, header) = records.next().unwrap_or_default();
+    let path_column = header
+        .iter()
+        .position(|name| name == "path")
+        .ok_or_else(|| error("Missing \"path\" column".to_string()))?;
+    for name in &header {
+        if name != "path" && !tag_writer::WRITABLE_FIELDS.contains(&name.as_str()) {
+            return Err(error(format!("Unknown column \"{}\"", name)));
+        }
+    }
+
+    let mut rows = Vec::new();
+    for (line, record) in records {
+        let mut values = Tags::new();
+        for (name, value) in header.iter().zip(&record) {
+            if name != "path" && !value.is_empty() {
+                values.insert(name.clone(), value.clone());
+            }
+        }
+        let mut tags = RawTags::default();
+        tag_writer::apply(&mut tags, &values)
+            .map_err(|err| error(format!("Line {}: {}", line, err)))?;
+        if let Some(file) = record.get(path_column) {
+            rows.push(Row {
+                line,
+                path: normalize(&start_dir.join(file.trim())),
+                tags,
+            });
+        }
+    }
+
+    Ok(rows)
+}
+
+/// Splits CSV text into records of fields. Fields may be quoted with double
+/// quotes, a quote within a quoted field is doubled. Empty lines are ignored.
+/// Each record comes with the number of the line it starts on.
+fn parse_csv(text: &str) -> Result<Vec<(usize, Vec<String>)>, String> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut is_quoted = false;
+    let mut line = 1;
+    let mut start = line;
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if is_quoted => {
+                if chars.peek() == Some(&'"') {
+                    chars.next();
+                    field.push('"');
+                } else {
+                    is_quoted = false;
+                }
+            }
+            '"' if field.is_empty() => is_quoted = true,
+            ',' if !is_quoted => record.push(std::mem::take(&mut field)),
+            '\r' if !is_quoted => {}
+            '\n' if !is_quoted => {
+                if !record.is_empty() || !field.is_empty() {
+                    record.push(std::mem::take(&mut field));
+                    records.push((start, std::mem::take(&mut record)));
+                }
+                line += 1;
+                start = line;
+            }
+            '\n' => {
+                line += 1;
+                field.push(c);
+            }
+            _ => field.push(c),
+        }
+    }
+    if is_quoted {
+        return Err(format!("Line {}: Missing closing quote", start));
+    }
+    if !record.is_empty() || !field.is_empty() {
+        record.push(field);
+        records.push((start, record));
+    }
+
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_system::InMemoryFileSystem;
+    use crate::metadata_source::InMemory;
+
+    #[test]
+    fn test_parse_csv() {
+        let records = parse_csv(
+            "path,title\r\na.mp3,\"Foo, \"\"Bar\"\"\"\n\nb.mp3,\"Line 1\nLine 2\"\n\nc.mp3,\n",
+        )
+        .unwrap();
+        assert_eq!(
+            records,
+            vec![
+                (1, vec!["path".to_string(), "title".to_string()]),
+                (2, vec!["a.mp3".to_string(), "Foo, \"Bar\"".to_string()]),
+                (4, vec!["b.mp3".to_string(), "Line 1\nLine 2".to_string()]),
+                (7, vec!["c.mp3".to_string(), "".to_string()]),
+            ]
+        );
+        assert_eq!(
+            parse_csv("path\n\n\"a.mp3\n"),
+            Err("Line 3: Missing closing quote".to_string())
+        );
+    }
+
+    #[test]
+    fn test_plan() {
+        let file_system = InMemoryFileSystem::new();
+        let dir = PathBuf::from("/music/Hits");
+        for name in &["01 - Foo.mp3", "02 - Bar.mp3", "03 - Baz.ogg"] {
+            file_system.add_file(&dir.join(name), "");
+        }
+        file_system.add_file(
+            Path::new("/music/tags.csv"),
+            "path,artist,track\n\n./Hits/../Hits/02 - bar.MP3,The Bars,7\n\nHits/03 - Bax.mp3,,3\n",
+        );
+        let mut source = InMemory::new();
+        source.insert_tags(
+            &dir.join("01 - Foo.mp3"),
+            RawTags {
+                album: Some("Hits".to_string()),
+                title: Some("Foo!".to_string()),
+                ..RawTags::default()
+            },
+        );
+
+        let config = Config {
+            start_dir: PathBuf::from("/music"),
+            ..Config::default()
+        };
+        let sources = TagSources {
+            csv: Some(PathBuf::from("/music/tags.csv")),
+            pattern: Some(PathPattern::parse("{track} - {title}").unwrap()),
+            album_from_dir: true,
+        };
+        let plan = plan(&config, &sources, &source, &file_system).unwrap();
+
+        let operations = &plan.directories[0].operations;
+        assert_eq!(operations.len(), 3);
+        // the CSV file comes before the pattern
+        assert_eq!(
+            operations[0].to_string(),
+            "Writing tags to \"01 - Foo.mp3\": title \"Foo!\" -> \"Foo\", track (none) -> \"1\""
+        );
+        assert_eq!(
+            operations[1].to_string(),
+            "Writing tags to \"02 - Bar.mp3\": album (none) -> \"Hits\", artist (none) -> \"The Bars\", \
+            title (none) -> \"Bar\", track (none) -> \"7\""
+        );
+        assert!(matches!(operations[2], Operation::Skipped { .. }));
+
+        // rows matching no file are reported
+        assert_eq!(
+            plan.directories[1].operations,
+            vec![Operation::Skipped {
+                path: dir.join("03 - Bax.mp3"),
+                reason: "Line 5 of the CSV file matches no music file".to_string(),
+            }]
+        );
+    }
+}
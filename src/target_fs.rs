@@ -0,0 +1,202 @@
+use std::fmt;
+use std::fmt::Formatter;
+use std::str::FromStr;
+
+use regex::Regex;
+
+use crate::length::LengthUnit;
+
+/// The file system the music files end up on, which decides the characters
+/// allowed in names and their maximum length
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TargetFs {
+    /// Linux and other Unix file systems like ext4, only "/" is reserved
+    Posix,
+    /// NTFS as seen by Windows
+    Windows,
+    /// FAT32 with long file names, e. g. on USB sticks and SD cards
+    Fat32,
+    /// exFAT, e. g. on larger USB sticks and SD cards
+    Exfat,
+    /// APFS and HFS+, where ":" is shown as "/" by the Finder
+    Macos,
+    /// CDs with Joliet extensions
+    Iso9660,
+}
+
+/// Names of devices on Windows, which can't be used as file names, not even
+/// with an extension
+const RESERVED_NAMES: &[&str] = &[
+    "aux", "com1", "com2", "com3", "com4", "com5", "com6", "com7", "com8", "com9", "con", "lpt1",
+    "lpt2", "lpt3", "lpt4", "lpt5", "lpt6", "lpt7", "lpt8", "lpt9", "nul", "prn",
+];
+
+/// The characters Windows doesn't allow in names
+const WINDOWS_CHARACTERS: &[char] = &['\\', '/', ':', '*', '?', '"', '<', '>', '|'];
+
+impl TargetFs {
+    /// Isn't a character allowed in names on this file system? The sanitize
+    /// rules replace these characters by something more readable first.
+    pub fn is_forbidden(self, c: char) -> bool {
+        match self {
+            TargetFs::Posix => c == '/',
+            TargetFs::Macos => c == '/' || c == ':',
+            TargetFs::Windows | TargetFs::Fat32 | TargetFs::Exfat => {
+                WINDOWS_CHARACTERS.contains(&c)
+            }
+            TargetFs::Iso9660 => c == ';' || WINDOWS_CHARACTERS.contains(&c),
+        }
+    }
+
+    /// Can't names of Windows devices like "CON" or "nul.mp3" be used?
+    pub fn has_reserved_names(self) -> bool {
+        !matches!(self, TargetFs::Posix | TargetFs::Macos)
+    }
+
+    /// Returns the maximum length of a name and the unit it is counted in
+    pub fn max_name_length(self) -> (usize, LengthUnit) {
+        match self {
+            TargetFs::Posix => (255, LengthUnit::Utf8),
+            TargetFs::Windows | TargetFs::Fat32 | TargetFs::Exfat | TargetFs::Macos => {
+                (255, LengthUnit::Utf16)
+            }
+            TargetFs::Iso9660 => (64, LengthUnit::Utf16),
+        }
+    }
+
+    /// Turns a text into a name allowed on this file system. Forbidden
+    /// characters become "_", control characters are removed, leading and
+    /// trailing dots and whitespace as well, and runs of whitespace become a
+    /// single blank. Reserved names get a "_" appended.
+    pub fn sanitize(self, text: &str) -> String {
+        match self.steps(text).pop() {
+            Some((_, name)) => name,
+            None => text.to_string(),
+        }
+    }
+
+    /// Returns each step of sanitizing a text that changed it: its
+    /// description and the name it resulted in
+    pub fn steps(self, text: &str) -> Vec<(String, String)> {
+        let mut steps = Vec::new();
+        let mut name = text.to_string();
+        let mut step = |description: String, new_name: String, name: &mut String| {
+            if new_name != *name {
+                steps.push((format!("{}: {}", self, description), new_name.clone()));
+                *name = new_name;
+            }
+        };
+
+        let new_name = name
+            .chars()
+            .map(|c| if self.is_forbidden(c) { '_' } else { c })
+            .collect();
+        step("forbidden characters".to_string(), new_name, &mut name);
+
+        let new_name = name
+            .chars()
+            .filter_map(|c| match c {
+                _ if c.is_whitespace() => Some(' '),
+                _ if c.is_control() => None,
+                _ => Some(c),
+            })
+            .collect();
+        step("control characters".to_string(), new_name, &mut name);
+
+        let re = Regex::new(r"^[\s.]*|[\s.]*$").unwrap();
+        let new_name = re.replace_all(&name, "").to_string();
+        step(
+            "leading and trailing dots and blanks".to_string(),
+            new_name,
+            &mut name,
+        );
+
+        // replace whitespace with only one blank each
+        let re = Regex::new(r"\s+").unwrap();
+        let new_name = re.replace_all(&name, " ").to_string();
+        step("runs of blanks".to_string(), new_name, &mut name);
+
+        if self.has_reserved_names() && is_reserved(&name) {
+            let new_name = match name.find('.') {
+                Some(index) => format!("{}_{}", &name[..index], &name[index..]),
+                None => format!("{}_", name),
+            };
+            step("reserved name".to_string(), new_name, &mut name);
+        }
+
+        steps
+    }
+}
+
+/// Is a name, or the part before its first dot, the name of a Windows device?
+fn is_reserved(name: &str) -> bool {
+    let base = name.split('.').next().unwrap_or_default().trim_end();
+    RESERVED_NAMES.contains(&base.to_lowercase().as_str())
+}
+
+impl FromStr for TargetFs {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "posix" => Ok(TargetFs::Posix),
+            "windows" => Ok(TargetFs::Windows),
+            "fat32" => Ok(TargetFs::Fat32),
+            "exfat" => Ok(TargetFs::Exfat),
+            "macos" => Ok(TargetFs::Macos),
+            "iso9660" => Ok(TargetFs::Iso9660),
+            _ => Err(format!(
+                "Unknown target file system \"{}\", use posix, windows, fat32, exfat, macos, or iso9660",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for TargetFs {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            TargetFs::Posix => write!(f, "posix"),
+            TargetFs::Windows => write!(f, "windows"),
+            TargetFs::Fat32 => write!(f, "fat32"),
+            TargetFs::Exfat => write!(f, "exfat"),
+            TargetFs::Macos => write!(f, "macos"),
+            TargetFs::Iso9660 => write!(f, "iso9660"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sanitize() {
+        let name = "AC/DC: Who? *Me* <Live>";
+        assert_eq!(TargetFs::Posix.sanitize(name), "AC_DC: Who? *Me* <Live>");
+        assert_eq!(TargetFs::Macos.sanitize(name), "AC_DC_ Who? *Me* <Live>");
+        assert_eq!(TargetFs::Windows.sanitize(name), "AC_DC_ Who_ _Me_ _Live_");
+        assert_eq!(TargetFs::Iso9660.sanitize("Foo; Bar"), "Foo_ Bar");
+
+        // control characters, and dots and blanks at the ends
+        assert_eq!(TargetFs::Fat32.sanitize(" .Foo\u{7}\tBar. "), "Foo Bar");
+        assert_eq!(TargetFs::Posix.sanitize("Foo\u{0}Bar"), "FooBar");
+    }
+
+    #[test]
+    fn test_reserved_names() {
+        assert_eq!(TargetFs::Fat32.sanitize("CON"), "CON_");
+        assert_eq!(TargetFs::Windows.sanitize("nul.mp3"), "nul_.mp3");
+        assert_eq!(TargetFs::Exfat.sanitize("Com1 .txt"), "Com1 _.txt");
+        assert_eq!(TargetFs::Fat32.sanitize("Console"), "Console");
+        assert_eq!(TargetFs::Posix.sanitize("CON"), "CON");
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("fat32".parse(), Ok(TargetFs::Fat32));
+        assert_eq!(TargetFs::Iso9660.to_string(), "iso9660");
+        assert!("ntfs".parse::<TargetFs>().is_err());
+        assert_eq!(TargetFs::Iso9660.max_name_length(), (64, LengthUnit::Utf16));
+    }
+}
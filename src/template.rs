@@ -0,0 +1,316 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fmt::Formatter;
+
+/// A value a placeholder can be replaced with
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Text(String),
+    /// A number together with the width it is zero-padded to by default
+    Number {
+        value: u32,
+        width: usize,
+    },
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Text(text) => write!(f, "{}", text),
+            Value::Number { value, .. } => write!(f, "{}", value),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Part {
+    Literal(String),
+    Placeholder {
+        name: String,
+        width: Option<usize>,
+    },
+    /// A section in angle brackets that is dropped if any of its placeholders is missing
+    Optional(Vec<Part>),
+}
+
+/// A name template such as `<{disc} - >{track} <{artist} - >{title}`.
+///
+/// Placeholders are written in curly braces, optionally followed by a width
+/// (`{track:02}`) that overrides the default zero-padding of numbers.
+/// Sections in angle brackets are optional: they vanish as a whole if one of
+/// the placeholders inside them has no value.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Template {
+    source: String,
+    parts: Vec<Part>,
+}
+
+impl Template {
+    pub fn parse(source: &str) -> Result<Template, String> {
+        let mut chars = source.chars().peekable();
+        let parts = parse_parts(&mut chars, false)?;
+
+        Ok(Template {
+            source: source.to_string(),
+            parts,
+        })
+    }
+
+    /// Returns the names of all placeholders used in this template
+    pub fn placeholders(&self) -> Vec<&str> {
+        let mut names = Vec::new();
+        collect_placeholders(&self.parts, &mut names);
+        names
+    }
+
+    /// Replaces all placeholders with their values. Returns None if a
+    /// placeholder outside of an optional section has no value.
+    pub fn render(&self, values: &HashMap<&str, Value>) -> Option<String> {
+        render_parts(&self.parts, values)
+    }
+
+    /// Returns a regular expression matching the names rendered from this
+    /// template, capturing each placeholder in a group of the same name.
+    /// `group` returns the expression a placeholder's value has to match.
+    pub fn to_regex<F>(&self, group: F) -> String
+    where
+        F: Fn(&str) -> &'static str,
+    {
+        regex_parts(&self.parts, &group)
+    }
+}
+
+impl fmt::Display for Template {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+fn parse_parts<I>(
+    chars: &mut std::iter::Peekable<I>,
+    in_optional: bool,
+) -> Result<Vec<Part>, String>
+where
+    I: Iterator<Item = char>,
+{
+    let mut parts = Vec::new();
+    let mut literal = String::new();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' => {
+                if !literal.is_empty() {
+                    parts.push(Part::Literal(literal.clone()));
+                    literal.clear();
+                }
+                parts.push(parse_placeholder(chars)?);
+            }
+            '}' => return Err("Unexpected \"}\" without matching \"{\"".to_string()),
+            '<' => {
+                if in_optional {
+                    return Err("Optional sections cannot be nested".to_string());
+                }
+                if !literal.is_empty() {
+                    parts.push(Part::Literal(literal.clone()));
+                    literal.clear();
+                }
+                parts.push(Part::Optional(parse_parts(chars, true)?));
+            }
+            '>' => {
+                if !in_optional {
+                    return Err("Unexpected \">\" without matching \"<\"".to_string());
+                }
+                if !literal.is_empty() {
+                    parts.push(Part::Literal(literal));
+                }
+                return Ok(parts);
+            }
+            _ => literal.push(c),
+        }
+    }
+
+    if in_optional {
+        return Err("Missing \">\" at the end of an optional section".to_string());
+    }
+    if !literal.is_empty() {
+        parts.push(Part::Literal(literal));
+    }
+
+    Ok(parts)
+}
+
+fn parse_placeholder<I>(chars: &mut I) -> Result<Part, String>
+where
+    I: Iterator<Item = char>,
+{
+    let mut content = String::new();
+    loop {
+        match chars.next() {
+            None => return Err(format!("Missing \"}}\" after \"{{{}\"", content)),
+            Some('}') => break,
+            Some(c) => content.push(c),
+        }
+    }
+
+    let (name, width) = match content.find(':') {
+        None => (content.as_str(), None),
+        Some(pos) => {
+            let width = &content[pos + 1..];
+            match width.parse::<usize>() {
+                Ok(width) => (&content[..pos], Some(width)),
+                Err(_) => {
+                    return Err(format!(
+                        "Invalid width \"{}\" in \"{{{}}}\"",
+                        width, content
+                    ))
+                }
+            }
+        }
+    };
+
+    if name.is_empty() {
+        return Err("Empty placeholder \"{}\"".to_string());
+    }
+
+    Ok(Part::Placeholder {
+        name: name.to_string(),
+        width,
+    })
+}
+
+fn collect_placeholders<'a>(parts: &'a [Part], names: &mut Vec<&'a str>) {
+    for part in parts {
+        match part {
+            Part::Literal(_) => {}
+            Part::Placeholder { name, .. } => names.push(name),
+            Part::Optional(inner) => collect_placeholders(inner, names),
+        }
+    }
+}
+
+fn render_parts(parts: &[Part], values: &HashMap<&str, Value>) -> Option<String> {
+    let mut result = String::new();
+
+    for part in parts {
+        match part {
+            Part::Literal(literal) => result.push_str(literal),
+            Part::Placeholder { name, width } => match values.get(name.as_str())? {
+                Value::Text(text) => result.push_str(text),
+                Value::Number {
+                    value,
+                    width: default_width,
+                } => {
+                    let width = width.unwrap_or(*default_width);
+                    result.push_str(&format!("{:0width$}", value, width = width));
+                }
+            },
+            Part::Optional(inner) => {
+                if let Some(rendered) = render_parts(inner, values) {
+                    result.push_str(&rendered);
+                }
+            }
+        }
+    }
+
+    Some(result)
+}
+
+fn regex_parts<F>(parts: &[Part], group: &F) -> String
+where
+    F: Fn(&str) -> &'static str,
+{
+    let mut result = String::new();
+
+    for part in parts {
+        match part {
+            Part::Literal(literal) => result.push_str(&regex::escape(literal)),
+            Part::Placeholder { name, .. } => {
+                result.push_str(&format!("(?P<{}>{})", name, group(name)))
+            }
+            Part::Optional(inner) => {
+                result.push_str(&format!("(?:{})?", regex_parts(inner, group)))
+            }
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values() -> HashMap<&'static str, Value> {
+        let mut values = HashMap::new();
+        values.insert("artist", Value::Text("The Foos".to_string()));
+        values.insert("title", Value::Text("Foo de Foo".to_string()));
+        values.insert("track", Value::Number { value: 7, width: 2 });
+        values
+    }
+
+    #[test]
+    fn test_render() {
+        let template = Template::parse("{track} {artist} - {title}").unwrap();
+        assert_eq!(
+            template.render(&values()),
+            Some("07 The Foos - Foo de Foo".to_string())
+        );
+
+        // an explicit width overrides the default one
+        let template = Template::parse("{track:3} {title}").unwrap();
+        assert_eq!(
+            template.render(&values()),
+            Some("007 Foo de Foo".to_string())
+        );
+        let template = Template::parse("{track:0} {title}").unwrap();
+        assert_eq!(template.render(&values()), Some("7 Foo de Foo".to_string()));
+
+        // a missing mandatory placeholder spoils the whole name
+        let template = Template::parse("{disc} - {track} {title}").unwrap();
+        assert_eq!(template.render(&values()), None);
+    }
+
+    #[test]
+    fn test_render_optional_sections() {
+        let template = Template::parse("<{disc:02} - >{track} <{artist} - >{title}").unwrap();
+        assert_eq!(
+            template.render(&values()),
+            Some("07 The Foos - Foo de Foo".to_string())
+        );
+
+        let mut values = values();
+        values.insert("disc", Value::Number { value: 1, width: 1 });
+        values.remove("artist");
+        assert_eq!(
+            template.render(&values),
+            Some("01 - 07 Foo de Foo".to_string())
+        );
+    }
+
+    #[test]
+    fn test_to_regex() {
+        let template = Template::parse("<{disc} - >{track}. {title}").unwrap();
+        let group = |name: &str| if name == "title" { ".+" } else { r"\d+" };
+        assert_eq!(
+            template.to_regex(group),
+            r"(?:(?P<disc>\d+) \- )?(?P<track>\d+)\. (?P<title>.+)"
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(Template::parse("{track").is_err());
+        assert!(Template::parse("track}").is_err());
+        assert!(Template::parse("{}").is_err());
+        assert!(Template::parse("{track:x}").is_err());
+        assert!(Template::parse("<{disc} - {track}").is_err());
+        assert!(Template::parse("{disc} - > {track}").is_err());
+        assert!(Template::parse("<<{disc}>>").is_err());
+    }
+
+    #[test]
+    fn test_placeholders() {
+        let template = Template::parse("<{disc} - >{track} {title}").unwrap();
+        assert_eq!(template.placeholders(), vec!["disc", "track", "title"]);
+    }
+}
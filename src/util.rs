@@ -1,44 +1,44 @@
+use std::cmp;
+use std::fs;
 use std::path::{Path, PathBuf};
-use std::{cmp, fs};
-
-use regex::Regex;
-use walkdir::WalkDir;
 
 use crate::config::Config;
+use crate::file_system::FileSystem;
+use crate::formats;
+use crate::length;
+use crate::path_length;
+use crate::sanitize;
+
+/// Returns the lowercase extension matching a music file's format, or `None`
+/// for other files. Files are recognized by their content if configured,
+/// else by their extension. Files whose content isn't recognized are still
+/// recognized by their extension, so music files are never taken for others.
+pub fn music_extension(
+    path: &Path,
+    config: &Config,
+    file_system: &dyn FileSystem,
+) -> Option<String> {
+    if config.detect_content {
+        if let Some(extension) = formats::sniff(path, file_system) {
+            return Some(extension.to_string());
+        }
+    }
 
-/// Returns the list of directories.
-pub fn get_list_of_dirs(config: &Config) -> Vec<walkdir::DirEntry> {
-    WalkDir::new(&config.start_dir)
-        .contents_first(true)
-        .into_iter()
-        .filter_entry(|e| e.file_type().is_dir())
-        // filter out errors (cannot print warnings!)
-        //.filter_map(Result::ok)
-        // filter *and* report errors
-        .filter(|e| match e {
-            Ok(_) => true,
-            Err(err) => {
-                eprintln!("Error traversing directories: {}", err);
-                false
-            }
-        })
-        // convert to DirEntry
-        .map(|e| e.unwrap())
-        .collect()
-}
-
-pub fn is_music_file(entry: &fs::DirEntry) -> bool {
-    let path = entry.path();
-    let file_name = path.to_str();
-    match file_name {
-        None => false,
-        Some(file_name) => is_music_filename(file_name),
+    let file_name = path.to_str()?;
+    if is_music_filename(file_name) {
+        path.extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase())
+    } else {
+        None
     }
 }
 
 /// Checks if a name's extension is in a list of music file extensions
 pub fn is_music_filename(file_name: &str) -> bool {
-    let music_extensions = vec![".mp3", ".flac", ".m4a", ".m4b", ".m4p", ".m4v"];
+    let music_extensions = vec![
+        ".aif", ".aifc", ".aiff", ".ape", ".flac", ".m4a", ".m4b", ".m4p", ".m4v", ".mp3", ".mpc",
+        ".oga", ".ogg", ".opus", ".wav", ".wma", ".wv",
+    ];
     let file_name = file_name.to_lowercase();
     for ext in music_extensions {
         if file_name.ends_with(ext) {
@@ -49,53 +49,72 @@ pub fn is_music_filename(file_name: &str) -> bool {
     false
 }
 
-pub fn sanitize_file_or_directory_name(filename: &str) -> String {
-    let mut name = filename.replace("$", "_");
-    name = name.replace("???", "Fragezeichen");
-
-    // Windows doesn't like any of the following characters
-    name = name.replace("\\", "");
-    name = name.replace("/", " & ");
-    name = name.replace(":", " -");
-    name = name.replace("*", "_");
-    name = name.replace("?", "");
-    name = name.replace("\"", "");
-    name = name.replace("<", "");
-    name = name.replace(">", "");
-    name = name.replace("|", ", ");
-
-    // now we added blanks, let's handle the ones in the beginning and at the end
-    name = name.trim().to_string();
-
-    // remove dots at the start
-    let re = Regex::new(r"^[.]*").unwrap();
-    name = re.replace_all(&name, "").to_string();
-
-    // remove dots at the end
-    let re = Regex::new(r"[.]*$").unwrap();
-    name = re.replace_all(&name, "").to_string();
-
-    // replace whitespace with only one blank each
-    let re = Regex::new(r"\s+").unwrap();
-    name = re.replace_all(&name, " ").to_string();
-
-    // remove any blanks at the name's start or end
-    name.trim().to_string()
+/// Turns a text into a file or directory name allowed on the target file system
+pub fn sanitize_file_or_directory_name(filename: &str, config: &Config) -> String {
+    sanitize::sanitize(filename, config)
+}
+
+/// Cuts a name to the length the target file system allows, keeping the extension
+pub fn fit_target_fs(path: &Path, name: &str, config: &Config) -> String {
+    let (limit, unit) = config.target_fs.max_name_length();
+    if unit.measure(name) <= limit {
+        return name.to_string();
+    }
+    let (extension, _) = get_extension(path);
+    let stem = get_name_stem(name, &extension);
+    let length = limit.saturating_sub(unit.measure(&extension));
+    let stem = length::truncate(&stem, length, unit, &config.ellipsis);
+
+    format!("{}{}", stem.trim(), extension)
 }
 
 /// Shortens a file name so that it (together with the extension) fits in a given length
 /// Combines the path's extension with the stem from the name.
 pub fn shorten_names(path: &Path, name: &str, config: &Config) -> String {
-    let (extension, extension_len): (String, usize) = get_extension(path);
-    let stem = get_name_stem(name, &extension);
+    shorten_name_to(path, name, config.name_length as usize, config)
+}
 
-    let len = cmp::min(
-        cmp::max((config.name_length as i32) - (extension_len as i32), 0) as usize, // get a positive number
-        stem.len(),
-    );
+/// Shortens a file name like `shorten_names`, but to the given length
+pub fn shorten_name_to(path: &Path, name: &str, name_length: usize, config: &Config) -> String {
+    let (extension, _) = get_extension(path);
+    let stem = get_name_stem(name, &extension);
+    let unit = config.length_unit;
+    let length = name_length.saturating_sub(unit.measure(&extension));
+    let stem = length::truncate(&stem, length, unit, &config.ellipsis);
 
     // trim to not have a blank before the extension
-    format!("{}{}", &stem[..len].trim(), extension)
+    format!("{}{}", stem.trim(), extension)
+}
+
+/// Appends a number like " (2)" to a path's name (in front of its extension),
+/// shortening the name if necessary to keep the number within the name
+/// length and the maximum path length. Returns None if that leaves nothing
+/// of the name.
+pub fn with_number_suffix(path: &Path, number: usize, config: &Config) -> Option<PathBuf> {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let suffix = format!(" ({})", number);
+
+    let (extension, _) = get_extension(path);
+    let mut stem = get_name_stem(&name, &extension);
+    let path_limit = config
+        .max_path_length
+        .map(|max_length| path_length::name_budget(path, max_length as usize, config));
+    let limit = match (config.name_limit(), path_limit) {
+        (Some(name_limit), Some(path_limit)) => Some(cmp::min(name_limit, path_limit)),
+        (name_limit, path_limit) => name_limit.or(path_limit),
+    };
+    if let Some(limit) = limit {
+        let length = limit.saturating_sub(config.length_unit.measure(&suffix));
+        stem = get_name_stem(&shorten_name_to(path, &name, length, config), &extension);
+        if stem.is_empty() {
+            return None;
+        }
+    }
+
+    Some(path.with_file_name(format!("{}{}{}", stem, suffix, extension)))
 }
 
 /// Returns the path's extension with leading dot (or the empty string)
@@ -115,7 +134,7 @@ pub fn get_extension(path: &Path) -> (String, usize) {
 
 /// Returns the file name's stem, i. e. the name without the extension given as second argument
 pub fn get_name_stem(name: &str, extension: &str) -> String {
-    name.replace(&extension, "")
+    name.replace(extension, "")
 }
 
 /// Returns a path made of the given string slice
@@ -126,73 +145,131 @@ pub fn string_to_path(file_name: &str) -> std::io::Result<PathBuf> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::length::LengthUnit;
+    use crate::target_fs::TargetFs;
 
     #[test]
     fn test_is_music_filename() {
-        assert_eq!(is_music_filename("/tmp/music.mp3"), true);
-        assert_eq!(is_music_filename("/tmp/music.mp33"), false);
-        assert_eq!(is_music_filename("/tmp/music.Mp3"), true);
-        assert_eq!(is_music_filename("/tmp/music.FlAc"), true);
-        assert_eq!(is_music_filename("/tmp/music.m4a"), true);
-        assert_eq!(is_music_filename("/tmp/music.m4p"), true);
-        assert_eq!(is_music_filename("/tmp/music.m4v"), true);
-        assert_eq!(is_music_filename("/tmp/music.mp4"), false);
+        assert!(is_music_filename("/tmp/music.mp3"));
+        assert!(!is_music_filename("/tmp/music.mp33"));
+        assert!(is_music_filename("/tmp/music.Mp3"));
+        assert!(is_music_filename("/tmp/music.FlAc"));
+        assert!(is_music_filename("/tmp/music.m4a"));
+        assert!(is_music_filename("/tmp/music.m4p"));
+        assert!(is_music_filename("/tmp/music.m4v"));
+        assert!(!is_music_filename("/tmp/music.mp4"));
+        assert!(is_music_filename("/tmp/music.ogg"));
+        assert!(is_music_filename("/tmp/music.OPUS"));
+        assert!(is_music_filename("/tmp/music.wav"));
+        assert!(is_music_filename("/tmp/music.aiff"));
+        assert!(is_music_filename("/tmp/music.wma"));
+        assert!(is_music_filename("/tmp/music.wv"));
+        assert!(!is_music_filename("/tmp/music.txt"));
     }
 
     #[test]
     fn test_sanitize_file_or_directory_name() {
+        let config = Config::default();
         assert_eq!(
-            sanitize_file_or_directory_name("$foo $$ bar$"),
+            sanitize_file_or_directory_name("$foo $$ bar$", &config),
             "_foo __ bar_"
         );
 
         // special handling for "The Three ???"
         assert_eq!(
-            sanitize_file_or_directory_name("foo ??? bar"),
+            sanitize_file_or_directory_name("foo ??? bar", &config),
             "foo Fragezeichen bar"
         );
 
-        assert_eq!(sanitize_file_or_directory_name("foo\\bar"), "foobar");
+        assert_eq!(
+            sanitize_file_or_directory_name("foo\\bar", &config),
+            "foobar"
+        );
 
-        assert_eq!(sanitize_file_or_directory_name("foo/bar"), "foo & bar");
-        assert_eq!(sanitize_file_or_directory_name("foo/"), "foo &");
+        assert_eq!(
+            sanitize_file_or_directory_name("foo/bar", &config),
+            "foo & bar"
+        );
+        assert_eq!(sanitize_file_or_directory_name("foo/", &config), "foo &");
 
-        assert_eq!(sanitize_file_or_directory_name("foo: bar"), "foo - bar");
-        assert_eq!(sanitize_file_or_directory_name("foo:"), "foo -");
+        assert_eq!(
+            sanitize_file_or_directory_name("foo: bar", &config),
+            "foo - bar"
+        );
+        assert_eq!(sanitize_file_or_directory_name("foo:", &config), "foo -");
 
         assert_eq!(
-            sanitize_file_or_directory_name("*foo * bar*"),
+            sanitize_file_or_directory_name("*foo * bar*", &config),
             "_foo _ bar_"
         );
         assert_eq!(
-            sanitize_file_or_directory_name("*foo ** bar*"),
+            sanitize_file_or_directory_name("*foo ** bar*", &config),
             "_foo __ bar_"
         );
 
-        assert_eq!(sanitize_file_or_directory_name("foo ? bar"), "foo bar");
-        assert_eq!(sanitize_file_or_directory_name("?foo bar?"), "foo bar");
+        assert_eq!(
+            sanitize_file_or_directory_name("foo ? bar", &config),
+            "foo bar"
+        );
+        assert_eq!(
+            sanitize_file_or_directory_name("?foo bar?", &config),
+            "foo bar"
+        );
 
-        assert_eq!(sanitize_file_or_directory_name("\"foo bar\""), "foo bar");
+        assert_eq!(
+            sanitize_file_or_directory_name("\"foo bar\"", &config),
+            "foo bar"
+        );
 
-        assert_eq!(sanitize_file_or_directory_name("<foo bar>"), "foo bar");
+        assert_eq!(
+            sanitize_file_or_directory_name("<foo bar>", &config),
+            "foo bar"
+        );
 
-        assert_eq!(sanitize_file_or_directory_name("foo|bar"), "foo, bar");
+        assert_eq!(
+            sanitize_file_or_directory_name("foo|bar", &config),
+            "foo, bar"
+        );
 
         // whitespace
-        assert_eq!(sanitize_file_or_directory_name("foo\tbar"), "foo bar");
-        assert_eq!(sanitize_file_or_directory_name("foo   bar"), "foo bar");
-        assert_eq!(sanitize_file_or_directory_name("foo \t \t bar"), "foo bar");
-        assert_eq!(sanitize_file_or_directory_name(" foo bar "), "foo bar");
+        assert_eq!(
+            sanitize_file_or_directory_name("foo\tbar", &config),
+            "foo bar"
+        );
+        assert_eq!(
+            sanitize_file_or_directory_name("foo   bar", &config),
+            "foo bar"
+        );
+        assert_eq!(
+            sanitize_file_or_directory_name("foo \t \t bar", &config),
+            "foo bar"
+        );
+        assert_eq!(
+            sanitize_file_or_directory_name(" foo bar ", &config),
+            "foo bar"
+        );
 
         // leading and trailing dots
-        assert_eq!(sanitize_file_or_directory_name("...foo bar"), "foo bar");
-        assert_eq!(sanitize_file_or_directory_name(".foo bar"), "foo bar");
-        assert_eq!(sanitize_file_or_directory_name("foo bar..."), "foo bar");
-        assert_eq!(sanitize_file_or_directory_name("foo bar."), "foo bar");
+        assert_eq!(
+            sanitize_file_or_directory_name("...foo bar", &config),
+            "foo bar"
+        );
+        assert_eq!(
+            sanitize_file_or_directory_name(".foo bar", &config),
+            "foo bar"
+        );
+        assert_eq!(
+            sanitize_file_or_directory_name("foo bar...", &config),
+            "foo bar"
+        );
+        assert_eq!(
+            sanitize_file_or_directory_name("foo bar.", &config),
+            "foo bar"
+        );
 
         // example with french punctuation marks
         assert_eq!(
-            sanitize_file_or_directory_name("Où est le bien ? Où est le mal ?"),
+            sanitize_file_or_directory_name("Où est le bien ? Où est le mal ?", &config),
             "Où est le bien Où est le mal"
         );
     }
@@ -278,6 +355,90 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_fit_target_fs() {
+        let config = Config {
+            target_fs: TargetFs::Iso9660,
+            ..Config::default()
+        };
+        let path = PathBuf::from("/foo/bar.mp3");
+        let name = format!("{}.mp3", "ä".repeat(70));
+        assert_eq!(
+            fit_target_fs(&path, &name, &config),
+            format!("{}.mp3", "ä".repeat(60))
+        );
+        let config = Config {
+            target_fs: TargetFs::Posix,
+            ..Config::default()
+        };
+        assert_eq!(fit_target_fs(&path, &name, &config), name);
+    }
+
+    #[test]
+    fn test_shorten_names_with_unicode() {
+        let config = Config {
+            name_length: 8,
+            ..Config::default()
+        };
+        let path = PathBuf::from("/foo/bar.mp3");
+        assert_eq!(shorten_names(&path, "Ölkanne.mp3", &config), "Ölka.mp3");
+        assert_eq!(
+            shorten_names(&path, "東京タワー.mp3", &config),
+            "東京タワ.mp3"
+        );
+
+        let config = Config {
+            ellipsis: "…".to_string(),
+            length_unit: LengthUnit::Utf8,
+            name_length: 12,
+            ..Config::default()
+        };
+        assert_eq!(shorten_names(&path, "東京タワー.mp3", &config), "東….mp3");
+        assert_eq!(shorten_names(&path, "Ölkanne.mp3", &config), "Ölkanne.mp3");
+    }
+
+    #[test]
+    fn test_with_number_suffix() {
+        let config = Config::default();
+        assert_eq!(
+            with_number_suffix(&PathBuf::from("/foo/bar.mp3"), 2, &config),
+            Some(PathBuf::from("/foo/bar (2).mp3"))
+        );
+        assert_eq!(
+            with_number_suffix(&PathBuf::from("/foo/Titan A.E."), 3, &config),
+            Some(PathBuf::from("/foo/Titan A.E. (3)"))
+        );
+
+        let config = Config {
+            name_length: 10,
+            shorten_names: true,
+            ..Config::default()
+        };
+        assert_eq!(
+            with_number_suffix(&PathBuf::from("/foo/foo bar.mp3"), 2, &config),
+            Some(PathBuf::from("/foo/fo (2).mp3"))
+        );
+
+        // the path below the start directory stays within the maximum length
+        let config = Config {
+            max_path_length: Some(14),
+            start_dir: PathBuf::from("/music"),
+            ..Config::default()
+        };
+        assert_eq!(
+            with_number_suffix(&PathBuf::from("/music/foo/foo bar.mp3"), 2, &config),
+            Some(PathBuf::from("/music/foo/fo (2).mp3"))
+        );
+        let config = Config {
+            max_path_length: Some(12),
+            ..config
+        };
+        assert_eq!(
+            with_number_suffix(&PathBuf::from("/music/foo/foo bar.mp3"), 2, &config),
+            None
+        );
+    }
+
     #[test]
     fn test_get_extension() {
         assert_eq!(